@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use base::{
+    page::PageId,
+    replacer::{AccessType, Clock, Replacer, TwoQ, ARC, LRU},
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const K: usize = 2;
+
+fn replacers(frames: usize) -> Vec<(&'static str, Arc<dyn Replacer>)> {
+    vec![
+        ("lru-k", LRU::new(K)),
+        ("clock", Clock::new()),
+        ("2q", TwoQ::new(frames)),
+        ("arc", ARC::new(frames)),
+    ]
+}
+
+/// Fill every frame, then replay what the page cache does on a miss: evict a frame, load a new
+/// page into it and then access a random cached page
+fn bench_evict(c: &mut Criterion) {
+    let mut group = c.benchmark_group("evict");
+    for frames in [1 << 10, 1 << 14, 1 << 18] {
+        for (name, replacer) in replacers(frames) {
+            for i in 0..frames {
+                replacer.record_access(i, i as PageId, AccessType::Get);
+            }
+
+            let mut rng = StdRng::seed_from_u64(0);
+            let mut pages = (0..frames as PageId).collect::<Vec<_>>();
+            let mut next_page_id = frames as PageId;
+            group.bench_with_input(BenchmarkId::new(name, frames), &frames, |b, frames| {
+                b.iter(|| {
+                    let i = replacer.evict().expect("nothing is pinned");
+                    pages[i] = next_page_id;
+                    replacer.record_access(i, next_page_id, AccessType::Get);
+                    next_page_id += 1;
+
+                    let hot = rng.gen_range(0..*frames);
+                    replacer.record_access(hot, pages[hot], AccessType::Get);
+                    black_box(i)
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Pin and unpin cached pages, as every hit in the page cache does
+fn bench_hit(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hit");
+    let frames = 1 << 14;
+    for (name, replacer) in replacers(frames) {
+        for i in 0..frames {
+            replacer.record_access(i, i as PageId, AccessType::Get);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let i = rng.gen_range(0..frames);
+                replacer.record_access(i, i as PageId, AccessType::Get);
+                replacer.pin(i);
+                replacer.unpin(i);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_evict, bench_hit);
+criterion_main!(benches);
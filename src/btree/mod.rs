@@ -1,7 +1,13 @@
 pub mod node;
 pub mod slot;
 
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicI64, Ordering::Relaxed},
+        Arc,
+    },
+};
 
 use crate::{
     btree::{
@@ -10,47 +16,79 @@ use crate::{
     },
     catalog::Schema,
     disk::{Disk, FileSystem},
-    page::{PageBuf, PageId, PageReadGuard, PageWriteGuard},
-    page_cache::SharedPageCache,
+    page::{PageBuf, PageId, PageReadGuard},
+    page_cache::{PinWriteGuard, SharedPageCache},
+    replacer::{AccessType, Replacer, LRU},
     storable::Storable,
     table::tuple::{Comparand, Tuple},
-    writep,
+    transaction::Transaction,
 };
 
-pub struct BTree<'s, V, D: Disk = FileSystem> {
-    root: PageId,
-    pc: SharedPageCache<D>,
+/// Pages are logged redo only. If a transaction aborts its splits are kept and its keys are put
+/// back one at a time, other transactions can have written to the same pages since.
+pub struct BTree<'s, V, D: Disk = FileSystem, R: Replacer = LRU> {
+    /// Shared with the transactions that changed the tree, so an abort can undo their keys
+    root: Arc<AtomicI64>,
+    pc: SharedPageCache<D, R>,
     schema: &'s Schema,
     _data: PhantomData<V>,
 }
 
-impl<'s, V, D> BTree<'s, V, D>
+impl<'s, V, D, R> BTree<'s, V, D, R>
 where
-    V: Storable + Clone + Eq,
-    D: Disk,
+    V: Storable + Clone + Eq + Send + 'static,
+    D: Disk + Send + Sync + 'static,
+    R: Replacer + 'static,
 {
-    pub fn new(pc: SharedPageCache<D>, schema: &'s Schema) -> Self {
-        Self { root: -1, pc, schema, _data: PhantomData }
+    pub fn new(pc: SharedPageCache<D, R>, schema: &'s Schema) -> Self {
+        Self::new_with_root(pc, -1, schema)
     }
 
-    pub fn new_with_root(pc: SharedPageCache<D>, root: PageId, schema: &'s Schema) -> Self {
-        Self { root, pc, schema, _data: PhantomData }
+    pub fn new_with_root(pc: SharedPageCache<D, R>, root: PageId, schema: &'s Schema) -> Self {
+        Self { root: Arc::new(AtomicI64::new(root)), pc, schema, _data: PhantomData }
     }
 
     pub fn root(&self) -> PageId {
-        self.root
+        self.root.load(Relaxed)
     }
 
     // TODO: One thread could split the root whilst another holds a pin to the root. Should double
     // check is_root
-    pub fn insert(&mut self, key: &Tuple, value: &V) -> crate::Result<()> {
+    pub fn insert(&mut self, key: &Tuple, value: &V, txn: &Transaction) -> crate::Result<()> {
+        let old = self.value(key)?;
+        self._insert_root(key, value, txn)?;
+        self.on_abort(key, old, Some(value.clone()), txn);
+
+        Ok(())
+    }
+
+    /// Put `key` back to `from` if the transaction aborts, unless another transaction has changed
+    /// it from `to` since
+    fn on_abort(&self, key: &Tuple, from: Option<V>, to: Option<V>, txn: &Transaction) {
+        let (root, pc, schema, key) =
+            (self.root.clone(), self.pc.clone(), self.schema.clone(), key.clone());
+
+        txn.on_abort(move |txn| {
+            let mut btree = BTree { root, pc, schema: &schema, _data: PhantomData };
+            if btree.value(&key)? != to {
+                return Ok(());
+            }
+
+            match from {
+                Some(value) => btree._insert_root(&key, &value, txn),
+                None => btree._delete(&key, btree.root(), txn).map(|_| ()),
+            }
+        });
+    }
+
+    fn _insert_root(&mut self, key: &Tuple, value: &V, txn: &Transaction) -> crate::Result<()> {
         let pin;
-        let rpage = match self.root {
+        let rpage = match self.root() {
             -1 => {
                 pin = self.pc.new_page()?;
                 let node: Node<V> = Node::new(pin.id, NodeType::Leaf, true, &self.schema);
                 let mut page = pin.write();
-                writep!(page, &PageBuf::from(&node));
+                txn.write_redo_only(&mut page, &PageBuf::from(&node));
                 page
             }
             id => {
@@ -58,18 +96,18 @@ where
                 pin.write()
             }
         };
-        self.root = rpage.id;
+        self.root.store(rpage.id, Relaxed);
 
-        if let Some((s, os)) = self._insert(None, rpage, key, value)? {
+        if let Some((s, os)) = self._insert(None, rpage, key, value, txn)? {
             let new_root_page = self.pc.new_page()?;
             let mut new_root = Node::new(new_root_page.id, NodeType::Internal, true, &self.schema);
-            self.root = new_root.id;
+            self.root.store(new_root.id, Relaxed);
 
             new_root.insert(s);
             new_root.insert(os);
 
             let mut w = new_root_page.write();
-            writep!(w, &PageBuf::from(&new_root));
+            txn.write_redo_only(&mut w, &PageBuf::from(&new_root));
         }
 
         Ok(())
@@ -78,10 +116,11 @@ where
     // TODO: Duplicate code for find and insert
     fn _insert<'a>(
         &'a self,
-        mut prev_page: Option<&'a PageWriteGuard<'a>>,
-        mut page: PageWriteGuard<'a>,
+        mut prev_page: Option<&'a PinWriteGuard<'a>>,
+        mut page: PinWriteGuard<'a>,
         key: &Tuple,
         value: &V,
+        txn: &Transaction,
     ) -> crate::Result<Option<(Slot<V>, Slot<V>)>> {
         let mut node: Node<V> = Node::from(&page.data, &self.schema);
 
@@ -93,7 +132,7 @@ where
 
             if Comparand(&self.schema, key) >= Comparand(&self.schema, node.last_key().unwrap()) {
                 // Write the node
-                writep!(page, &PageBuf::from(&node));
+                txn.write_redo_only(&mut page, &PageBuf::from(&node));
 
                 // We don't need to keep a lock on this side of the tree
                 drop(page);
@@ -116,7 +155,7 @@ where
                         None => {
                             // Reached leaf node
                             nnode.replace(Slot(key.clone(), Either::Value(value.clone())));
-                            writep!(npage, &PageBuf::from(&nnode));
+                            txn.write_redo_only(&mut npage, &PageBuf::from(&nnode));
 
                             return Ok(node.get_separators(Some(nnode)));
                         }
@@ -126,13 +165,13 @@ where
                     let cpage = child_page.write();
 
                     prev_page.take();
-                    if let Some((s, os)) = self._insert(Some(&npage), cpage, key, value)? {
+                    if let Some((s, os)) = self._insert(Some(&npage), cpage, key, value, txn)? {
                         nnode.replace(s);
                         nnode.replace(os);
                     }
 
                     // Write the new node
-                    writep!(npage, &PageBuf::from(&nnode));
+                    txn.write_redo_only(&mut npage, &PageBuf::from(&nnode));
 
                     return Ok(node.get_separators(Some(nnode)));
                 }
@@ -140,7 +179,7 @@ where
 
             // Write the new node
             // Original node is written below
-            writep!(npage, &PageBuf::from(&nnode));
+            txn.write_redo_only(&mut npage, &PageBuf::from(&nnode));
 
             split = Some(nnode)
         }
@@ -163,7 +202,7 @@ where
                 None => {
                     // Reached leaf node
                     node.replace(Slot(key.clone(), Either::Value(value.clone())));
-                    writep!(page, &PageBuf::from(&node));
+                    txn.write_redo_only(&mut page, &PageBuf::from(&node));
 
                     return Ok(node.get_separators(split));
                 }
@@ -173,13 +212,13 @@ where
             let cpage = child_page.write();
 
             prev_page.take();
-            if let Some((s, os)) = self._insert(Some(&page), cpage, key, value)? {
+            if let Some((s, os)) = self._insert(Some(&page), cpage, key, value, txn)? {
                 node.replace(s);
                 node.replace(os);
             }
 
             // Write the original node
-            writep!(page, &PageBuf::from(&node));
+            txn.write_redo_only(&mut page, &PageBuf::from(&node));
 
             Ok(node.get_separators(split))
         }
@@ -188,11 +227,11 @@ where
     // TODO: return just the values instead? Less cloning
     pub fn scan(&self) -> crate::Result<Vec<(Tuple, V)>> {
         let mut ret = Vec::new();
-        if self.root == -1 {
+        if self.root() == -1 {
             return Ok(ret);
         }
 
-        let pin = self.pc.fetch_page(self.root)?;
+        let pin = self.pc.fetch_page_with(self.root(), AccessType::Scan)?;
         let r = pin.read();
 
         self._scan(None, r, &mut ret)?;
@@ -213,7 +252,7 @@ where
             let Slot(_, v) = node.first().unwrap();
             match v {
                 Either::Pointer(ptr) => {
-                    let pin = self.pc.fetch_page(*ptr)?;
+                    let pin = self.pc.fetch_page_with(*ptr, AccessType::Scan)?;
                     let r = pin.read();
 
                     prev_page.take();
@@ -223,6 +262,11 @@ where
             };
         }
 
+        // Read the next leaf whilst this one is copied out
+        if node.next != -1 {
+            self.pc.prefetch(node.next);
+        }
+
         acc.extend(node.iter().map(|Slot(k, v)| match v {
             Either::Value(v) => (k.clone(), v.clone()),
             Either::Pointer(_) => unreachable!(),
@@ -232,7 +276,7 @@ where
             return Ok(());
         }
 
-        let pin = self.pc.fetch_page(node.next)?;
+        let pin = self.pc.fetch_page_with(node.next, AccessType::Scan)?;
         let r = pin.read();
 
         prev_page.take();
@@ -242,7 +286,7 @@ where
     pub fn range(&self, from: &Tuple, to: &Tuple) -> crate::Result<Vec<(Tuple, V)>> {
         let mut ret = Vec::new();
 
-        let cur = match self.get_ptr(&from, self.root)? {
+        let cur = match self.get_ptr(&from, self.root())? {
             Some(c) => c,
             None => return Ok(ret),
         };
@@ -310,11 +354,18 @@ where
 
     // TODO: return just the value instead? Less cloning
     pub fn get(&self, key: &Tuple) -> crate::Result<Option<Slot<V>>> {
-        if self.root == -1 {
+        if self.root() == -1 {
             return Ok(None);
         }
 
-        self._get(key, self.root)
+        self._get(key, self.root())
+    }
+
+    fn value(&self, key: &Tuple) -> crate::Result<Option<V>> {
+        Ok(self.get(key)?.and_then(|Slot(_, v)| match v {
+            Either::Value(v) => Some(v),
+            Either::Pointer(_) => None,
+        }))
     }
 
     fn _get(&self, key: &Tuple, ptr: PageId) -> crate::Result<Option<Slot<V>>> {
@@ -329,25 +380,30 @@ where
         }
     }
 
-    pub fn delete(&self, key: &Tuple) -> crate::Result<bool> {
-        if self.root == -1 {
+    pub fn delete(&self, key: &Tuple, txn: &Transaction) -> crate::Result<bool> {
+        let Some(old) = self.value(key)? else {
             return Ok(false);
+        };
+
+        let rem = self._delete(key, self.root(), txn)?;
+        if rem {
+            self.on_abort(key, Some(old), None, txn);
         }
 
-        self._delete(key, self.root)
+        Ok(rem)
     }
 
-    fn _delete(&self, key: &Tuple, ptr: PageId) -> crate::Result<bool> {
+    fn _delete(&self, key: &Tuple, ptr: PageId, txn: &Transaction) -> crate::Result<bool> {
         let page = self.pc.fetch_page(ptr)?;
         let mut w = page.write();
         let mut node: Node<V> = Node::from(&w.data, &self.schema);
 
         match node.find_child(&key) {
-            Some(ptr) => self._delete(key, ptr),
+            Some(ptr) => self._delete(key, ptr, txn),
             None if node.t == NodeType::Leaf => {
                 let rem = node.remove(&key);
                 if rem {
-                    writep!(w, &PageBuf::from(&node));
+                    txn.write_redo_only(&mut w, &PageBuf::from(&node));
                 }
                 Ok(rem)
             }
@@ -358,11 +414,11 @@ where
     #[cfg(test)]
     #[allow(dead_code)]
     fn print(&self) {
-        if self.root == -1 {
+        if self.root() == -1 {
             return;
         }
 
-        self._print(self.root);
+        self._print(self.root());
     }
 
     #[cfg(test)]
@@ -408,12 +464,12 @@ where
     #[cfg(test)]
     #[allow(dead_code)]
     fn leaf_count(&self) -> crate::Result<usize> {
-        if self.root == -1 {
+        if self.root() == -1 {
             return Ok(0);
         }
 
         let mut ret = 1;
-        let mut cur = self.first(self.root)?;
+        let mut cur = self.first(self.root())?;
 
         while cur != -1 {
             let pin = self.pc.fetch_page(cur)?;
@@ -435,9 +491,8 @@ mod test {
     use crate::{
         catalog::{Column, Type},
         disk::Memory,
-        page::PAGE_SIZE,
-        page_cache::PageCache,
         replacer::LRU,
+        test::page_cache,
     };
 
     use super::*;
@@ -460,12 +515,12 @@ mod test {
 
     #[test]
     fn test_btree_values() -> crate::Result<()> {
-        const MEMORY: usize = PAGE_SIZE * 16;
         const K: usize = 2;
 
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let lru = LRU::new(K);
-        let pc = PageCache::new(disk, lru, 0);
+        let (pc, tm) = page_cache(disk, lru)?;
+        let txn = tm.begin();
 
         let schema = Schema::new(vec![Column { name: "".into(), ty: Type::Int, offset: 0 }]);
         let mut btree = BTree::new(pc.clone(), &schema);
@@ -475,7 +530,7 @@ mod test {
         let inserts = inserts!(range, i32);
 
         for (k, v) in &inserts {
-            btree.insert(k, v)?;
+            btree.insert(k, v, &txn)?;
         }
 
         pc.flush_all_pages()?;
@@ -489,7 +544,7 @@ mod test {
         // Delete half and make sure they no longer exist in the tree
         let (first_half, second_half) = inserts.split_at(inserts.len() / 2);
         for (k, _) in first_half {
-            btree.delete(k)?;
+            btree.delete(k, &txn)?;
         }
 
         pc.flush_all_pages()?;
@@ -520,7 +575,7 @@ mod test {
         let inserts = inserts!(range, i32);
 
         for (k, v) in &inserts {
-            btree.insert(k, v)?;
+            btree.insert(k, v, &txn)?;
         }
 
         pc.flush_all_pages()?;
@@ -536,12 +591,12 @@ mod test {
 
     #[test]
     fn test_btree_scan() -> crate::Result<()> {
-        const MEMORY: usize = PAGE_SIZE * 16;
         const K: usize = 2;
 
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let lru = LRU::new(K);
-        let pc = PageCache::new(disk, lru, 0);
+        let (pc, tm) = page_cache(disk, lru)?;
+        let txn = tm.begin();
         let pc2 = pc.clone();
 
         let schema = Schema::new(vec![Column { name: "".into(), ty: Type::Int, offset: 0 }]);
@@ -550,7 +605,7 @@ mod test {
         let range = -50..50;
         let mut want = inserts!(range, i32);
         for (k, v) in &want {
-            btree.insert(k, v)?;
+            btree.insert(k, v, &txn)?;
         }
 
         pc2.flush_all_pages()?;
@@ -577,12 +632,12 @@ mod test {
             to: Tuple,
         }
 
-        const MEMORY: usize = PAGE_SIZE * 16;
         const K: usize = 2;
 
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let lru = LRU::new(K);
-        let pc = PageCache::new(disk, lru, 0);
+        let (pc, tm) = page_cache(disk, lru)?;
+        let txn = tm.begin();
         let pc2 = pc.clone();
 
         let tcs = [
@@ -607,7 +662,7 @@ mod test {
 
             let mut inserts = inserts!(range, i32);
             for (k, v) in &inserts {
-                btree.insert(k, v)?;
+                btree.insert(k, v, &txn)?;
             }
 
             pc2.flush_all_pages()?;
@@ -6,7 +6,7 @@ use crate::{
     btree::slot::Either,
     catalog::Schema,
     get_ptr,
-    page::{PageBuf, PageId, PAGE_SIZE},
+    page::{PageBuf, PageId, PAGE_HEADER_SIZE, PAGE_SIZE},
     storable::Storable,
     table::tuple::{Comparand, Tuple},
 };
@@ -47,14 +47,14 @@ impl std::fmt::Display for NodeType {
     }
 }
 
-const NODE_TYPE: usize = 0;
-const NODE_IS_ROOT: usize = 1;
-const NODE_LEN: Range<usize> = 2..6;
-const NODE_NEXT: Range<usize> = 6..10;
-const NODE_ID: Range<usize> = 10..14;
-const NODE_VALUES_START: usize = 14;
+const NODE_TYPE: usize = PAGE_HEADER_SIZE;
+const NODE_IS_ROOT: usize = PAGE_HEADER_SIZE + 1;
+const NODE_LEN: Range<usize> = PAGE_HEADER_SIZE + 2..PAGE_HEADER_SIZE + 6;
+const NODE_NEXT: Range<usize> = PAGE_HEADER_SIZE + 6..PAGE_HEADER_SIZE + 14;
+const NODE_ID: Range<usize> = PAGE_HEADER_SIZE + 14..PAGE_HEADER_SIZE + 22;
+const NODE_VALUES_START: usize = PAGE_HEADER_SIZE + 22;
 
-// | NodeType (1) | Root (1) | Len (4) | Max (4) | Next (4) | PageId (4) | Values
+// PageHeader | NodeType (1) | Root (1) | Len (4) | Next (8) | PageId (8) | Values
 #[derive(Clone, Debug)]
 pub struct Node<'s, V> {
     pub t: NodeType,
@@ -125,7 +125,7 @@ where
             from += size;
         }
 
-        if ret == [0; 4096] {
+        if ret == [0; PAGE_SIZE] {
             panic!("PageBuf::from(Node) produced an empty buffer");
         }
 
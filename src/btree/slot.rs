@@ -31,7 +31,11 @@ macro_rules! get_value {
 }
 
 impl<V> Either<V> {
-    pub const SIZE: usize = 1 + size_of::<V>();
+    /// Room for whichever of a value or a pointer is larger
+    pub const SIZE: usize = 1 + match size_of::<V>() > size_of::<PageId>() {
+        true => size_of::<V>(),
+        false => size_of::<PageId>(),
+    };
 }
 
 impl<V> From<&[u8]> for Either<V>
@@ -45,12 +49,11 @@ where
         let value = &value[1..];
         match either {
             0 => {
-                let value = V::from_bytes(value);
+                let value = V::from_bytes(&value[..size_of::<V>()]);
                 Either::Value(value)
             }
             1 => {
-                let b: [u8; 4] = value.try_into().unwrap();
-                let ptr = i32::from_be_bytes(b);
+                let ptr = PageId::from_be_bytes(value[..size_of::<PageId>()].try_into().unwrap());
                 Either::Pointer(ptr)
             }
             _ => unreachable!(),
@@ -79,7 +82,7 @@ where
     }
 }
 
-// Size = 1 + Schema + size_of::<V>()
+// Size = Schema + Either::SIZE
 // | Key | Flag (1) | Value
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Slot<V>(pub Tuple, pub Either<V>);
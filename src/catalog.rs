@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::atomic::{AtomicU32, Ordering::Relaxed},
 };
 
@@ -8,10 +8,13 @@ use crate::{
     disk::{Disk, FileSystem},
     page::PageId,
     page_cache::SharedPageCache,
+    replacer::{Replacer, LRU},
     table::{
-        list::List as Table,
+        list::{List as Table, VacuumStats},
         tuple::{RId, Tuple},
     },
+    transaction::Transaction,
+    wal::TxnId,
 };
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -124,11 +127,11 @@ impl Schema {
 
 pub type OId = u32;
 
-pub struct TableInfo<D: Disk = FileSystem> {
+pub struct TableInfo<D: Disk = FileSystem, R: Replacer = LRU> {
     name: String,
     schema: Schema,
     oid: OId,
-    table: Table<D>,
+    table: Table<D, R>,
 }
 
 pub struct IndexMeta {
@@ -151,9 +154,9 @@ pub struct IndexInfo {
     root: PageId,
 }
 
-pub struct Catalog<D: Disk = FileSystem> {
-    pc: SharedPageCache<D>,
-    tables: HashMap<OId, TableInfo<D>>,
+pub struct Catalog<D: Disk = FileSystem, R: Replacer = LRU> {
+    pc: SharedPageCache<D, R>,
+    tables: HashMap<OId, TableInfo<D, R>>,
     table_names: HashMap<String, OId>,
     next_table_oid: AtomicU32,
     indexes: HashMap<OId, IndexInfo>,
@@ -161,8 +164,8 @@ pub struct Catalog<D: Disk = FileSystem> {
     next_index_oid: AtomicU32,
 }
 
-impl<D: Disk> Catalog<D> {
-    pub fn new(pc: SharedPageCache<D>) -> Self {
+impl<D: Disk + Send + Sync + 'static, R: Replacer + 'static> Catalog<D, R> {
+    pub fn new(pc: SharedPageCache<D, R>) -> Self {
         Self {
             pc,
             tables: HashMap::new(),
@@ -178,7 +181,7 @@ impl<D: Disk> Catalog<D> {
         &mut self,
         name: &str,
         schema: Schema,
-    ) -> crate::Result<Option<&TableInfo<D>>> {
+    ) -> crate::Result<Option<&TableInfo<D, R>>> {
         if self.table_names.contains_key(name) {
             return Ok(None);
         }
@@ -194,11 +197,11 @@ impl<D: Disk> Catalog<D> {
         Ok(self.tables.get(&oid))
     }
 
-    pub fn get_table_by_oid(&self, oid: OId) -> Option<&TableInfo<D>> {
+    pub fn get_table_by_oid(&self, oid: OId) -> Option<&TableInfo<D, R>> {
         self.tables.get(&oid)
     }
 
-    pub fn get_table_by_name(&self, name: &str) -> Option<&TableInfo<D>> {
+    pub fn get_table_by_name(&self, name: &str) -> Option<&TableInfo<D, R>> {
         self.tables.get(self.table_names.get(name)?)
     }
 
@@ -213,6 +216,7 @@ impl<D: Disk> Catalog<D> {
         index_ty: IndexType,
         schema: &Schema,
         key: &[&str],
+        txn: &Transaction,
     ) -> Option<&IndexInfo> {
         // TODO: verify key schema against table schema
 
@@ -236,13 +240,13 @@ impl<D: Disk> Catalog<D> {
         match index_ty {
             IndexType::HashTable => todo!(),
             IndexType::BTree => {
-                let mut btree = BTree::<RId, _>::new(self.pc.clone(), &index_schema);
+                let mut btree = BTree::<RId, _, _>::new(self.pc.clone(), &index_schema);
                 let info = self.tables.get(&self.table_names[table_name])?;
-                for result in info.table.iter().expect("todo") {
+                for result in info.table.iter(txn.snapshot()).expect("todo") {
                     // Remove columns from the tuple to match schema
                     let (_, Tuple { rid, data }) = result.expect("todo");
                     let tuple = Tuple::from(&data, &tuple_schema);
-                    btree.insert(&tuple, &rid).expect("todo");
+                    btree.insert(&tuple, &rid, txn).expect("todo");
                 }
 
                 root = btree.root();
@@ -272,6 +276,27 @@ impl<D: Disk> Catalog<D> {
     pub fn list_indexes(&self) -> Vec<&IndexInfo> {
         self.indexes.iter().map(|(_, info)| info).collect()
     }
+
+    /// Vacuum the table, pages its indexes point into aren't freed
+    pub fn vacuum(
+        &self,
+        table_name: &str,
+        horizon: TxnId,
+        txn: &Transaction,
+    ) -> crate::Result<Option<VacuumStats>> {
+        let Some(info) = self.get_table_by_name(table_name) else {
+            return Ok(None);
+        };
+
+        let mut indexed = HashSet::new();
+        for oid in self.index_names[table_name].values() {
+            let IndexInfo { schema, root, .. } = &self.indexes[oid];
+            let btree = BTree::<RId, _, _>::new_with_root(self.pc.clone(), *root, schema);
+            indexed.extend(btree.scan()?.into_iter().map(|(_, rid)| rid.page_id));
+        }
+
+        info.table.vacuum(horizon, &indexed, txn).map(Some)
+    }
 }
 
 #[cfg(test)]
@@ -283,18 +308,18 @@ mod test {
         catalog::{Catalog, IndexType, Schema, Type},
         disk::Memory,
         page::PAGE_SIZE,
-        page_cache::PageCache,
         replacer::LRU,
         table::tuple::{RId, Tuple, TupleBuilder, TupleMeta, Value},
+        test::page_cache,
     };
 
     #[test]
     fn test_btree_index() -> crate::Result<()> {
-        const MEMORY: usize = PAGE_SIZE * 16;
         const K: usize = 2;
-        let memory = Memory::new::<MEMORY>();
+        let memory = Memory::default();
         let replacer = LRU::new(K);
-        let pc = PageCache::new(memory, replacer, 0);
+        let (pc, tm) = page_cache(memory, replacer)?;
+        let txn = tm.begin();
 
         struct Test {
             schema: Schema,
@@ -393,13 +418,20 @@ mod test {
 
             for tuple in tuples {
                 info.table
-                    .insert(&tuple, &TupleMeta { deleted: false })?
+                    .insert(&tuple, &TupleMeta::default(), &txn)?
                     .expect("there should be a rid");
             }
 
             let index_schema = schema.filter(key).compact();
 
-            catalog.create_index(INDEX_A, TABLE_A, IndexType::BTree, &schema, &["col_a", "col_c"]);
+            catalog.create_index(
+                INDEX_A,
+                TABLE_A,
+                IndexType::BTree,
+                &schema,
+                &["col_a", "col_c"],
+                &txn,
+            );
             let index = catalog.get_index(TABLE_A, INDEX_A).expect("index_a should exist");
             let index: BTree<RId, _> = BTree::new_with_root(pc.clone(), index.root, &index_schema);
             let have = index.scan()?;
@@ -409,4 +441,51 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_vacuum() -> crate::Result<()> {
+        const TABLE_A: &str = "table_a";
+        const INDEX_A: &str = "index_a";
+
+        let (pc, tm) = page_cache(Memory::default(), LRU::new(2))?;
+        let schema: Schema = [("col_a", Type::Int), ("col_b", Type::Varchar)].into();
+        let mut catalog = Catalog::new(pc.clone());
+        catalog.create_table(TABLE_A, schema.clone())?;
+        let info = catalog.get_table_by_name(TABLE_A).expect("table_a should exist");
+
+        // Fill two pages and start a third
+        let txn = tm.begin();
+        let mut rids = Vec::new();
+        let mut pages = Vec::new();
+        while pages.len() < 3 {
+            let tuple = TupleBuilder::new()
+                .add(&Value::Int(rids.len() as i32))
+                .add(&Value::Varchar("a".repeat(PAGE_SIZE / 8)))
+                .build();
+            let rid = info.table.insert(&tuple, &TupleMeta::default(), &txn)?.unwrap();
+            if !pages.contains(&rid.page_id) {
+                pages.push(rid.page_id);
+            }
+            rids.push(rid);
+        }
+        catalog.create_index(INDEX_A, TABLE_A, IndexType::BTree, &schema, &["col_a"], &txn);
+        tm.commit(&txn)?;
+
+        let info = catalog.get_table_by_name(TABLE_A).expect("table_a should exist");
+        let middle = rids.iter().filter(|r| r.page_id == pages[1]).collect::<Vec<_>>();
+        let txn = tm.begin();
+        for rid in &middle {
+            assert!(info.table.delete(**rid, &txn)?);
+        }
+        tm.commit(&txn)?;
+
+        // The index still holds the deleted rows' ids
+        let txn = tm.begin();
+        let stats = catalog.vacuum(TABLE_A, tm.horizon(), &txn)?.unwrap();
+        tm.commit(&txn)?;
+        assert_eq!(stats.pages_freed, 0);
+        assert_eq!(stats.tuples_removed, middle.len());
+
+        Ok(())
+    }
 }
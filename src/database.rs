@@ -0,0 +1,363 @@
+use std::{ops::Range, path::Path, sync::Arc, time::Duration};
+
+use crate::{
+    disk::{Disk, FileSystem, Options},
+    page::{set_checksum, verify_checksum, PageBuf, PageId, PAGE_HEADER_SIZE, PAGE_SIZE},
+    page_cache::{Flusher, PageCache, PageCacheError, Result, SharedPageCache, CACHE_SIZE},
+    replacer::LRU,
+    transaction::TransactionManager,
+    wal::{LogFile, Wal},
+};
+
+/*
+    Superblock (page 0):
+    PageHeader | Magic (8) | Version (4) | PageSize (4) | NextPageId (8) | FreeMapRoot (8) |
+    CatalogRoot (8)
+*/
+
+pub const MAGIC: [u8; 8] = *b"BASEDB\0\0";
+pub const FORMAT_VERSION: u32 = 3;
+pub const SUPERBLOCK_PAGE_ID: PageId = 0;
+
+const MAGIC_RANGE: Range<usize> = PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8;
+const VERSION: Range<usize> = PAGE_HEADER_SIZE + 8..PAGE_HEADER_SIZE + 12;
+const PAGE_SIZE_RANGE: Range<usize> = PAGE_HEADER_SIZE + 12..PAGE_HEADER_SIZE + 16;
+const NEXT_PAGE_ID: Range<usize> = PAGE_HEADER_SIZE + 16..PAGE_HEADER_SIZE + 24;
+const FREE_MAP_ROOT: Range<usize> = PAGE_HEADER_SIZE + 24..PAGE_HEADER_SIZE + 32;
+const CATALOG_ROOT: Range<usize> = PAGE_HEADER_SIZE + 32..PAGE_HEADER_SIZE + 40;
+
+const K: usize = 2;
+
+/// How often dirty pages are written in the background
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Superblock {
+    pub version: u32,
+    pub page_size: u32,
+    pub next_page_id: PageId,
+    /// -1 if nothing has been deallocated
+    pub free_map_root: PageId,
+    /// -1 if there is no catalog
+    pub catalog_root: PageId,
+}
+
+impl Default for Superblock {
+    fn default() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            next_page_id: SUPERBLOCK_PAGE_ID + 1,
+            free_map_root: -1,
+            catalog_root: -1,
+        }
+    }
+}
+
+impl TryFrom<&PageBuf> for Superblock {
+    type Error = PageCacheError;
+
+    fn try_from(buf: &PageBuf) -> Result<Self> {
+        let u32_at = |range: Range<usize>| u32::from_be_bytes(buf[range].try_into().unwrap());
+        let page_id_at =
+            |range: Range<usize>| PageId::from_be_bytes(buf[range].try_into().unwrap());
+
+        if buf[MAGIC_RANGE] != MAGIC {
+            return Err(PageCacheError::Incompatible("not a database file".into()));
+        }
+        if !verify_checksum(buf) {
+            return Err(PageCacheError::Corrupt { page_id: SUPERBLOCK_PAGE_ID });
+        }
+
+        let version = u32_at(VERSION);
+        if version != FORMAT_VERSION {
+            return Err(PageCacheError::Incompatible(format!(
+                "format version {version}, expected {FORMAT_VERSION}"
+            )));
+        }
+
+        let page_size = u32_at(PAGE_SIZE_RANGE);
+        if page_size as usize != PAGE_SIZE {
+            return Err(PageCacheError::Incompatible(format!(
+                "page size {page_size}, expected {PAGE_SIZE}"
+            )));
+        }
+
+        Ok(Self {
+            version,
+            page_size,
+            next_page_id: page_id_at(NEXT_PAGE_ID),
+            free_map_root: page_id_at(FREE_MAP_ROOT),
+            catalog_root: page_id_at(CATALOG_ROOT),
+        })
+    }
+}
+
+impl From<&Superblock> for PageBuf {
+    fn from(sb: &Superblock) -> Self {
+        let mut ret: PageBuf = [0; PAGE_SIZE];
+
+        ret[MAGIC_RANGE].copy_from_slice(&MAGIC);
+        ret[VERSION].copy_from_slice(&sb.version.to_be_bytes());
+        ret[PAGE_SIZE_RANGE].copy_from_slice(&sb.page_size.to_be_bytes());
+        ret[NEXT_PAGE_ID].copy_from_slice(&sb.next_page_id.to_be_bytes());
+        ret[FREE_MAP_ROOT].copy_from_slice(&sb.free_map_root.to_be_bytes());
+        ret[CATALOG_ROOT].copy_from_slice(&sb.catalog_root.to_be_bytes());
+
+        ret
+    }
+}
+
+pub struct Database<D: Disk = FileSystem> {
+    pc: SharedPageCache<D>,
+    tm: TransactionManager<D>,
+    catalog_root: PageId,
+    flusher: Option<Flusher>,
+}
+
+impl Database<FileSystem> {
+    /// Open or create the database at `path`, the log is kept next to it at `{path}.wal`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_capacity(path, CACHE_SIZE)
+    }
+
+    /// Open the database with `frames` pages of cache
+    pub fn open_with_capacity(path: impl AsRef<Path>, frames: usize) -> Result<Self> {
+        Self::open_with_options(path, frames, Options::default())
+    }
+
+    /// The log is synced with the same durability as the database file
+    pub fn open_with_options(
+        path: impl AsRef<Path>,
+        frames: usize,
+        options: Options,
+    ) -> Result<Self> {
+        let mut wal_path = path.as_ref().as_os_str().to_owned();
+        wal_path.push(".wal");
+
+        let disk = FileSystem::with_options(&path, options)?;
+        let wal = Wal::new(LogFile::with_durability(wal_path, options.durability)?)?;
+
+        let mut db = Self::open_with(disk, wal, frames)?;
+        db.flusher = Some(db.pc.start_flusher(FLUSH_INTERVAL));
+
+        Ok(db)
+    }
+}
+
+impl<D: Disk> Database<D> {
+    pub fn open_with(disk: D, wal: Arc<Wal>, frames: usize) -> Result<Self> {
+        let data = disk.read_page(SUPERBLOCK_PAGE_ID)?;
+        let sb = if data.iter().all(|b| *b == 0) {
+            let sb = Superblock::default();
+            let mut data = PageBuf::from(&sb);
+            set_checksum(&mut data);
+            disk.write_page(SUPERBLOCK_PAGE_ID, &data)?;
+
+            sb
+        } else {
+            Superblock::try_from(&data)?
+        };
+
+        let pc = PageCache::open_with_capacity(disk, LRU::new(K), sb.next_page_id, wal, frames)?;
+        if sb.free_map_root != -1 {
+            pc.set_free_map_root(sb.free_map_root)?;
+        }
+        let tm = TransactionManager::new(pc.clone())?;
+        let db = Self { pc, tm, catalog_root: sb.catalog_root, flusher: None };
+
+        // The root never changes once it's stored, the map pages are logged as they change
+        if sb.free_map_root == -1 {
+            db.pc.init_free_map()?;
+            db.write_superblock()?;
+        }
+
+        Ok(db)
+    }
+
+    pub fn pc(&self) -> &SharedPageCache<D> {
+        &self.pc
+    }
+
+    pub fn tm(&self) -> &TransactionManager<D> {
+        &self.tm
+    }
+
+    pub fn catalog_root(&self) -> Option<PageId> {
+        (self.catalog_root != -1).then_some(self.catalog_root)
+    }
+
+    /// The superblock is written and synced straight away, nothing else would make it durable
+    /// before a clean shutdown
+    pub fn set_catalog_root(&mut self, page_id: PageId) -> Result<()> {
+        self.catalog_root = page_id;
+        self.write_superblock()
+    }
+
+    pub fn superblock(&self) -> Superblock {
+        Superblock {
+            next_page_id: self.pc.next_page_id(),
+            free_map_root: self.pc.free_map_root().unwrap_or(-1),
+            catalog_root: self.catalog_root,
+            ..Default::default()
+        }
+    }
+
+    /// Write every page and then the superblock, and sync the file
+    pub fn flush(&self) -> Result<()> {
+        self.pc.flush_all_pages()?;
+        self.write_superblock()
+    }
+
+    /// Write the superblock and sync the file. The next page id is also recovered from the log, so
+    /// it only has to be written here when the superblock changes for another reason.
+    fn write_superblock(&self) -> Result<()> {
+        let page = self.pc.fetch_page(SUPERBLOCK_PAGE_ID)?;
+        let mut page_w = page.write();
+        page_w.data = PageBuf::from(&self.superblock());
+        page_w.dirty = true;
+        drop(page_w);
+        drop(page);
+
+        self.pc.flush_page(SUPERBLOCK_PAGE_ID)?;
+        self.pc.sync()
+    }
+}
+
+impl<D: Disk> Drop for Database<D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("ERROR: could not flush database - {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+
+    use crate::{
+        database::{Database, Superblock, FORMAT_VERSION, SUPERBLOCK_PAGE_ID},
+        disk::{Disk, Faulty, FileSystem, Memory},
+        page::{set_checksum, PageBuf, PAGE_SIZE},
+        page_cache::PageCacheError,
+        table::{list::List, tuple::TupleMeta},
+        test::CleanUp,
+        wal::{LogMemory, Wal},
+    };
+
+    #[test]
+    fn test_database_open() -> crate::Result<()> {
+        const DB: &str = "test_database_open.db";
+        let _cleanup = (CleanUp::file(DB), CleanUp::file("test_database_open.db.wal"));
+
+        let (first_page_id, freed, want) = {
+            let mut db = Database::open(DB)?;
+            let txn = db.tm().begin();
+            let list = List::default(db.pc().clone())?;
+            let rid = list.insert(&BytesMut::from(&b"row"[..]), &TupleMeta::default(), &txn)?;
+            db.tm().commit(&txn)?;
+
+            let freed = db.pc().new_page()?.id;
+            db.pc().deallocate_page(freed)?;
+            db.set_catalog_root(rid.unwrap().page_id)?;
+
+            (rid.unwrap().page_id, freed, db.superblock())
+        };
+        assert_ne!(first_page_id, SUPERBLOCK_PAGE_ID);
+
+        let db = Database::open_with_capacity(DB, 4)?;
+        assert_eq!(db.pc().capacity(), 4);
+        assert_eq!(db.superblock(), want);
+        assert_eq!(db.catalog_root(), Some(first_page_id));
+
+        // Freed pages are reused after reopening
+        assert_eq!(db.pc().new_page()?.id, freed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_database_incompatible() -> crate::Result<()> {
+        const DB: &str = "test_database_incompatible.db";
+        let _cleanup = (CleanUp::file(DB), CleanUp::file("test_database_incompatible.db.wal"));
+
+        let disk = FileSystem::new(DB)?;
+        disk.write_page(SUPERBLOCK_PAGE_ID, &[1; PAGE_SIZE])?;
+        assert_eq!(
+            Database::open(DB).err(),
+            Some(PageCacheError::Incompatible("not a database file".into()))
+        );
+
+        let write = |sb: &Superblock| {
+            let mut data = PageBuf::from(sb);
+            set_checksum(&mut data);
+            disk.write_page(SUPERBLOCK_PAGE_ID, &data)
+        };
+
+        write(&Superblock { version: FORMAT_VERSION + 1, ..Default::default() })?;
+        assert!(matches!(Database::open(DB), Err(PageCacheError::Incompatible(_))));
+
+        write(&Superblock { page_size: PAGE_SIZE as u32 * 2, ..Default::default() })?;
+        assert!(matches!(Database::open(DB), Err(PageCacheError::Incompatible(_))));
+
+        let mut data = PageBuf::from(&Superblock::default());
+        set_checksum(&mut data);
+        data[100] = 1;
+        disk.write_page(SUPERBLOCK_PAGE_ID, &data)?;
+        assert_eq!(Database::open(DB).err(), Some(PageCacheError::Corrupt { page_id: 0 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_database_crash_free_map() -> crate::Result<()> {
+        let disk = Arc::new(Faulty::new(Memory::default()));
+        let log = Arc::new(LogMemory::default());
+
+        let freed = {
+            let db = Database::open_with(disk.clone(), Wal::new(log.clone())?, 4)?;
+            let txn = db.tm().begin();
+            let freed = db.pc().new_page()?.id;
+            txn.deallocate(freed);
+            db.tm().commit(&txn)?;
+
+            // Skip the flush on drop
+            std::mem::forget(db);
+            freed
+        };
+        disk.crash()?;
+
+        let db = Database::open_with(disk.clone(), Wal::new(log)?, 4)?;
+        assert_eq!(db.pc().new_page()?.id, freed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_database_crash_superblock() -> crate::Result<()> {
+        let disk = Arc::new(Faulty::new(Memory::default()));
+        let log = Arc::new(LogMemory::default());
+
+        let want = {
+            let mut db = Database::open_with(disk.clone(), Wal::new(log.clone())?, 4)?;
+            let txn = db.tm().begin();
+            let list = List::default(db.pc().clone())?;
+            let rid = list.insert(&BytesMut::from(&b"row"[..]), &TupleMeta::default(), &txn)?;
+            db.tm().commit(&txn)?;
+            db.set_catalog_root(rid.unwrap().page_id)?;
+
+            let want = db.superblock();
+            std::mem::forget(db);
+            want
+        };
+        disk.crash()?;
+
+        let db = Database::open_with(disk.clone(), Wal::new(log)?, 4)?;
+        assert_eq!(db.superblock(), want);
+
+        Ok(())
+    }
+}
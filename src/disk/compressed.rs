@@ -0,0 +1,438 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    disk::Disk,
+    page::{PageBuf, PageId, PAGE_SIZE},
+};
+
+/*
+    Extent map, kept next to the data file at `{path}.map`:
+    Magic (8) | End (8) | Count (4) | Extent* | Checksum (4)
+
+    Extent:
+    PageId (8) | Offset (8) | Len (4) | Cap (4)
+*/
+
+const MAP_MAGIC: &[u8; 8] = b"BASEMAP\0";
+const MAP_HEADER_SIZE: usize = 8 + 8 + 4;
+const EXTENT_SIZE: usize = 8 + 8 + 4 + 4;
+
+/// Extents are allocated in multiples of this, so the free space left between them is never too
+/// small to be reused
+const EXTENT_ALIGN: u32 = 256;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Extent {
+    offset: u64,
+    /// A page that doesn't compress is stored as is, with a length of `PAGE_SIZE`
+    len: u32,
+    cap: u32,
+}
+
+/// Free space between the extents, found by length for the best fit and by offset to merge
+/// neighbours
+#[derive(Default)]
+struct FreeSpace {
+    by_offset: BTreeMap<u64, u64>,
+    by_len: BTreeSet<(u64, u64)>,
+}
+
+impl FreeSpace {
+    fn insert(&mut self, mut offset: u64, mut len: u64) {
+        if let Some((&prev, &prev_len)) = self.by_offset.range(..offset).next_back() {
+            if prev + prev_len == offset {
+                self.remove(prev, prev_len);
+                offset = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(&next_len) = self.by_offset.get(&(offset + len)) {
+            self.remove(offset + len, next_len);
+            len += next_len;
+        }
+
+        self.by_offset.insert(offset, len);
+        self.by_len.insert((len, offset));
+    }
+
+    fn remove(&mut self, offset: u64, len: u64) {
+        self.by_offset.remove(&offset);
+        self.by_len.remove(&(len, offset));
+    }
+
+    /// Take `len` bytes from the smallest free space they fit in, the rest of it stays free
+    fn take(&mut self, len: u64) -> Option<u64> {
+        let (free_len, offset) = self.by_len.range((len, 0)..).next().copied()?;
+        self.remove(offset, free_len);
+        if free_len > len {
+            self.insert(offset + len, free_len - len);
+        }
+
+        Some(offset)
+    }
+}
+
+#[derive(Default)]
+struct Extents {
+    map: HashMap<PageId, Extent>,
+    free: FreeSpace,
+    /// Extents freed since the map was last saved. The saved map can still point at them, so they
+    /// can't be reused until it has been replaced.
+    freed: Vec<(u32, u64)>,
+    /// Pages moved to a new extent since the map was saved. Only these are overwritten in place,
+    /// the saved map has to point at intact pages if the map isn't saved again.
+    moved: HashSet<PageId>,
+    /// End of the last extent
+    end: u64,
+}
+
+impl Extents {
+    fn allocate(&mut self, len: u32) -> Extent {
+        let cap = len.div_ceil(EXTENT_ALIGN) * EXTENT_ALIGN;
+        if let Some(offset) = self.free.take(cap as u64) {
+            return Extent { offset, len, cap };
+        }
+
+        let offset = self.end;
+        self.end += cap as u64;
+
+        Extent { offset, len, cap }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(MAP_HEADER_SIZE + self.map.len() * EXTENT_SIZE + 4);
+        ret.extend_from_slice(MAP_MAGIC);
+        ret.extend_from_slice(&self.end.to_be_bytes());
+        ret.extend_from_slice(&(self.map.len() as u32).to_be_bytes());
+        for (page_id, extent) in &self.map {
+            ret.extend_from_slice(&page_id.to_be_bytes());
+            ret.extend_from_slice(&extent.offset.to_be_bytes());
+            ret.extend_from_slice(&extent.len.to_be_bytes());
+            ret.extend_from_slice(&extent.cap.to_be_bytes());
+        }
+        ret.extend_from_slice(&crc32c::crc32c(&ret).to_be_bytes());
+
+        ret
+    }
+
+    fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed extent map");
+
+        if buf.len() < MAP_HEADER_SIZE + 4 || &buf[0..8] != MAP_MAGIC {
+            return Err(invalid());
+        }
+
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        if crc32c::crc32c(body).to_be_bytes() != crc {
+            return Err(invalid());
+        }
+
+        let end = u64::from_be_bytes(body[8..16].try_into().unwrap());
+        let count = u32::from_be_bytes(body[16..20].try_into().unwrap()) as usize;
+        if body.len() != MAP_HEADER_SIZE + count * EXTENT_SIZE {
+            return Err(invalid());
+        }
+
+        let map: HashMap<_, _> = body[MAP_HEADER_SIZE..]
+            .chunks_exact(EXTENT_SIZE)
+            .map(|c| {
+                let page_id = PageId::from_be_bytes(c[0..8].try_into().unwrap());
+                let offset = u64::from_be_bytes(c[8..16].try_into().unwrap());
+                let len = u32::from_be_bytes(c[16..20].try_into().unwrap());
+                let cap = u32::from_be_bytes(c[20..24].try_into().unwrap());
+
+                (page_id, Extent { offset, len, cap })
+            })
+            .collect();
+
+        // Anything between the extents in use is free
+        let mut used: Vec<_> = map.values().map(|e| (e.offset, e.cap as u64)).collect();
+        used.sort();
+        let mut free = FreeSpace::default();
+        let mut next = 0;
+        for (offset, cap) in used.into_iter().chain([(end, 0)]) {
+            if offset > next {
+                free.insert(next, offset - next);
+            }
+            next = offset + cap;
+        }
+
+        Ok(Self { map, free, freed: Vec::new(), moved: HashSet::new(), end })
+    }
+}
+
+/// Stores pages compressed with LZ4. Each page is written to an extent just large enough for it,
+/// found through the extent map. Extents are cut from the smallest free space that fits. The map is saved when the disk is synced, until then pages are
+/// written to new extents so a crash leaves the saved map pointing at the synced pages.
+pub struct Compressed {
+    file: File,
+    map_path: PathBuf,
+    extents: Mutex<Extents>,
+}
+
+impl Compressed {
+    pub fn new(file: impl AsRef<Path>) -> io::Result<Self> {
+        let mut map_path = OsString::from(file.as_ref());
+        map_path.push(".map");
+        let map_path = PathBuf::from(map_path);
+
+        let extents = match fs::read(&map_path) {
+            Ok(buf) => Extents::from_bytes(&buf)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Extents::default(),
+            Err(e) => return Err(e),
+        };
+
+        let file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(file)?;
+
+        Ok(Self { file, map_path, extents: Mutex::new(extents) })
+    }
+
+    /// The number of bytes the pages take up in the file
+    pub fn size(&self) -> u64 {
+        self.extents.lock().expect("todo").end
+    }
+}
+
+impl Disk for Compressed {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        let extents = self.extents.lock().expect("todo");
+        let Some(extent) = extents.map.get(&page_id).copied() else {
+            // Never written
+            return Ok([0; PAGE_SIZE]);
+        };
+
+        let mut buf = vec![0; extent.len as usize];
+        self.file.read_exact_at(&mut buf, extent.offset)?;
+        drop(extents);
+
+        let mut ret = [0; PAGE_SIZE];
+        if extent.len as usize == PAGE_SIZE {
+            ret.copy_from_slice(&buf);
+            return Ok(ret);
+        }
+
+        match lz4_flex::block::decompress_into(&buf, &mut ret) {
+            Ok(PAGE_SIZE) => Ok(ret),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "page could not be decompressed")),
+        }
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        let compressed = lz4_flex::block::compress(data);
+        let buf = match compressed.len() < PAGE_SIZE {
+            true => &compressed[..],
+            false => &data[..],
+        };
+        let len = buf.len() as u32;
+
+        let mut extents = self.extents.lock().expect("todo");
+        let moved = extents.moved.contains(&page_id);
+        let extent = match extents.map.get(&page_id).copied() {
+            Some(extent) if moved && extent.cap >= len => Extent { len, ..extent },
+            // Not in the saved map, so it can be reused straight away
+            Some(extent) if moved => {
+                extents.free.insert(extent.offset, extent.cap as u64);
+                extents.allocate(len)
+            }
+            Some(extent) => {
+                extents.freed.push((extent.cap, extent.offset));
+                extents.allocate(len)
+            }
+            None => extents.allocate(len),
+        };
+
+        self.file.write_all_at(buf, extent.offset)?;
+        extents.map.insert(page_id, extent);
+        extents.moved.insert(page_id);
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut extents = self.extents.lock().expect("todo");
+        self.file.sync_data()?;
+
+        // Replace the map in one step so a crash leaves either the old or the new one
+        let mut tmp = self.map_path.clone().into_os_string();
+        tmp.push(".tmp");
+        let map = File::create(&tmp)?;
+        map.write_all_at(&extents.to_bytes(), 0)?;
+        map.sync_data()?;
+        fs::rename(&tmp, &self.map_path)?;
+        if let Some(dir) = self.map_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+
+        for (cap, offset) in std::mem::take(&mut extents.freed) {
+            extents.free.insert(offset, cap as u64);
+        }
+        extents.moved.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+
+    use crate::{
+        disk::{Compressed, Disk},
+        page::{PageBuf, PageId, PAGE_SIZE},
+        page_cache::PageCache,
+        replacer::LRU,
+        test::CleanUp,
+        writep,
+    };
+
+    fn text(i: usize) -> PageBuf {
+        let mut ret = [0; PAGE_SIZE];
+        for (j, chunk) in ret.chunks_mut(16).enumerate() {
+            chunk[..8].copy_from_slice(&((i * 1000 + j) as u64).to_be_bytes());
+            chunk[8..].copy_from_slice(b"row text");
+        }
+
+        ret
+    }
+
+    #[test]
+    fn test_compressed() -> std::io::Result<()> {
+        const FILE: &str = "test_compressed.db";
+        let _cleanup = (CleanUp::file(FILE), CleanUp::file("test_compressed.db.map"));
+
+        let mut random = [0; PAGE_SIZE];
+        rand::thread_rng().fill(&mut random[..]);
+
+        {
+            let disk = Compressed::new(FILE)?;
+            for i in 0..16 {
+                disk.write_page(i as PageId, &text(i))?;
+            }
+            assert!(disk.size() < 16 * PAGE_SIZE as u64 / 2);
+            assert_eq!(disk.read_page(100)?, [0; PAGE_SIZE]);
+
+            // Pages that grow move to a larger extent, pages that don't compress are stored as is
+            disk.write_page(3, &random)?;
+            disk.write_page(4, &[0; PAGE_SIZE])?;
+            disk.sync()?;
+
+            // Page 3's old extent is free once the map has been saved
+            let size = disk.size();
+            disk.write_page(20, &text(3))?;
+            assert_eq!(disk.size(), size);
+        }
+
+        let disk = Compressed::new(FILE)?;
+        for i in (0..16).filter(|i| ![3, 4].contains(i)) {
+            assert_eq!(disk.read_page(i as PageId)?, text(i));
+        }
+        assert_eq!(disk.read_page(3)?, random);
+        assert_eq!(disk.read_page(4)?, [0; PAGE_SIZE]);
+
+        // Writes since the last sync aren't in the saved map
+        assert_eq!(disk.read_page(20)?, [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_reuse() -> std::io::Result<()> {
+        const FILE: &str = "test_compressed_reuse.db";
+        let _cleanup = (CleanUp::file(FILE), CleanUp::file("test_compressed_reuse.db.map"));
+
+        let random = || {
+            let mut ret = [0; PAGE_SIZE];
+            rand::thread_rng().fill(&mut ret[..]);
+            ret
+        };
+
+        // Small extents next to each other, together larger than a page
+        let disk = Compressed::new(FILE)?;
+        let mut pages = 0;
+        while disk.size() < PAGE_SIZE as u64 {
+            disk.write_page(pages, &text(pages as usize))?;
+            pages += 1;
+        }
+        disk.sync()?;
+
+        // Their space is merged once they move, so a page that doesn't compress fits
+        for i in 0..pages {
+            disk.write_page(i, &random())?;
+        }
+        disk.sync()?;
+        let size = disk.size();
+        disk.write_page(pages, &random())?;
+        assert_eq!(disk.size(), size);
+
+        // A small page is cut from a larger free extent
+        disk.write_page(0, &text(0))?;
+        disk.sync()?;
+        let size = disk.size();
+        disk.write_page(pages + 1, &text(1))?;
+        assert_eq!(disk.size(), size);
+
+        let disk = Compressed::new(FILE)?;
+        assert_eq!(disk.read_page(0)?, text(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_crash() -> std::io::Result<()> {
+        const FILE: &str = "test_compressed_crash.db";
+        let _cleanup = (CleanUp::file(FILE), CleanUp::file("test_compressed_crash.db.map"));
+
+        {
+            let disk = Compressed::new(FILE)?;
+            for i in 0..4 {
+                disk.write_page(i as PageId, &text(i))?;
+            }
+            disk.sync()?;
+
+            // Written but the map is never saved. Page 1 fits in its extent, page 2 grows.
+            let mut random = [0; PAGE_SIZE];
+            rand::thread_rng().fill(&mut random[..]);
+            disk.write_page(1, &text(100))?;
+            disk.write_page(1, &text(101))?;
+            disk.write_page(2, &random)?;
+            disk.write_page(4, &text(4))?;
+            assert_eq!(disk.read_page(1)?, text(101));
+        }
+
+        let disk = Compressed::new(FILE)?;
+        for i in 0..4 {
+            assert_eq!(disk.read_page(i as PageId)?, text(i));
+        }
+        assert_eq!(disk.read_page(4)?, [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_page_cache() -> crate::Result<()> {
+        const FILE: &str = "test_compressed_page_cache.db";
+        let _cleanup = (CleanUp::file(FILE), CleanUp::file("test_compressed_page_cache.db.map"));
+        const K: usize = 2;
+
+        let pc = PageCache::with_capacity(Compressed::new(FILE)?, LRU::new(K), 0, 4);
+        for i in 0..16 {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            writep!(w, 100..108, &(i as u64).to_be_bytes());
+        }
+        for i in 0..16 {
+            assert_eq!(pc.fetch_page(i)?.read().data[100..108], (i as u64).to_be_bytes());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    sync::Mutex,
+};
+
+use chacha20poly1305::{AeadInOut, ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use rand::Rng;
+
+use futures::future::{self, BoxFuture};
+
+use crate::{
+    disk::Disk,
+    page::{page_relation, relation_page, PageBuf, PageId, RelationId, PAGE_SIZE},
+};
+
+/*
+    Pages are stored in groups, each led by a meta page holding the nonce and tag of every page in
+    the group. The first page of the inner disk holds a known value encrypted with the key, so a
+    wrong key is caught when the disk is opened. Each relation is laid out the same way in the same
+    relation of the inner disk, its first page is left unused.
+
+    Header page:
+    Magic (8) | Seal | Check (16)
+
+    Group:
+    Meta | Page * PAGES_PER_GROUP
+
+    Meta:
+    Seal * PAGES_PER_GROUP
+
+    Seal:
+    Nonce (12) | Tag (16)
+*/
+
+const MAGIC: &[u8; 8] = b"BASEENC\0";
+const CHECK: &[u8; 16] = b"base key check\0\0";
+
+const NONCE_SIZE: usize = 12;
+const SEAL_SIZE: usize = NONCE_SIZE + 16;
+const PAGES_PER_GROUP: usize = PAGE_SIZE / SEAL_SIZE;
+
+type Seal = [u8; SEAL_SIZE];
+
+/// Where a page and its seal are stored on the inner disk
+struct Location {
+    meta_page_id: PageId,
+    data_page_id: PageId,
+    /// Of the seal in the meta page
+    offset: usize,
+}
+
+fn locate(page_id: PageId) -> io::Result<Location> {
+    if page_id < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "negative page id"));
+    }
+
+    let relation = page_relation(page_id);
+    let page = page_id as u32 as usize;
+    let meta = 1 + page / PAGES_PER_GROUP * (PAGES_PER_GROUP + 1);
+    let data = u32::try_from(meta + 1 + page % PAGES_PER_GROUP)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "page id out of range"))?;
+
+    Ok(Location {
+        meta_page_id: relation_page(relation, meta as u32),
+        data_page_id: relation_page(relation, data),
+        offset: page % PAGES_PER_GROUP * SEAL_SIZE,
+    })
+}
+
+fn tampered(page_id: PageId) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("page {page_id} failed to decrypt, it was modified or the key is wrong"),
+    )
+}
+
+/// Encrypt `buf` in place with a random nonce
+fn seal(cipher: &ChaCha20Poly1305, aad: &[u8], buf: &mut [u8]) -> io::Result<Seal> {
+    let mut nonce = [0; NONCE_SIZE];
+    rand::thread_rng().fill(&mut nonce);
+
+    let tag = cipher
+        .encrypt_inout_detached(&Nonce::from(nonce), aad, buf.into())
+        .map_err(|_| io::Error::other("page could not be encrypted"))?;
+
+    let mut ret = [0; SEAL_SIZE];
+    ret[..NONCE_SIZE].copy_from_slice(&nonce);
+    ret[NONCE_SIZE..].copy_from_slice(&tag);
+
+    Ok(ret)
+}
+
+/// Decrypt `buf` in place, `false` if it doesn't match the seal
+fn open(cipher: &ChaCha20Poly1305, aad: &[u8], buf: &mut [u8], seal: &[u8]) -> bool {
+    let nonce = Nonce::try_from(&seal[..NONCE_SIZE]).unwrap();
+    let tag = Tag::try_from(&seal[NONCE_SIZE..]).unwrap();
+
+    cipher.decrypt_inout_detached(&nonce, aad, buf.into(), &tag).is_ok()
+}
+
+/// Decrypt the page read from the inner disk
+fn decrypt(
+    cipher: &ChaCha20Poly1305,
+    page_id: PageId,
+    mut data: PageBuf,
+    seal: &Seal,
+) -> io::Result<PageBuf> {
+    if *seal == [0; SEAL_SIZE] {
+        // Never written
+        return match data.iter().all(|b| *b == 0) {
+            true => Ok(data),
+            false => Err(tampered(page_id)),
+        };
+    }
+
+    match open(cipher, &page_id.to_be_bytes(), &mut data, seal) {
+        true => Ok(data),
+        false => Err(tampered(page_id)),
+    }
+}
+
+/// Encrypts pages with ChaCha20-Poly1305 before they reach `inner`. The page id is authenticated
+/// along with each page, so a page copied to another id fails to decrypt.
+///
+/// A page and its seal are written separately, a crash between the two leaves a page that fails
+/// to decrypt rather than one that silently holds the wrong data. Recovery rebuilds it from the
+/// log.
+pub struct Encrypted<D: Disk> {
+    inner: D,
+    cipher: ChaCha20Poly1305,
+    /// Meta pages read so far
+    meta: Mutex<HashMap<PageId, Box<PageBuf>>>,
+}
+
+impl<D: Disk> Encrypted<D> {
+    /// Fails with `PermissionDenied` if `inner` was encrypted with a different key
+    pub fn new(inner: D, key: &[u8; 32]) -> io::Result<Self> {
+        let cipher = ChaCha20Poly1305::new(&Key::from(*key));
+
+        let mut header = inner.read_page(0)?;
+        if header.iter().all(|b| *b == 0) {
+            let mut check = *CHECK;
+            header[0..8].copy_from_slice(MAGIC);
+            header[8..8 + SEAL_SIZE].copy_from_slice(&seal(&cipher, MAGIC, &mut check)?);
+            header[8 + SEAL_SIZE..][..16].copy_from_slice(&check);
+            inner.write_page(0, &header)?;
+        } else if &header[0..8] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an encrypted disk"));
+        }
+
+        let mut check = [0; 16];
+        check.copy_from_slice(&header[8 + SEAL_SIZE..][..16]);
+        if !open(&cipher, MAGIC, &mut check, &header[8..8 + SEAL_SIZE]) || &check != CHECK {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong encryption key"));
+        }
+
+        Ok(Self { inner, cipher, meta: Mutex::new(HashMap::new()) })
+    }
+
+    fn meta<'a>(
+        &self,
+        meta: &'a mut HashMap<PageId, Box<PageBuf>>,
+        meta_page_id: PageId,
+    ) -> io::Result<&'a mut Box<PageBuf>> {
+        match meta.entry(meta_page_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(Box::new(self.inner.read_page(meta_page_id)?))),
+        }
+    }
+}
+
+impl<D: Disk> Encrypted<D> {
+    fn seal(&self, location: &Location) -> io::Result<Seal> {
+        let mut meta = self.meta.lock().expect("todo");
+        let meta_page = self.meta(&mut meta, location.meta_page_id)?;
+
+        Ok(meta_page[location.offset..][..SEAL_SIZE].try_into().unwrap())
+    }
+
+    /// Encrypt the page and write its seal, returns what to write to the data page. Holding the
+    /// lock until the meta page is written keeps concurrent writes to a group from losing seals.
+    fn write_seal(
+        &self,
+        page_id: PageId,
+        location: &Location,
+        data: &PageBuf,
+    ) -> io::Result<PageBuf> {
+        let mut buf = *data;
+        let seal = seal(&self.cipher, &page_id.to_be_bytes(), &mut buf)?;
+
+        // Only update the cached meta page once it has been written
+        let mut meta = self.meta.lock().expect("todo");
+        let meta_page = self.meta(&mut meta, location.meta_page_id)?;
+        let mut updated = **meta_page;
+        updated[location.offset..][..SEAL_SIZE].copy_from_slice(&seal);
+        self.inner.write_page(location.meta_page_id, &updated)?;
+        **meta_page = updated;
+
+        Ok(buf)
+    }
+}
+
+impl<D: Disk> Disk for Encrypted<D> {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        let location = locate(page_id)?;
+        let seal = self.seal(&location)?;
+        let data = self.inner.read_page(location.data_page_id)?;
+
+        decrypt(&self.cipher, page_id, data, &seal)
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        let location = locate(page_id)?;
+        let buf = self.write_seal(page_id, &location, data)?;
+
+        self.inner.write_page(location.data_page_id, &buf)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    /// Pages of other relations start at 1 and are grouped behind meta pages
+    fn relation_len(&self, relation: RelationId) -> io::Result<u32> {
+        let len = self.inner.relation_len(relation)?.saturating_sub(1);
+        let group = PAGES_PER_GROUP as u32 + 1;
+
+        Ok(len / group * PAGES_PER_GROUP as u32 + (len % group).saturating_sub(1))
+    }
+
+    fn drop_relation(&self, relation: RelationId) -> io::Result<()> {
+        let mut meta = self.meta.lock().expect("todo");
+        self.inner.drop_relation(relation)?;
+        meta.retain(|page_id, _| page_relation(*page_id) != relation);
+
+        Ok(())
+    }
+
+    fn read_page_async(&self, page_id: PageId) -> BoxFuture<'static, io::Result<PageBuf>> {
+        let seal = locate(page_id).and_then(|location| Ok((self.seal(&location)?, location)));
+        let (seal, location) = match seal {
+            Ok(seal) => seal,
+            Err(e) => return Box::pin(future::ready(Err(e))),
+        };
+
+        let read = self.inner.read_page_async(location.data_page_id);
+        let cipher = self.cipher.clone();
+        Box::pin(async move { decrypt(&cipher, page_id, read.await?, &seal) })
+    }
+
+    /// The seal is written before the write completes, if it fails the page fails to decrypt until
+    /// it is written again
+    fn write_page_async(
+        &self,
+        page_id: PageId,
+        data: &PageBuf,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        let buf = locate(page_id)
+            .and_then(|location| Ok((self.write_seal(page_id, &location, data)?, location)));
+        match buf {
+            Ok((buf, location)) => self.inner.write_page_async(location.data_page_id, &buf),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::ErrorKind, sync::Arc};
+
+    use crate::{
+        disk::encrypted::PAGES_PER_GROUP,
+        disk::{encrypted::locate, Disk, Encrypted, Faulty, Memory, Tablespace},
+        page::{relation_page, PageBuf, PAGE_HEADER_SIZE, PAGE_SIZE},
+        page_cache::{PageCache, PageCacheError},
+        recovery::recover,
+        replacer::LRU,
+        test::CleanUp,
+        transaction::TransactionManager,
+        wal::{LogMemory, Wal},
+        writep,
+    };
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn test_encrypted() -> std::io::Result<()> {
+        let memory = Arc::new(Memory::default());
+        let disk = Encrypted::new(memory.clone(), &KEY)?;
+        let data_page_id = |page_id| locate(page_id).unwrap().data_page_id;
+
+        // Enough pages for more than one group
+        for i in 0..300 {
+            disk.write_page(i, &[i as u8; PAGE_SIZE])?;
+        }
+        assert_eq!(disk.read_page(1000)?, [0; PAGE_SIZE]);
+
+        // Nothing is stored in the clear
+        let stored = memory.read_page(data_page_id(5))?;
+        assert!(stored.iter().filter(|b| **b == 5).count() < PAGE_SIZE / 16);
+
+        let disk = Encrypted::new(memory.clone(), &KEY)?;
+        for i in 0..300 {
+            assert_eq!(disk.read_page(i)?, [i as u8; PAGE_SIZE]);
+        }
+
+        assert_eq!(
+            Encrypted::new(memory.clone(), &[8; 32]).err().map(|e| e.kind()),
+            Some(ErrorKind::PermissionDenied)
+        );
+
+        // A modified page, or a page moved to another id, fails to decrypt
+        let mut data = memory.read_page(data_page_id(5))?;
+        data[100] ^= 1;
+        memory.write_page(data_page_id(5), &data)?;
+        assert_eq!(disk.read_page(5).err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+
+        let data = memory.read_page(data_page_id(7))?;
+        memory.write_page(data_page_id(6), &data)?;
+        assert_eq!(disk.read_page(6).err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+
+        memory.write_page(data_page_id(1000), &[1; PAGE_SIZE])?;
+        assert_eq!(disk.read_page(1000).err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_page_cache() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Encrypted::new(Memory::default(), &KEY)?;
+        let pc = PageCache::with_capacity(disk, LRU::new(K), 0, 4);
+
+        for i in 0..16 {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            writep!(w, PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 4, &(i as u32).to_be_bytes());
+        }
+        for i in 0..16 {
+            let page = pc.fetch_page(i)?;
+            assert_eq!(page.read().data[PAGE_HEADER_SIZE..][..4], (i as u32).to_be_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_crash() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let faulty = Arc::new(Faulty::new(Memory::default()));
+        let log = Arc::new(LogMemory::default());
+        let fill = |b: u8| -> PageBuf {
+            let mut ret = [0; PAGE_SIZE];
+            ret[PAGE_HEADER_SIZE..][..64].fill(b);
+            ret
+        };
+
+        {
+            let disk = Encrypted::new(faulty.clone(), &KEY)?;
+            let pc = PageCache::new_with_wal(disk, LRU::new(K), 0, Wal::new(log.clone())?);
+            let tm = TransactionManager::new(pc.clone())?;
+
+            let t1 = tm.begin();
+            let page = pc.new_page()?;
+            t1.write(&mut page.write(), &fill(1));
+            drop(page);
+            tm.commit(&t1)?;
+            tm.checkpoint()?;
+
+            let t2 = tm.begin();
+            let page = pc.fetch_page(0)?;
+            t2.write(&mut page.write(), &fill(2));
+            drop(page);
+            tm.commit(&t2)?;
+
+            // The seal reaches the disk but the page doesn't
+            faulty.fail_write(1);
+            assert!(pc.flush_page(0).is_err());
+        }
+
+        let disk = Encrypted::new(faulty, &KEY)?;
+        assert_eq!(disk.read_page(0).err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+
+        recover(&disk, &*Wal::new(log)?)?;
+        assert!(disk.read_page(0)?[PAGE_HEADER_SIZE..] == fill(2)[PAGE_HEADER_SIZE..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_relations() -> std::io::Result<()> {
+        const DIR: &str = "test_encrypted_relations";
+        let _cleanup = CleanUp::dir(DIR);
+
+        let disk = Encrypted::new(Tablespace::new(DIR)?, &KEY)?;
+        for len in [1, PAGES_PER_GROUP as u32, PAGES_PER_GROUP as u32 + 1] {
+            disk.write_page(relation_page(len, len - 1), &[1; PAGE_SIZE])?;
+            assert_eq!(disk.relation_len(len)?, len);
+        }
+
+        // Batches of pages spanning more than one group
+        let page_ids = (0..200).map(|i| relation_page(1, i)).collect::<Vec<_>>();
+        let pages = (0..200).map(|i| [i as u8; PAGE_SIZE]).collect::<Vec<_>>();
+        disk.write_pages(&page_ids.iter().copied().zip(&pages).collect::<Vec<_>>())?;
+        assert_eq!(disk.relation_len(1)?, 200);
+        assert!(disk.read_pages(&page_ids)? == pages);
+
+        let disk = Encrypted::new(Tablespace::new(DIR)?, &KEY)?;
+        assert!(disk.read_pages(&page_ids)? == pages);
+
+        disk.drop_relation(1)?;
+        assert_eq!(disk.relation_len(1)?, 0);
+        assert_eq!(disk.read_page(page_ids[0])?, [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::{collections::HashMap, io, sync::Mutex};
+
+use futures::future::{self, BoxFuture};
+
+use crate::{
+    disk::Disk,
+    page::{page_relation, PageBuf, PageId, RelationId, PAGE_SIZE},
+};
+
+#[derive(Default)]
+struct State {
+    /// Reads left before one fails
+    fail_read: Option<usize>,
+    fail_write: Option<usize>,
+    tear_write: Option<usize>,
+    /// Bytes of the torn write that reach the disk
+    tear_len: usize,
+    /// What each page written since the last sync held before, restored by a crash
+    unsynced: HashMap<PageId, PageBuf>,
+}
+
+/// Counts down to the operation that should fail, `true` once it is reached
+fn due(countdown: &mut Option<usize>) -> bool {
+    match countdown {
+        Some(0) => {
+            *countdown = None;
+            true
+        }
+        Some(n) => {
+            *n -= 1;
+            false
+        }
+        None => false,
+    }
+}
+
+/// Wraps a disk to inject the faults a real one can have, for testing error handling and
+/// recovery
+pub struct Faulty<D: Disk> {
+    inner: D,
+    state: Mutex<State>,
+}
+
+impl<D: Disk> Faulty<D> {
+    pub fn new(inner: D) -> Self {
+        Self { inner, state: Mutex::default() }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Fail the `n`th read from now, counting from 0
+    pub fn fail_read(&self, n: usize) {
+        self.state.lock().expect("todo").fail_read = Some(n);
+    }
+
+    /// Fail the `n`th write from now, counting from 0. The page is left unchanged.
+    pub fn fail_write(&self, n: usize) {
+        self.state.lock().expect("todo").fail_write = Some(n);
+    }
+
+    /// Only write the first `len` bytes of the `n`th write from now. The write still succeeds,
+    /// like one interrupted by a power failure.
+    pub fn tear_write(&self, n: usize, len: usize) {
+        let mut state = self.state.lock().expect("todo");
+        state.tear_write = Some(n);
+        state.tear_len = len.min(PAGE_SIZE);
+    }
+
+    /// Flip a bit of the page as it is stored
+    pub fn flip_bit(&self, page_id: PageId, bit: usize) -> io::Result<()> {
+        let mut data = self.inner.read_page(page_id)?;
+        data[bit / 8 % PAGE_SIZE] ^= 1 << (bit % 8);
+
+        self.inner.write_page(page_id, &data)
+    }
+
+    /// Lose every write since the last sync, as if the machine lost power
+    pub fn crash(&self) -> io::Result<()> {
+        let mut state = self.state.lock().expect("todo");
+        for (page_id, data) in state.unsynced.drain() {
+            self.inner.write_page(page_id, &data)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: Disk> Faulty<D> {
+    /// Applies the injected faults to a write, returns what should reach the inner disk
+    fn fault_write(
+        &self,
+        state: &mut State,
+        page_id: PageId,
+        data: &PageBuf,
+    ) -> io::Result<PageBuf> {
+        if due(&mut state.fail_write) {
+            return Err(io::Error::other("injected write failure"));
+        }
+
+        let old = self.inner.read_page(page_id)?;
+        state.unsynced.entry(page_id).or_insert(old);
+
+        if due(&mut state.tear_write) {
+            let mut torn = old;
+            torn[..state.tear_len].copy_from_slice(&data[..state.tear_len]);
+
+            return Ok(torn);
+        }
+
+        Ok(*data)
+    }
+}
+
+impl<D: Disk> Disk for Faulty<D> {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        let mut state = self.state.lock().expect("todo");
+        if due(&mut state.fail_read) {
+            return Err(io::Error::other("injected read failure"));
+        }
+
+        self.inner.read_page(page_id)
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        let mut state = self.state.lock().expect("todo");
+        let data = self.fault_write(&mut state, page_id, data)?;
+
+        self.inner.write_page(page_id, &data)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().expect("todo");
+        self.inner.sync()?;
+        state.unsynced.clear();
+
+        Ok(())
+    }
+
+    fn relation_len(&self, relation: RelationId) -> io::Result<u32> {
+        self.inner.relation_len(relation)
+    }
+
+    fn drop_relation(&self, relation: RelationId) -> io::Result<()> {
+        let mut state = self.state.lock().expect("todo");
+        self.inner.drop_relation(relation)?;
+
+        // A crash can't bring back pages of a relation that no longer exists
+        state.unsynced.retain(|page_id, _| page_relation(*page_id) != relation);
+
+        Ok(())
+    }
+
+    fn read_page_async(&self, page_id: PageId) -> BoxFuture<'static, io::Result<PageBuf>> {
+        let mut state = self.state.lock().expect("todo");
+        if due(&mut state.fail_read) {
+            return Box::pin(future::ready(Err(io::Error::other("injected read failure"))));
+        }
+
+        self.inner.read_page_async(page_id)
+    }
+
+    fn write_page_async(
+        &self,
+        page_id: PageId,
+        data: &PageBuf,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        let mut state = self.state.lock().expect("todo");
+        match self.fault_write(&mut state, page_id, data) {
+            Ok(data) => self.inner.write_page_async(page_id, &data),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{
+        disk::{Disk, Faulty, Memory, Tablespace},
+        page::{relation_page, PAGE_HEADER_SIZE, PAGE_SIZE},
+        page_cache::{PageCache, PageCacheError},
+        replacer::LRU,
+        test::CleanUp,
+        writep,
+    };
+
+    #[test]
+    fn test_faulty() -> std::io::Result<()> {
+        let disk = Faulty::new(Memory::default());
+
+        disk.fail_write(1);
+        disk.write_page(0, &[1; PAGE_SIZE])?;
+        assert!(disk.write_page(1, &[1; PAGE_SIZE]).is_err());
+        disk.write_page(1, &[1; PAGE_SIZE])?;
+
+        disk.fail_read(0);
+        assert!(disk.read_page(0).is_err());
+        assert_eq!(disk.read_page(0)?, [1; PAGE_SIZE]);
+
+        disk.tear_write(0, 100);
+        disk.write_page(0, &[2; PAGE_SIZE])?;
+        let have = disk.read_page(0)?;
+        assert!(have[..100].iter().all(|b| *b == 2) && have[100..].iter().all(|b| *b == 1));
+
+        disk.flip_bit(1, 9)?;
+        assert_eq!(disk.read_page(1)?[1], 3);
+
+        // Only writes since the last sync are lost
+        disk.sync()?;
+        disk.write_page(1, &[3; PAGE_SIZE])?;
+        disk.write_page(1, &[4; PAGE_SIZE])?;
+        disk.write_page(5, &[4; PAGE_SIZE])?;
+        disk.crash()?;
+        assert_eq!(disk.read_page(0)?, have);
+        assert_eq!(disk.read_page(1)?[..2], [1, 3]);
+        assert_eq!(disk.read_page(5)?, [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_faulty_page_cache() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Arc::new(Faulty::new(Memory::default()));
+        let pc = PageCache::new(disk.clone(), LRU::new(K), 0);
+
+        let id = pc.new_page()?.id;
+        let write = |b: u8| -> Result<(), PageCacheError> {
+            let page = pc.fetch_page(id)?;
+            let mut w = page.write();
+            writep!(w, PAGE_HEADER_SIZE.., &[b; PAGE_SIZE - PAGE_HEADER_SIZE]);
+            drop(w);
+            drop(page);
+
+            pc.flush_page(id)
+        };
+
+        // A failed write leaves the page dirty so it can be retried
+        write(1)?;
+        disk.fail_write(0);
+        assert_eq!(write(2), Err(PageCacheError::Disk(std::io::ErrorKind::Other)));
+        assert!(pc.fetch_page(id)?.read().dirty);
+        pc.flush_page(id)?;
+
+        disk.fail_read(0);
+        pc.remove_page(id);
+        assert_eq!(pc.fetch_page(id).err(), Some(PageCacheError::Disk(std::io::ErrorKind::Other)));
+        assert_eq!(pc.fetch_page(id)?.read().data[PAGE_HEADER_SIZE], 2);
+
+        // Torn pages and flipped bits are caught by the checksum
+        disk.tear_write(0, PAGE_SIZE / 2);
+        write(3)?;
+        pc.remove_page(id);
+        assert_eq!(pc.fetch_page(id).err(), Some(PageCacheError::Corrupt { page_id: id }));
+
+        disk.inner().write_page(id, &[0; PAGE_SIZE])?;
+        write(4)?;
+        disk.flip_bit(id, PAGE_SIZE * 4)?;
+        pc.remove_page(id);
+        assert_eq!(pc.fetch_page(id).err(), Some(PageCacheError::Corrupt { page_id: id }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_faulty_forwarding() -> std::io::Result<()> {
+        const DIR: &str = "test_faulty_forwarding";
+        let _cleanup = CleanUp::dir(DIR);
+
+        let disk = Faulty::new(Tablespace::new(DIR)?);
+        let page_id = relation_page(1, 2);
+
+        // Batched reads and writes go through the same faults
+        disk.fail_write(1);
+        assert!(disk.write_pages(&[(0, &[1; PAGE_SIZE]), (page_id, &[1; PAGE_SIZE])]).is_err());
+        disk.write_pages(&[(0, &[1; PAGE_SIZE]), (page_id, &[1; PAGE_SIZE])])?;
+        assert_eq!(disk.relation_len(1)?, 3);
+
+        disk.fail_read(0);
+        assert!(disk.read_pages(&[0, page_id]).is_err());
+        assert_eq!(disk.read_pages(&[0, page_id])?, [[1; PAGE_SIZE]; 2]);
+
+        // Dropped pages stay dropped after a crash
+        disk.drop_relation(1)?;
+        disk.crash()?;
+        assert_eq!(disk.relation_len(1)?, 0);
+        assert_eq!(disk.read_page(page_id)?, [0; PAGE_SIZE]);
+        assert_eq!(disk.read_page(0)?, [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+}
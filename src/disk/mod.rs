@@ -0,0 +1,389 @@
+pub mod compressed;
+pub mod encrypted;
+pub mod faulty;
+pub mod tablespace;
+#[cfg(target_os = "linux")]
+pub mod uring;
+
+use std::{
+    collections::HashMap,
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use futures::{
+    executor,
+    future::{self, BoxFuture},
+};
+use nix::{errno::Errno, sys::uio};
+use std::fs::{File, OpenOptions};
+
+use crate::page::{PageBuf, PageId, RelationId, PAGE_SIZE};
+
+pub use compressed::Compressed;
+pub use encrypted::Encrypted;
+pub use faulty::Faulty;
+pub use tablespace::Tablespace;
+#[cfg(target_os = "linux")]
+pub use uring::Uring;
+
+pub trait Disk {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf>;
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()>;
+
+    /// Make every page written so far durable
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The number of pages up to and including the last one written to `relation`, for disks that
+    /// store relations separately
+    fn relation_len(&self, _relation: RelationId) -> io::Result<u32> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Remove every page of `relation`, they read back as zeros afterwards
+    fn drop_relation(&self, _relation: RelationId) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Start reading the page, the future resolves once it has been read. Disks without
+    /// asynchronous I/O read it straight away.
+    fn read_page_async(&self, page_id: PageId) -> BoxFuture<'static, io::Result<PageBuf>> {
+        Box::pin(future::ready(self.read_page(page_id)))
+    }
+
+    /// `data` is copied, so it can be changed before the write completes
+    fn write_page_async(
+        &self,
+        page_id: PageId,
+        data: &PageBuf,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(future::ready(self.write_page(page_id, data)))
+    }
+
+    /// Every read is started before waiting on any of them
+    fn read_pages(&self, page_ids: &[PageId]) -> io::Result<Vec<PageBuf>> {
+        let reads = page_ids.iter().map(|page_id| self.read_page_async(*page_id));
+
+        executor::block_on(future::try_join_all(reads))
+    }
+
+    fn write_pages(&self, pages: &[(PageId, &PageBuf)]) -> io::Result<()> {
+        let writes = pages.iter().map(|(page_id, data)| self.write_page_async(*page_id, data));
+        executor::block_on(future::try_join_all(writes))?;
+
+        Ok(())
+    }
+}
+
+impl<D: Disk> Disk for Arc<D> {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        (**self).read_page(page_id)
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        (**self).write_page(page_id, data)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        (**self).sync()
+    }
+
+    fn relation_len(&self, relation: RelationId) -> io::Result<u32> {
+        (**self).relation_len(relation)
+    }
+
+    fn drop_relation(&self, relation: RelationId) -> io::Result<()> {
+        (**self).drop_relation(relation)
+    }
+
+    fn read_page_async(&self, page_id: PageId) -> BoxFuture<'static, io::Result<PageBuf>> {
+        (**self).read_page_async(page_id)
+    }
+
+    fn write_page_async(
+        &self,
+        page_id: PageId,
+        data: &PageBuf,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        (**self).write_page_async(page_id, data)
+    }
+}
+
+/// What `sync` does, for both the database file and the log
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Durability {
+    /// Leave writes to the OS, a crash can lose committed transactions
+    None,
+    /// fdatasync, file metadata that isn't needed to read the data back may be lost
+    #[default]
+    Data,
+    /// fsync
+    Full,
+}
+
+impl Durability {
+    pub fn sync(self, file: &File) -> io::Result<()> {
+        match self {
+            Durability::None => Ok(()),
+            Durability::Data => file.sync_data(),
+            Durability::Full => file.sync_all(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Options {
+    /// Open with `O_DIRECT`, bypassing the OS page cache. Only supported on Linux.
+    pub direct: bool,
+    pub durability: Durability,
+}
+
+/// `O_DIRECT` needs buffers aligned to the device's block size
+#[repr(C, align(4096))]
+struct Aligned(PageBuf);
+
+/// Where the page starts in a file holding every page
+fn offset(page_id: PageId) -> io::Result<i64> {
+    page_id
+        .checked_mul(PAGE_SIZE as i64)
+        .filter(|offset| *offset >= 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "page id out of range"))
+}
+
+pub struct FileSystem {
+    file: File,
+    options: Options,
+}
+
+impl Disk for FileSystem {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        let offset = offset(page_id)?;
+        let fd = self.file.as_raw_fd();
+        let mut buf = Aligned([0; PAGE_SIZE]);
+
+        let mut read = 0;
+        while read < PAGE_SIZE {
+            match uio::pread(fd, &mut buf.0[read..], offset + read as i64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Pages past the end of the file haven't been written yet, but a page can't end early
+        if read != 0 && read != PAGE_SIZE {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(buf.0)
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        let offset = offset(page_id)?;
+        let fd = self.file.as_raw_fd();
+
+        let aligned;
+        let data = match self.options.direct {
+            true => {
+                aligned = Aligned(*data);
+                &aligned.0
+            }
+            false => data,
+        };
+
+        let mut written = 0;
+        while written < PAGE_SIZE {
+            match uio::pwrite(fd, &data[written..], offset + written as i64) {
+                Ok(n) => written += n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.options.durability.sync(&self.file)
+    }
+}
+
+impl FileSystem {
+    pub fn new(file: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_options(file, Options::default())
+    }
+
+    pub fn with_options(file: impl AsRef<Path>, options: Options) -> io::Result<Self> {
+        let mut open = OpenOptions::new();
+        open.read(true).write(true).create(true);
+        if options.direct {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                open.custom_flags(nix::fcntl::OFlag::O_DIRECT.bits());
+            }
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        Ok(Self { file: open.open(file)?, options })
+    }
+}
+
+/// Pages held in memory. Only pages that have been written are kept, the rest read back as zeros
+/// like they do from a sparse file.
+///
+/// Ids aren't bounded by what has been allocated, the disk doesn't know. Every id that
+/// `relation_page` can produce is valid, up to `i64::MAX`, and negative ids are rejected with
+/// `InvalidInput`.
+#[derive(Default)]
+pub struct Memory {
+    pages: RwLock<HashMap<PageId, PageBuf>>,
+}
+
+fn check(page_id: PageId) -> io::Result<()> {
+    match page_id < 0 {
+        true => Err(io::Error::new(io::ErrorKind::InvalidInput, "negative page id")),
+        false => Ok(()),
+    }
+}
+
+impl Disk for Memory {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        check(page_id)?;
+        let pages = self.pages.read().expect("todo");
+
+        Ok(pages.get(&page_id).copied().unwrap_or([0; PAGE_SIZE]))
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        check(page_id)?;
+        self.pages.write().expect("todo").insert(page_id, *data);
+
+        Ok(())
+    }
+}
+
+impl Memory {
+    /// The number of pages up to and including the last one written
+    pub fn len(&self) -> usize {
+        let pages = self.pages.read().expect("todo");
+
+        pages.keys().max().map_or(0, |page_id| *page_id as usize + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A copy of the disk as it is now, see `restore`
+    pub fn snapshot(&self) -> Self {
+        Self { pages: RwLock::new(self.pages.read().expect("todo").clone()) }
+    }
+
+    /// Go back to the contents of `snapshot`, for example to simulate a crash
+    pub fn restore(&self, snapshot: &Self) {
+        let pages = snapshot.pages.read().expect("todo").clone();
+        *self.pages.write().expect("todo") = pages;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::OpenOptions, io::Write};
+
+    use crate::{
+        disk::{Disk, Durability, FileSystem, Memory, Options},
+        page::{relation_page, PageId, RelationId, PAGE_SIZE},
+        test::CleanUp,
+    };
+
+    #[test]
+    fn test_short_read() -> std::io::Result<()> {
+        const FILE: &str = "test_short_read.db";
+        let _cleanup = CleanUp::file(FILE);
+
+        let disk = FileSystem::new(FILE)?;
+        disk.write_page(0, &[1; PAGE_SIZE])?;
+        OpenOptions::new().append(true).open(FILE)?.write_all(&[2; PAGE_SIZE / 2])?;
+
+        assert_eq!(disk.read_page(0)?, [1; PAGE_SIZE]);
+        assert_eq!(disk.read_page(1).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(disk.read_page(2)?, [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_direct() -> std::io::Result<()> {
+        const FILE: &str = "test_direct.db";
+        let _cleanup = CleanUp::file(FILE);
+
+        let options = Options { direct: true, durability: Durability::Full };
+        let disk = FileSystem::with_options(FILE, options)?;
+        for i in 0..4 {
+            disk.write_page(i, &[i as u8 + 1; PAGE_SIZE])?;
+        }
+        disk.sync()?;
+
+        // Unaligned buffers are copied before they are handed to the kernel
+        let buf = vec![9; PAGE_SIZE + 1];
+        disk.write_page(4, buf[1..].try_into().unwrap())?;
+
+        let plain = FileSystem::new(FILE)?;
+        for i in 0..4 {
+            assert_eq!(disk.read_page(i)?, [i as u8 + 1; PAGE_SIZE]);
+            assert_eq!(plain.read_page(i)?, [i as u8 + 1; PAGE_SIZE]);
+        }
+        assert_eq!(disk.read_page(4)?, [9; PAGE_SIZE]);
+        assert_eq!(disk.read_page(5)?, [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory() -> std::io::Result<()> {
+        let disk = Memory::default();
+        assert_eq!(disk.read_page(3)?, [0; PAGE_SIZE]);
+        assert!(disk.is_empty());
+
+        disk.write_page(3, &[1; PAGE_SIZE])?;
+        assert_eq!(disk.len(), 4);
+        assert_eq!(disk.read_page(2)?, [0; PAGE_SIZE]);
+        assert_eq!(disk.read_page(3)?, [1; PAGE_SIZE]);
+
+        let invalid = Some(std::io::ErrorKind::InvalidInput);
+        assert_eq!(disk.read_page(-1).err().map(|e| e.kind()), invalid);
+        assert_eq!(disk.read_page(PageId::MIN).err().map(|e| e.kind()), invalid);
+        assert_eq!(disk.write_page(-1, &[1; PAGE_SIZE]).err().map(|e| e.kind()), invalid);
+
+        // Restoring drops everything written since the snapshot
+        let snapshot = disk.snapshot();
+        disk.write_page(3, &[2; PAGE_SIZE])?;
+        disk.write_page(100, &[2; PAGE_SIZE])?;
+        disk.restore(&snapshot);
+        assert_eq!(disk.len(), 4);
+        assert_eq!(disk.read_page(3)?, [1; PAGE_SIZE]);
+        assert_eq!(disk.read_page(100)?, [0; PAGE_SIZE]);
+
+        // Pages of other relations are far apart, nothing is stored for the pages in between
+        let page_id = relation_page(7, 2);
+        disk.write_page(page_id, &[3; PAGE_SIZE])?;
+        assert_eq!(disk.read_page(page_id)?, [3; PAGE_SIZE]);
+        assert_eq!(disk.read_page(page_id - 1)?, [0; PAGE_SIZE]);
+        assert_eq!(disk.pages.read().unwrap().len(), 2);
+
+        // The last page of the last relation is the largest id
+        let last = relation_page(i32::MAX as RelationId, u32::MAX);
+        assert_eq!(last, PageId::MAX);
+        disk.write_page(last, &[4; PAGE_SIZE])?;
+        assert_eq!(disk.read_page(last)?, [4; PAGE_SIZE]);
+
+        Ok(())
+    }
+}
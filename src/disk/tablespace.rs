@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    disk::Disk,
+    page::{page_relation, PageBuf, PageId, RelationId, PAGE_SIZE},
+};
+
+/*
+    Each relation is kept in the tablespace directory, split into segment files of SEGMENT_PAGES
+    pages:
+    {relation}.{segment}
+
+    Page n of a relation is page n % SEGMENT_PAGES of segment n / SEGMENT_PAGES.
+*/
+
+/// 1 GiB segments
+pub const SEGMENT_PAGES: u32 = (1 << 30) / PAGE_SIZE as u32;
+
+type Segment = (RelationId, u32);
+
+/// The segment holding the page and the offset of the page in it
+fn locate(page_id: PageId) -> io::Result<(Segment, u64)> {
+    if page_id < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "negative page id"));
+    }
+
+    let page = page_id as u32;
+    let segment = (page_relation(page_id), page / SEGMENT_PAGES);
+
+    Ok((segment, (page % SEGMENT_PAGES) as u64 * PAGE_SIZE as u64))
+}
+
+/// Stores every relation in its own files, the relation a page belongs to is part of its id. See
+/// `relation_page`. Dropping a relation unlinks its files, returning the space to the OS.
+pub struct Tablespace {
+    dir: PathBuf,
+    /// Segments opened so far
+    files: Mutex<HashMap<Segment, Arc<File>>>,
+}
+
+impl Tablespace {
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self { dir: dir.as_ref().into(), files: Mutex::default() })
+    }
+
+    fn path(&self, (relation, segment): Segment) -> PathBuf {
+        self.dir.join(format!("{relation}.{segment}"))
+    }
+
+    /// `None` if the segment hasn't been created
+    fn file(&self, segment: Segment, create: bool) -> io::Result<Option<Arc<File>>> {
+        let mut files = self.files.lock().expect("todo");
+        if let Some(file) = files.get(&segment) {
+            return Ok(Some(file.clone()));
+        }
+
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(self.path(segment))
+        {
+            Ok(file) => Arc::new(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !create => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        files.insert(segment, file.clone());
+
+        Ok(Some(file))
+    }
+
+    /// The segments of `relation` that have been created
+    fn segments(&self, relation: RelationId) -> io::Result<Vec<u32>> {
+        let mut ret = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let Some((r, segment)) = name.to_str().and_then(|name| name.split_once('.')) else {
+                continue;
+            };
+
+            if let (Ok(r), Ok(segment)) = (r.parse::<RelationId>(), segment.parse()) {
+                if r == relation {
+                    ret.push(segment);
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Make created and removed segments durable
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+impl Disk for Tablespace {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        let (segment, offset) = locate(page_id)?;
+        let mut buf = [0; PAGE_SIZE];
+        let Some(file) = self.file(segment, false)? else {
+            return Ok(buf);
+        };
+
+        let mut read = 0;
+        while read < PAGE_SIZE {
+            match file.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Pages past the end of a segment haven't been written yet, but a page can't end early
+        if read != 0 && read != PAGE_SIZE {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(buf)
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        let (segment, offset) = locate(page_id)?;
+        let file = self.file(segment, true)?.unwrap();
+
+        file.write_all_at(data, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let files: Vec<_> = self.files.lock().expect("todo").values().cloned().collect();
+        for file in files {
+            file.sync_data()?;
+        }
+
+        self.sync_dir()
+    }
+
+    fn relation_len(&self, relation: RelationId) -> io::Result<u32> {
+        let Some(last) = self.segments(relation)?.into_iter().max() else {
+            return Ok(0);
+        };
+
+        let len = fs::metadata(self.path((relation, last)))?.len();
+
+        Ok(last * SEGMENT_PAGES + len.div_ceil(PAGE_SIZE as u64) as u32)
+    }
+
+    fn drop_relation(&self, relation: RelationId) -> io::Result<()> {
+        let mut files = self.files.lock().expect("todo");
+        files.retain(|(r, _), _| *r != relation);
+
+        for segment in self.segments(relation)? {
+            match fs::remove_file(self.path((relation, segment))) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        self.sync_dir()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc};
+
+    use crate::{
+        disk::{tablespace::SEGMENT_PAGES, Disk, Tablespace},
+        page::{relation_page, PAGE_HEADER_SIZE, PAGE_SIZE},
+        page_cache::PageCache,
+        replacer::LRU,
+        test::CleanUp,
+        wal::{LogMemory, LogRecord, Wal},
+        writep,
+    };
+
+    #[test]
+    fn test_tablespace() -> std::io::Result<()> {
+        const DIR: &str = "test_tablespace";
+        let _cleanup = CleanUp::dir(DIR);
+
+        {
+            let disk = Tablespace::new(DIR)?;
+            disk.write_page(relation_page(0, 3), &[1; PAGE_SIZE])?;
+            disk.write_page(relation_page(1, 0), &[2; PAGE_SIZE])?;
+            disk.write_page(relation_page(1, SEGMENT_PAGES), &[3; PAGE_SIZE])?;
+            disk.sync()?;
+
+            assert!(disk.read_page(-1).is_err());
+        }
+
+        let disk = Tablespace::new(DIR)?;
+        assert_eq!(disk.read_page(relation_page(0, 3))?, [1; PAGE_SIZE]);
+        assert_eq!(disk.read_page(relation_page(1, 0))?, [2; PAGE_SIZE]);
+        assert_eq!(disk.read_page(relation_page(1, SEGMENT_PAGES))?, [3; PAGE_SIZE]);
+        assert_eq!(disk.read_page(relation_page(2, 0))?, [0; PAGE_SIZE]);
+        assert_eq!(disk.relation_len(0)?, 4);
+        assert_eq!(disk.relation_len(1)?, SEGMENT_PAGES + 1);
+        assert_eq!(disk.relation_len(2)?, 0);
+
+        // Every segment of the relation is removed, other relations are untouched
+        assert!(Path::new(DIR).join("1.1").exists());
+        disk.drop_relation(1)?;
+        assert!(!Path::new(DIR).join("1.0").exists() && !Path::new(DIR).join("1.1").exists());
+        assert_eq!(disk.read_page(relation_page(1, 0))?, [0; PAGE_SIZE]);
+        assert_eq!(disk.relation_len(1)?, 0);
+        assert_eq!(disk.read_page(relation_page(0, 3))?, [1; PAGE_SIZE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tablespace_page_cache() -> crate::Result<()> {
+        const DIR: &str = "test_tablespace_page_cache";
+        let _cleanup = CleanUp::dir(DIR);
+        const K: usize = 2;
+
+        let disk = Arc::new(Tablespace::new(DIR)?);
+        let wal = Wal::new(LogMemory::default())?;
+        let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), 0, wal.clone());
+
+        for relation in [1, 2] {
+            for i in 0..4 {
+                let page = pc.new_page_in(relation)?;
+                assert_eq!(page.id, relation_page(relation, i));
+
+                let mut w = page.write();
+                writep!(w, PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 4, &relation.to_be_bytes());
+            }
+        }
+        pc.flush_page(relation_page(1, 0))?;
+
+        // Cached pages are discarded rather than written
+        pc.drop_relation(1)?;
+        pc.flush_all_pages()?;
+        assert_eq!(disk.relation_len(1)?, 0);
+        assert_eq!(disk.relation_len(2)?, 4);
+        assert_eq!(pc.new_page_in(1)?.id, relation_page(1, 0));
+        assert_eq!(pc.new_page_in(2)?.id, relation_page(2, 4));
+        assert_eq!(pc.new_page()?.id, 0);
+
+        // Changes logged before the drop aren't replayed by recovery
+        let update = |page, after: &[u8]| LogRecord::Update {
+            txn_id: 1,
+            prev_lsn: 0,
+            page_id: relation_page(2, page),
+            offset: PAGE_HEADER_SIZE as u16,
+            before: vec![0; after.len()],
+            after: after.to_vec(),
+        };
+        wal.append(&update(0, b"old"));
+        wal.append(&LogRecord::Drop { relation: 2 });
+        wal.append(&update(1, b"new"));
+        wal.append(&LogRecord::Commit { txn_id: 1, prev_lsn: 0 });
+        drop(pc);
+
+        let pc = PageCache::open(disk.clone(), LRU::new(K), 0, wal)?;
+        assert_eq!(disk.relation_len(2)?, 2);
+        assert_eq!(pc.fetch_page(relation_page(2, 0))?.read().data, [0; PAGE_SIZE]);
+        assert_eq!(
+            pc.fetch_page(relation_page(2, 1))?.read().data[PAGE_HEADER_SIZE..][..3],
+            *b"new"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use futures::{
+    channel::oneshot::{self, Sender},
+    executor,
+    future::BoxFuture,
+    FutureExt,
+};
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::{
+    disk::Disk,
+    page::{PageBuf, PageId, PAGE_SIZE},
+};
+
+const ENTRIES: u32 = 256;
+
+/// Wakes the reaper so it can stop
+const STOP: u64 = u64::MAX;
+
+/// A request the kernel hasn't completed. The buffer is owned here so it stays put until then.
+struct Op {
+    buf: Box<PageBuf>,
+    write: bool,
+    done: Sender<io::Result<Box<PageBuf>>>,
+}
+
+struct Inner {
+    ring: IoUring,
+    /// Only one thread can push to the submission queue at a time
+    pending: Mutex<HashMap<u64, Op>>,
+}
+
+/// A file read and written through io_uring. Requests from every thread share one ring, so
+/// reads and writes started together are submitted together. A background thread completes them.
+pub struct Uring {
+    file: File,
+    inner: Arc<Inner>,
+    next: AtomicU64,
+    reaper: Option<JoinHandle<()>>,
+}
+
+impl Uring {
+    pub fn new(file: impl AsRef<Path>) -> io::Result<Self> {
+        let file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(file)?;
+        let inner = Arc::new(Inner { ring: IoUring::new(ENTRIES)?, pending: Mutex::default() });
+
+        let reaper = {
+            let inner = inner.clone();
+            thread::spawn(move || inner.reap())
+        };
+
+        Ok(Self { file, inner, next: AtomicU64::new(0), reaper: Some(reaper) })
+    }
+
+    fn submit(
+        &self,
+        page_id: PageId,
+        buf: Box<PageBuf>,
+        write: bool,
+    ) -> BoxFuture<'static, io::Result<Box<PageBuf>>> {
+        let (done, rx) = oneshot::channel();
+        let offset = PAGE_SIZE as u64 * page_id as u64;
+        let fd = types::Fd(self.file.as_raw_fd());
+        let user_data = self.next.fetch_add(1, Relaxed);
+
+        let mut op = Op { buf, write, done };
+        let ptr = op.buf.as_mut_ptr();
+        let entry = match write {
+            true => opcode::Write::new(fd, ptr, PAGE_SIZE as u32).offset(offset).build(),
+            false => opcode::Read::new(fd, ptr, PAGE_SIZE as u32).offset(offset).build(),
+        };
+
+        let mut pending = self.inner.pending.lock().expect("todo");
+        pending.insert(user_data, op);
+        if let Err(e) = self.inner.push(&entry.user_data(user_data)) {
+            let op = pending.remove(&user_data).unwrap();
+            let _ = op.done.send(Err(e));
+        }
+        drop(pending);
+
+        rx.map(|result| result.unwrap_or_else(|_| Err(io::ErrorKind::Other.into()))).boxed()
+    }
+}
+
+impl Inner {
+    /// Has to be called with `pending` locked
+    fn push(&self, entry: &squeue::Entry) -> io::Result<()> {
+        loop {
+            // Safety: pushes are serialised by the `pending` lock
+            let pushed = unsafe { self.ring.submission_shared().push(entry).is_ok() };
+            if pushed {
+                break;
+            }
+
+            // The queue is full, hand what is there to the kernel
+            self.ring.submit()?;
+        }
+
+        self.ring.submit()?;
+
+        Ok(())
+    }
+
+    fn reap(&self) {
+        let mut stopping = false;
+        loop {
+            if stopping && self.pending.lock().expect("todo").is_empty() {
+                return;
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => panic!("io_uring wait failed: {e}"),
+            }
+
+            // Safety: the reaper is the only thread reading completions
+            let completed: Vec<_> = unsafe { self.ring.completion_shared() }.collect();
+            let mut pending = self.pending.lock().expect("todo");
+            for cqe in completed {
+                if cqe.user_data() == STOP {
+                    stopping = true;
+                    continue;
+                }
+
+                let Some(op) = pending.remove(&cqe.user_data()) else {
+                    continue;
+                };
+
+                let result = match cqe.result() {
+                    n if n < 0 => Err(io::Error::from_raw_os_error(-n)),
+                    n if n as usize == PAGE_SIZE => Ok(op.buf),
+                    // Pages past the end of the file haven't been written yet
+                    0 if !op.write => Ok(op.buf),
+                    _ if op.write => Err(io::ErrorKind::WriteZero.into()),
+                    _ => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+                let _ = op.done.send(result);
+            }
+        }
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // The reaper finishes the requests still in flight before stopping. If it can't be woken
+        // it is left running, it owns the buffers the kernel could be using.
+        let pending = self.inner.pending.lock().expect("todo");
+        let stopped = self.inner.push(&opcode::Nop::new().build().user_data(STOP));
+        drop(pending);
+
+        if let (Ok(()), Some(reaper)) = (stopped, self.reaper.take()) {
+            let _ = reaper.join();
+        }
+    }
+}
+
+impl Disk for Uring {
+    fn read_page(&self, page_id: PageId) -> io::Result<PageBuf> {
+        executor::block_on(self.read_page_async(page_id))
+    }
+
+    fn write_page(&self, page_id: PageId, data: &PageBuf) -> io::Result<()> {
+        executor::block_on(self.write_page_async(page_id, data))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn read_page_async(&self, page_id: PageId) -> BoxFuture<'static, io::Result<PageBuf>> {
+        self.submit(page_id, Box::new([0; PAGE_SIZE]), false).map(|r| r.map(|buf| *buf)).boxed()
+    }
+
+    fn write_page_async(
+        &self,
+        page_id: PageId,
+        data: &PageBuf,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        self.submit(page_id, Box::new(*data), true).map(|r| r.map(|_| ())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        disk::{Disk, FileSystem, Uring},
+        page::{PageBuf, PageId, PAGE_SIZE},
+        test::CleanUp,
+    };
+
+    #[test]
+    fn test_uring() -> std::io::Result<()> {
+        const FILE: &str = "test_uring.db";
+        let _cleanup = CleanUp::file(FILE);
+
+        let disk = Uring::new(FILE)?;
+        let pages: Vec<PageBuf> = (0..64).map(|i| [i as u8; PAGE_SIZE]).collect();
+
+        let writes: Vec<_> = pages.iter().enumerate().map(|(i, p)| (i as PageId, p)).collect();
+        disk.write_pages(&writes)?;
+        assert_eq!(
+            disk.read_pages(&(0..64).rev().collect::<Vec<_>>())?,
+            pages.iter().rev().cloned().collect::<Vec<_>>()
+        );
+
+        // Pages past the end of the file read back empty
+        assert_eq!(disk.read_page(100)?, [0; PAGE_SIZE]);
+
+        // Readable through the synchronous disk too
+        disk.write_page(3, &[7; PAGE_SIZE])?;
+        drop(disk);
+        assert_eq!(FileSystem::new(FILE)?.read_page(3)?, [7; PAGE_SIZE]);
+
+        Ok(())
+    }
+}
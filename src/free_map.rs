@@ -0,0 +1,84 @@
+use std::ops::Range;
+
+use crate::{
+    bitmap::BitMap,
+    page::{PageBuf, PageId, PAGE_HEADER_SIZE, PAGE_SIZE},
+};
+
+/*
+    FreeMapPage:
+    PageHeader | NextPageId | Bits
+
+    Each map page tracks the next PAGES_PER_MAP page ids, starting from 0 for the root. A set bit
+    means the page is free.
+*/
+
+pub const NEXT_PAGE_ID: Range<usize> = PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8;
+pub const BITS_START: usize = PAGE_HEADER_SIZE + 8;
+pub const BITS_SIZE: usize = PAGE_SIZE - BITS_START;
+pub const PAGES_PER_MAP: usize = BITS_SIZE * 8;
+
+pub struct MapPage {
+    /// 0 if this is the last map page
+    pub next_page_id: PageId,
+    pub free: BitMap<BITS_SIZE>,
+}
+
+impl From<&PageBuf> for MapPage {
+    fn from(buf: &PageBuf) -> Self {
+        let next_page_id = PageId::from_be_bytes(buf[NEXT_PAGE_ID].try_into().unwrap());
+
+        let mut free = BitMap::<BITS_SIZE>::new();
+        free.as_mut_slice().copy_from_slice(&buf[BITS_START..]);
+
+        Self { next_page_id, free }
+    }
+}
+
+impl From<&MapPage> for PageBuf {
+    fn from(page: &MapPage) -> Self {
+        let mut ret: PageBuf = [0; PAGE_SIZE];
+
+        ret[NEXT_PAGE_ID].copy_from_slice(&page.next_page_id.to_be_bytes());
+        ret[BITS_START..].copy_from_slice(page.free.as_slice());
+
+        ret
+    }
+}
+
+impl MapPage {
+    /// Finds the first free page and marks it as allocated
+    pub fn pop(&mut self) -> Option<usize> {
+        let (byte, b) = self.free.as_slice().iter().enumerate().find(|(_, b)| **b != 0)?;
+        let i = byte * 8 + b.trailing_zeros() as usize;
+        self.free.set(i, false);
+
+        Some(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        free_map::{MapPage, PAGES_PER_MAP},
+        page::{PageBuf, PAGE_SIZE},
+    };
+
+    #[test]
+    fn test_map_page() {
+        let mut page = MapPage::from(&[0; PAGE_SIZE]);
+        assert_eq!(page.pop(), None);
+
+        page.next_page_id = 7;
+        page.free.set(10, true);
+        page.free.set(3, true);
+        page.free.set(PAGES_PER_MAP - 1, true);
+
+        let mut page = MapPage::from(&PageBuf::from(&page));
+        assert_eq!(page.next_page_id, 7);
+        assert_eq!(page.pop(), Some(3));
+        assert_eq!(page.pop(), Some(10));
+        assert_eq!(page.pop(), Some(PAGES_PER_MAP - 1));
+        assert_eq!(page.pop(), None);
+    }
+}
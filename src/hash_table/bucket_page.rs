@@ -2,21 +2,25 @@ use std::{mem::size_of, ops::Range};
 
 use crate::{
     bitmap::BitMap,
-    page::{PageBuf, PAGE_SIZE},
+    page::{PageBuf, PAGE_HEADER_SIZE, PAGE_SIZE},
     pair::Pair,
     storable::Storable,
 };
 
+/// Most pairs a bucket can hold
+pub const BUCKET_SIZE: usize = PAGE_SIZE / 8;
+
 /// Number of bytes for the bitmaps
-pub const BIT_SIZE: usize = 512 / 8;
+pub const BIT_SIZE: usize = BUCKET_SIZE / 8;
 
-const OCCUPIED: Range<usize> = 0..BIT_SIZE;
-const READABLE: Range<usize> = BIT_SIZE..BIT_SIZE + BIT_SIZE;
+const OCCUPIED: Range<usize> = PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + BIT_SIZE;
+const READABLE: Range<usize> = PAGE_HEADER_SIZE + BIT_SIZE..PAGE_HEADER_SIZE + BIT_SIZE * 2;
+const PAIRS_START: usize = PAGE_HEADER_SIZE + BIT_SIZE * 2;
 
 pub struct Bucket<K, V> {
     pub occupied: BitMap<BIT_SIZE>,
     pub readable: BitMap<BIT_SIZE>,
-    pairs: [Option<Pair<K, V>>; 512],
+    pairs: [Option<Pair<K, V>>; BUCKET_SIZE],
 }
 
 impl<K, V> From<&PageBuf> for Bucket<K, V>
@@ -32,12 +36,12 @@ where
         readable.as_mut_slice().copy_from_slice(&buf[READABLE]);
 
         // Use the occupied map to find pairs to insert
-        let mut pairs: [Option<Pair<K, V>>; 512] = std::array::from_fn(|_| None);
+        let mut pairs: [Option<Pair<K, V>>; BUCKET_SIZE] = std::array::from_fn(|_| None);
 
         let k_size = size_of::<K>();
         let v_size = size_of::<V>();
 
-        let mut pos = BIT_SIZE * 2;
+        let mut pos = PAIRS_START;
         for (i, pair) in pairs.iter_mut().enumerate() {
             if !occupied.check(i) {
                 pos += k_size + v_size;
@@ -69,21 +73,22 @@ where
         let mut ret: PageBuf = [0; PAGE_SIZE];
 
         ret[OCCUPIED].copy_from_slice(bucket.occupied.as_slice());
-        ret[READABLE].copy_from_slice(bucket.occupied.as_slice());
+        ret[READABLE].copy_from_slice(bucket.readable.as_slice());
 
-        let mut pos = BIT_SIZE * 2;
+        // Pairs are stored at the position of their index so removed pairs leave a gap
+        let mut pos = PAIRS_START;
         let p_size = size_of::<K>() + size_of::<V>();
-        for pair in &bucket.pairs {
+        for (i, pair) in bucket.pairs.iter().enumerate() {
             if pos + p_size > PAGE_SIZE {
                 break;
             }
 
-            if let Some(pair) = pair {
+            if let Some(pair) = pair.as_ref().filter(|_| bucket.occupied.check(i)) {
                 pair.a.write_to(&mut ret, pos);
-                pos += pair.a.size();
-                pair.b.write_to(&mut ret, pos);
-                pos += pair.b.size();
+                pair.b.write_to(&mut ret, pos + pair.a.size());
             }
+
+            pos += p_size;
         }
 
         ret
@@ -173,10 +178,14 @@ where
 
     #[inline]
     pub fn is_full(&self) -> bool {
-        let len = self.occupied.len();
+        self.occupied.len() >= Self::capacity()
+    }
+
+    /// Limited by the space in the page or the size of the bitmaps
+    pub fn capacity() -> usize {
         let s = size_of::<K>() + size_of::<V>();
 
-        len >= (PAGE_SIZE - 128) / s
+        ((PAGE_SIZE - PAIRS_START) / s).min(BUCKET_SIZE)
     }
 }
 
@@ -1,31 +1,33 @@
 use std::ops::Range;
 
-use crate::page::{PageBuf, PageId, PAGE_SIZE};
+use crate::page::{PageBuf, PageId, PAGE_HEADER_SIZE, PAGE_SIZE};
 
-pub const PAGE_IDS_SIZE_U32: usize = 512;
-pub const PAGE_IDS_SIZE_U8: usize = 512 * 4;
+/// Number of buckets the directory can point to, a power of two that fits in a page
+pub const MAX_BUCKETS: usize = PAGE_SIZE / 16;
+/// Bytes taken by the bucket page ids
+pub const PAGE_IDS_SIZE: usize = MAX_BUCKETS * 8;
 
-const GLOBAL_DEPTH: Range<usize> = 0..4;
-const LOCAL_DEPTHS: Range<usize> = 4..4 + PAGE_IDS_SIZE_U32;
-const PAGE_IDS: Range<usize> = PAGE_IDS_SIZE_U32..PAGE_IDS_SIZE_U32 + PAGE_IDS_SIZE_U8;
+const GLOBAL_DEPTH: Range<usize> = PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 4;
+const LOCAL_DEPTHS: Range<usize> = PAGE_HEADER_SIZE + 4..PAGE_HEADER_SIZE + 4 + MAX_BUCKETS;
+const PAGE_IDS: Range<usize> = LOCAL_DEPTHS.end..LOCAL_DEPTHS.end + PAGE_IDS_SIZE;
 
 #[derive(Debug)]
 pub struct Directory {
     global_depth: u32,
     /// Local depth for each page
-    local_depths: [u8; PAGE_IDS_SIZE_U32],
+    local_depths: [u8; MAX_BUCKETS],
     /// Bucket page IDs
-    page_ids: [u8; PAGE_IDS_SIZE_U8],
+    page_ids: [u8; PAGE_IDS_SIZE],
 }
 
 impl From<&PageBuf> for Directory {
     fn from(buf: &PageBuf) -> Self {
         let global_depth = u32::from_be_bytes(buf[GLOBAL_DEPTH].try_into().unwrap());
 
-        let mut local_depths = [0; PAGE_IDS_SIZE_U32];
+        let mut local_depths = [0; MAX_BUCKETS];
         local_depths[..].copy_from_slice(&buf[LOCAL_DEPTHS]);
 
-        let mut bucket_page_ids = [0; PAGE_IDS_SIZE_U8];
+        let mut bucket_page_ids = [0; PAGE_IDS_SIZE];
         bucket_page_ids[..].copy_from_slice(&buf[PAGE_IDS]);
 
         Self { global_depth, local_depths, page_ids: bucket_page_ids }
@@ -52,11 +54,11 @@ impl From<Directory> for PageBuf {
 
 impl Directory {
     pub fn get(&self, i: usize) -> PageId {
-        i32::from_be_bytes(self.page_ids[i * 4..(i * 4) + 4].try_into().unwrap())
+        PageId::from_be_bytes(self.page_ids[i * 8..(i * 8) + 8].try_into().unwrap())
     }
 
     pub fn insert(&mut self, i: usize, id: PageId) {
-        self.page_ids[i * 4..(i * 4) + 4].copy_from_slice(&i32::to_be_bytes(id));
+        self.page_ids[i * 8..(i * 8) + 8].copy_from_slice(&PageId::to_be_bytes(id));
     }
 
     pub fn set_global_depth(&mut self, depth: u32) {
@@ -10,27 +10,49 @@ use crate::{
     hash_table::dir_page::{self, Directory},
     page::{PageBuf, PageId},
     page_cache::SharedPageCache,
+    replacer::{Replacer, LRU},
     storable::Storable,
-    writep,
+    transaction::Transaction,
 };
 
-pub struct ExtendibleHashTable<K, V, D: Disk = FileSystem> {
+/// Pages are logged redo only. If a transaction aborts its splits are kept and its pairs are put
+/// back one at a time, other transactions can have written to the same buckets since.
+pub struct ExtendibleHashTable<K, V, D: Disk = FileSystem, R: Replacer = LRU> {
     dir_page_id: PageId,
-    pc: SharedPageCache<D>,
+    pc: SharedPageCache<D, R>,
     _data: PhantomData<(K, V)>,
 }
 
-impl<K, V, D> ExtendibleHashTable<K, V, D>
+impl<K, V, D, R> ExtendibleHashTable<K, V, D, R>
 where
-    K: Storable + Copy + Eq + Hash,
-    V: Storable + Copy + Eq,
-    D: Disk,
+    K: Storable + Copy + Eq + Hash + Send + 'static,
+    V: Storable + Copy + Eq + Send + 'static,
+    D: Disk + Send + Sync + 'static,
+    R: Replacer + 'static,
 {
-    pub fn new(dir_page_id: PageId, pc: SharedPageCache<D>) -> Self {
+    pub fn new(dir_page_id: PageId, pc: SharedPageCache<D, R>) -> Self {
         Self { dir_page_id, pc, _data: PhantomData }
     }
 
-    pub fn insert(&self, k: &K, v: &V) -> crate::Result<bool> {
+    pub fn insert(&self, k: &K, v: &V, txn: &Transaction) -> crate::Result<bool> {
+        let ret = self._insert(k, v, txn)?;
+
+        let (ht, k, v) = (Self::new(self.dir_page_id, self.pc.clone()), *k, *v);
+        txn.on_abort(move |txn| {
+            // Removing takes out every copy of the pair, put back the ones that aren't this one
+            let copies = ht.copies(&k, &v)?;
+            ht._remove(&k, &v, txn)?;
+            for _ in 1..copies {
+                ht._insert(&k, &v, txn)?;
+            }
+
+            Ok(())
+        });
+
+        Ok(ret)
+    }
+
+    fn _insert(&self, k: &K, v: &V, txn: &Transaction) -> crate::Result<bool> {
         let dir_page = self.pc.fetch_page(self.dir_page_id)?;
         let mut dir_page_w = dir_page.page.write();
         let mut dir = Directory::from(&dir_page_w.data);
@@ -41,7 +63,7 @@ where
             0 => {
                 let p = self.pc.new_page()?;
                 dir.insert(bucket_index, p.page.read().id);
-                writep!(dir_page_w, &PageBuf::from(&dir));
+                txn.write_redo_only(&mut dir_page_w, &PageBuf::from(&dir));
                 p
             }
             _ => self.pc.fetch_page(bucket_page_id)?,
@@ -51,7 +73,7 @@ where
         let mut bucket = Bucket::from(&bucket_page_w.data);
 
         bucket.insert(k, v);
-        writep!(bucket_page_w, &PageBuf::from(&bucket));
+        txn.write_redo_only(&mut bucket_page_w, &PageBuf::from(&bucket));
 
         if bucket.is_full() {
             if dir.local_depth_mask(bucket_index) == dir.global_depth_mask() {
@@ -78,26 +100,41 @@ where
                 new_bucket.insert(&pair.a, &pair.b);
             }
 
-            for i in (Self::get_bucket_index(k, &dir) & (bit - 1)..dir_page::PAGE_IDS_SIZE_U32)
-                .step_by(bit)
+            for i in
+                (Self::get_bucket_index(k, &dir) & (bit - 1)..dir_page::MAX_BUCKETS).step_by(bit)
             {
                 let new_page_id = if i & bit > 0 { page0_w.id } else { page1_w.id };
 
                 dir.insert(i, new_page_id);
             }
 
-            writep!(dir_page_w, &PageBuf::from(dir));
-            writep!(page0_w, &PageBuf::from(&bucket0));
-            writep!(page1_w, &PageBuf::from(&bucket0));
+            txn.write_redo_only(&mut dir_page_w, &PageBuf::from(dir));
+            txn.write_redo_only(&mut page0_w, &PageBuf::from(&bucket0));
+            txn.write_redo_only(&mut page1_w, &PageBuf::from(&bucket0));
 
-            // TODO: mark original page on disk as ready to be allocated
-            self.pc.remove_page(bucket_page_w.id);
+            txn.deallocate(bucket_page_w.id);
         }
 
         Ok(true)
     }
 
-    pub fn remove(&self, k: &K, v: &V) -> crate::Result<bool> {
+    pub fn remove(&self, k: &K, v: &V, txn: &Transaction) -> crate::Result<bool> {
+        let removed = self.copies(k, v)?;
+        let ret = self._remove(k, v, txn)?;
+
+        let (ht, k, v) = (Self::new(self.dir_page_id, self.pc.clone()), *k, *v);
+        txn.on_abort(move |txn| {
+            for _ in 0..removed {
+                ht._insert(&k, &v, txn)?;
+            }
+
+            Ok(())
+        });
+
+        Ok(ret)
+    }
+
+    fn _remove(&self, k: &K, v: &V, txn: &Transaction) -> crate::Result<bool> {
         let dir_page = self.pc.fetch_page(self.dir_page_id)?;
         let dir_page_r = dir_page.page.read();
         let dir = Directory::from(&dir_page_r.data);
@@ -112,7 +149,7 @@ where
         let mut bucket = Bucket::from(&bucket_page_w.data);
 
         let ret = bucket.remove(k, v);
-        writep!(bucket_page_w, &PageBuf::from(bucket));
+        txn.write_redo_only(&mut bucket_page_w, &PageBuf::from(bucket));
 
         // TODO: attempt to merge if empty
 
@@ -137,6 +174,10 @@ where
         Ok(bucket.find(k))
     }
 
+    fn copies(&self, k: &K, v: &V) -> crate::Result<usize> {
+        Ok(self.get(k)?.into_iter().filter(|have| have == v).count())
+    }
+
     pub fn get_num_buckets(&self) -> crate::Result<u32> {
         let dir_page = self.pc.fetch_page(self.dir_page_id)?;
         let dir_page_r = dir_page.page.read();
@@ -155,7 +196,7 @@ where
         let hash = Self::hash(k);
         let i = hash & dir_page.global_depth_mask();
 
-        i % dir_page::PAGE_IDS_SIZE_U32
+        i % dir_page::MAX_BUCKETS
     }
 }
 
@@ -165,10 +206,13 @@ mod test {
 
     use crate::{
         disk::Memory,
-        hash_table::{bucket_page::BIT_SIZE, dir_page::Directory, extendible::ExtendibleHashTable},
-        page::PAGE_SIZE,
-        page_cache::PageCache,
+        hash_table::{
+            bucket_page::{Bucket, BIT_SIZE},
+            dir_page::Directory,
+            extendible::ExtendibleHashTable,
+        },
         replacer::LRU,
+        test::page_cache,
     };
 
     macro_rules! inserts {
@@ -189,12 +233,12 @@ mod test {
 
     #[test]
     fn test_extendible_hash_table() -> crate::Result<()> {
-        const MEMORY: usize = PAGE_SIZE * 4;
         const K: usize = 2;
 
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let replacer = LRU::new(K);
-        let pm = PageCache::new(disk, replacer, 0);
+        let (pm, tm) = page_cache(disk, replacer)?;
+        let txn = tm.begin();
         let _dir_page = pm.new_page();
 
         let ht = ExtendibleHashTable::new(0, pm.clone());
@@ -203,11 +247,11 @@ mod test {
         let inserts = inserts!(-pairs..pairs, i32);
 
         for (k, v) in &inserts {
-            ht.insert(k, v)?;
+            ht.insert(k, v, &txn)?;
         }
 
         let remove = rand::random::<usize>() % inserts.len();
-        assert!(ht.remove(&inserts[remove].0, &inserts[remove].1)?);
+        assert!(ht.remove(&inserts[remove].0, &inserts[remove].1, &txn)?);
 
         let rem = ht.get(&inserts[remove].0)?;
         assert!(rem.is_empty());
@@ -234,21 +278,21 @@ mod test {
 
     #[test]
     fn test_split() {
-        const MEMORY: usize = PAGE_SIZE * 4;
         const K: usize = 2;
 
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let replacer = LRU::new(K);
-        let pm = PageCache::new(disk, replacer, 0);
+        let (pm, tm) = page_cache(disk, replacer).unwrap();
+        let txn = tm.begin();
         let ht = ExtendibleHashTable::new(0, pm.clone());
 
         let _dir_page = pm.new_page();
         assert!(ht.get_num_buckets().unwrap() == 1);
 
-        // (i32, usize) = 12 bytes
-        // (4096 - 128) / 12 = 330
-        for (k, v) in (0..BIT_SIZE * 8).zip(0..BIT_SIZE * 8).take(330) {
-            ht.insert(&k, &v).unwrap();
+        // One more pair than fits in a bucket
+        let n = Bucket::<usize, usize>::capacity() + 1;
+        for (k, v) in (0..BIT_SIZE * 8).zip(0..BIT_SIZE * 8).take(n) {
+            ht.insert(&k, &v, &txn).unwrap();
         }
 
         assert!(ht.get_num_buckets().unwrap() == 2);
@@ -258,5 +302,11 @@ mod test {
         let dir = Directory::from(&dir_page_w.data);
 
         assert!(dir.global_depth() == 1);
+        drop(dir_page_w);
+
+        // The bucket that was split is freed once the transaction commits
+        let bucket_page_id = 1;
+        tm.commit(&txn).unwrap();
+        assert_eq!(pm.new_page().unwrap().id, bucket_page_id);
     }
 }
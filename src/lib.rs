@@ -2,20 +2,48 @@ pub mod binder;
 pub mod bitmap;
 pub mod btree;
 pub mod catalog;
+pub mod database;
 pub mod disk;
+pub mod free_map;
 pub mod hash_table;
+pub mod lock;
 pub mod page;
 pub mod page_cache;
 pub mod pair;
 pub mod parser;
+pub mod recovery;
 pub mod replacer;
 pub mod storable;
 pub mod table;
+pub mod transaction;
+pub mod wal;
 
 pub use page_cache::Result;
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
+    use crate::{
+        disk::Disk,
+        page_cache::{PageCache, SharedPageCache},
+        replacer::Replacer,
+        transaction::TransactionManager,
+        wal::{LogMemory, Wal},
+    };
+
+    /// A page cache logging to memory, with a transaction manager to write through it
+    pub fn page_cache<D: Disk, R: Replacer>(
+        disk: D,
+        replacer: Arc<R>,
+    ) -> crate::Result<(SharedPageCache<D, R>, TransactionManager<D, R>)> {
+        let wal = Wal::new(LogMemory::default())?;
+        let pc = PageCache::new_with_wal(disk, replacer, 0, wal);
+        let tm = TransactionManager::new(pc.clone())?;
+
+        Ok((pc, tm))
+    }
+
     pub enum Type {
         File,
         Dir,
@@ -0,0 +1,618 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    catalog::OId,
+    page::PageId,
+    table::tuple::RId,
+    transaction::{Transaction, TransactionState},
+    wal::TxnId,
+};
+
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+impl LockMode {
+    fn compatible(self, other: LockMode) -> bool {
+        use LockMode::*;
+
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive) | (Shared, Shared) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether holding `self` already gives everything `other` would
+    fn covers(self, other: LockMode) -> bool {
+        use LockMode::*;
+
+        match (self, other) {
+            (Exclusive, _) => true,
+            (SharedIntentionExclusive, Exclusive) => false,
+            (SharedIntentionExclusive, _) => true,
+            (Shared, Shared | IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive | IntentionShared) => true,
+            (IntentionShared, IntentionShared) => true,
+            _ => false,
+        }
+    }
+
+    /// The least a parent has to be locked in for a child to be locked in `self`
+    fn parent(self) -> LockMode {
+        match self {
+            LockMode::IntentionShared | LockMode::Shared => LockMode::IntentionShared,
+            _ => LockMode::IntentionExclusive,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Resource {
+    Table(OId),
+    Page(OId, PageId),
+    Row(OId, RId),
+}
+
+impl Resource {
+    fn parent(&self) -> Option<Resource> {
+        match self {
+            Resource::Table(_) => None,
+            Resource::Page(oid, _) | Resource::Row(oid, _) => Some(Resource::Table(*oid)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LockError {
+    /// The lock couldn't be granted before the timeout
+    Timeout,
+    /// Another transaction is already upgrading its lock on the resource
+    UpgradeConflict,
+    /// Locks can only be made stronger
+    InvalidUpgrade,
+    /// The table has to be locked in an intention mode first
+    ParentNotLocked,
+    NotRunning,
+    /// The transaction was picked to break a deadlock and has to be aborted
+    Deadlock,
+}
+pub type Result<T> = std::result::Result<T, LockError>;
+
+struct Request {
+    txn_id: TxnId,
+    mode: LockMode,
+    granted: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    requests: Vec<Request>,
+    upgrading: Option<TxnId>,
+}
+
+impl Queue {
+    /// Requests are granted in order, a request can't skip over an earlier one that is waiting
+    fn grant(&mut self) -> bool {
+        let mut granted = false;
+        for i in 0..self.requests.len() {
+            if self.requests[i].granted {
+                continue;
+            }
+
+            let mode = self.requests[i].mode;
+            if !self.requests[..i].iter().all(|r| r.mode.compatible(mode)) {
+                break;
+            }
+
+            self.requests[i].granted = true;
+            granted = true;
+        }
+
+        granted
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    queue: Mutex<Queue>,
+    cv: Condvar,
+}
+
+pub struct LockManager {
+    slots: Mutex<HashMap<Resource, Arc<Slot>>>,
+    held: Mutex<HashMap<TxnId, HashSet<Resource>>>,
+    /// Transactions chosen by the deadlock detector that haven't released their locks yet
+    victims: Mutex<HashSet<TxnId>>,
+    timeout: Duration,
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOCK_TIMEOUT)
+    }
+}
+
+impl LockManager {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            slots: Mutex::new(HashMap::new()),
+            held: Mutex::new(HashMap::new()),
+            victims: Mutex::new(HashSet::new()),
+            timeout,
+        }
+    }
+
+    fn slot(&self, resource: Resource) -> Arc<Slot> {
+        self.slots.lock().expect("todo").entry(resource).or_default().clone()
+    }
+
+    fn held(&self) -> MutexGuard<'_, HashMap<TxnId, HashSet<Resource>>> {
+        self.held.lock().expect("todo")
+    }
+
+    fn is_victim(&self, txn_id: TxnId) -> bool {
+        self.victims.lock().expect("todo").contains(&txn_id)
+    }
+
+    pub fn lock_table(&self, txn: &Transaction, oid: OId, mode: LockMode) -> Result<()> {
+        self.lock(txn, Resource::Table(oid), mode)
+    }
+
+    pub fn lock_page(&self, txn: &Transaction, oid: OId, id: PageId, mode: LockMode) -> Result<()> {
+        self.lock(txn, Resource::Page(oid, id), mode)
+    }
+
+    pub fn lock_row(&self, txn: &Transaction, oid: OId, rid: RId, mode: LockMode) -> Result<()> {
+        self.lock(txn, Resource::Row(oid, rid), mode)
+    }
+
+    /// Blocks until the lock is granted. Locks are held until the transaction commits or aborts.
+    pub fn lock(&self, txn: &Transaction, resource: Resource, mode: LockMode) -> Result<()> {
+        if txn.state() != TransactionState::Running {
+            return Err(LockError::NotRunning);
+        }
+        if self.is_victim(txn.id()) {
+            return Err(LockError::Deadlock);
+        }
+
+        if let Some(parent) = resource.parent() {
+            match self.mode(txn.id(), parent) {
+                Some(held) if held.covers(mode.parent()) => {}
+                _ => return Err(LockError::ParentNotLocked),
+            }
+        }
+
+        let slot = self.slot(resource);
+        let mut queue = slot.queue.lock().expect("todo");
+
+        let mut upgrade_from = None;
+        match queue.requests.iter().position(|r| r.txn_id == txn.id()) {
+            Some(i) if queue.requests[i].mode.covers(mode) => return Ok(()),
+            Some(i) => {
+                if !mode.covers(queue.requests[i].mode) {
+                    return Err(LockError::InvalidUpgrade);
+                }
+                if queue.upgrading.is_some() {
+                    return Err(LockError::UpgradeConflict);
+                }
+
+                // Upgrades wait ahead of every other waiting request
+                upgrade_from = Some(queue.requests.remove(i).mode);
+                let first_waiting =
+                    queue.requests.iter().position(|r| !r.granted).unwrap_or(queue.requests.len());
+                queue
+                    .requests
+                    .insert(first_waiting, Request { txn_id: txn.id(), mode, granted: false });
+                queue.upgrading = Some(txn.id());
+            }
+            None => queue.requests.push(Request { txn_id: txn.id(), mode, granted: false }),
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if queue.grant() {
+                slot.cv.notify_all();
+            }
+
+            let request = queue.requests.iter().find(|r| r.txn_id == txn.id()).unwrap();
+            if request.granted {
+                break;
+            }
+
+            let now = Instant::now();
+            let deadlocked = self.is_victim(txn.id());
+            if deadlocked || now >= deadline {
+                match upgrade_from {
+                    // Nothing behind the upgrade was granted while it waited, so the lock it
+                    // started from can be given back in its place
+                    Some(from) => {
+                        let request =
+                            queue.requests.iter_mut().find(|r| r.txn_id == txn.id()).unwrap();
+                        *request = Request { txn_id: txn.id(), mode: from, granted: true };
+                        queue.upgrading = None;
+                    }
+                    None => queue.requests.retain(|r| r.txn_id != txn.id()),
+                }
+
+                // Requests behind this one might be grantable now
+                if queue.grant() {
+                    slot.cv.notify_all();
+                }
+
+                return Err(if deadlocked { LockError::Deadlock } else { LockError::Timeout });
+            }
+
+            queue = slot.cv.wait_timeout(queue, deadline - now).expect("todo").0;
+        }
+
+        if queue.upgrading == Some(txn.id()) {
+            queue.upgrading = None;
+        }
+        drop(queue);
+
+        self.held().entry(txn.id()).or_default().insert(resource);
+
+        Ok(())
+    }
+
+    /// The mode `txn_id` holds `resource` in, if granted
+    pub fn mode(&self, txn_id: TxnId, resource: Resource) -> Option<LockMode> {
+        let slot = self.slots.lock().expect("todo").get(&resource)?.clone();
+        let queue = slot.queue.lock().expect("todo");
+
+        queue.requests.iter().find(|r| r.txn_id == txn_id && r.granted).map(|r| r.mode)
+    }
+
+    /// Release every lock held by the transaction, called once it has committed or aborted
+    pub fn release_all(&self, txn_id: TxnId) {
+        self.victims.lock().expect("todo").remove(&txn_id);
+        let Some(resources) = self.held().remove(&txn_id) else {
+            return;
+        };
+
+        for resource in resources {
+            let slot = self.slot(resource);
+            let mut queue = slot.queue.lock().expect("todo");
+            queue.requests.retain(|r| r.txn_id != txn_id);
+            queue.grant();
+            slot.cv.notify_all();
+        }
+    }
+
+    /// Build the waits-for graph from the lock queues, an edge goes from a waiting transaction to
+    /// every transaction ahead of it that it can't be granted with
+    fn waits_for(&self) -> (BTreeMap<TxnId, BTreeSet<TxnId>>, HashMap<TxnId, Arc<Slot>>) {
+        let slots: Vec<Arc<Slot>> = self.slots.lock().expect("todo").values().cloned().collect();
+
+        let mut graph: BTreeMap<TxnId, BTreeSet<TxnId>> = BTreeMap::new();
+        let mut waiting = HashMap::new();
+        for slot in slots {
+            let queue = slot.queue.lock().expect("todo");
+            for (i, request) in queue.requests.iter().enumerate().filter(|(_, r)| !r.granted) {
+                waiting.insert(request.txn_id, slot.clone());
+
+                let ahead = queue.requests[..i]
+                    .iter()
+                    .filter(|r| !r.granted || !r.mode.compatible(request.mode))
+                    .map(|r| r.txn_id);
+                graph.entry(request.txn_id).or_default().extend(ahead);
+            }
+        }
+
+        (graph, waiting)
+    }
+
+    /// Runs a single pass of deadlock detection. The youngest transaction in each cycle is made to
+    /// fail with `LockError::Deadlock`. Returns the chosen victims.
+    pub fn detect(&self) -> Vec<TxnId> {
+        let (mut graph, waiting) = self.waits_for();
+
+        let mut victims = Vec::new();
+        while let Some(cycle) = find_cycle(&graph) {
+            let victim = *cycle.iter().max().unwrap();
+            graph.remove(&victim);
+            graph.values_mut().for_each(|edges| {
+                edges.remove(&victim);
+            });
+
+            victims.push(victim);
+        }
+
+        self.victims.lock().expect("todo").extend(&victims);
+        for victim in &victims {
+            if let Some(slot) = waiting.get(victim) {
+                // Take the queue lock so the wake up can't land between the victim checking and
+                // going back to sleep
+                let _queue = slot.queue.lock().expect("todo");
+                slot.cv.notify_all();
+            }
+        }
+
+        victims
+    }
+
+    /// Run `detect` every `interval` on a background thread until the returned handle is dropped
+    pub fn start_deadlock_detector(self: &Arc<Self>, interval: Duration) -> DeadlockDetector {
+        let (stop, rx) = mpsc::channel::<()>();
+        let lm = self.clone();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                lm.detect();
+            }
+        });
+
+        DeadlockDetector { stop: Some(stop), handle: Some(handle) }
+    }
+}
+
+/// Returns the transactions in a cycle, searching from the oldest transaction first
+fn find_cycle(graph: &BTreeMap<TxnId, BTreeSet<TxnId>>) -> Option<Vec<TxnId>> {
+    fn visit(
+        graph: &BTreeMap<TxnId, BTreeSet<TxnId>>,
+        txn_id: TxnId,
+        path: &mut Vec<TxnId>,
+        done: &mut HashSet<TxnId>,
+    ) -> Option<Vec<TxnId>> {
+        if let Some(i) = path.iter().position(|t| *t == txn_id) {
+            return Some(path[i..].to_vec());
+        }
+        if !done.insert(txn_id) {
+            return None;
+        }
+
+        path.push(txn_id);
+        for next in graph.get(&txn_id).into_iter().flatten() {
+            if let Some(cycle) = visit(graph, *next, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+
+        None
+    }
+
+    let mut done = HashSet::new();
+    for txn_id in graph.keys() {
+        if let Some(cycle) = visit(graph, *txn_id, &mut Vec::new(), &mut done) {
+            return Some(cycle);
+        }
+    }
+
+    None
+}
+
+pub struct DeadlockDetector {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for DeadlockDetector {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the detector up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        disk::Memory,
+        lock::{LockError, LockManager, LockMode::*, Resource},
+        page_cache::PageCache,
+        replacer::LRU,
+        table::tuple::RId,
+        transaction::TransactionManager,
+        wal::{LogMemory, Wal},
+    };
+
+    fn setup(timeout: Duration) -> crate::Result<TransactionManager<Memory>> {
+        const K: usize = 2;
+
+        let disk = Memory::default();
+        let wal = Wal::new(LogMemory::default())?;
+        let pc = PageCache::new_with_wal(disk, LRU::new(K), 0, wal);
+
+        TransactionManager::new_with_lock_manager(pc, Arc::new(LockManager::new(timeout)))
+    }
+
+    #[test]
+    fn test_lock_shared() -> crate::Result<()> {
+        let tm = setup(Duration::from_millis(50))?;
+        let lm = tm.lock_manager();
+        let rid = RId { page_id: 0, slot_id: 0 };
+
+        let a = tm.begin();
+        let b = tm.begin();
+
+        assert_eq!(lm.lock_row(&a, 0, rid, Shared), Err(LockError::ParentNotLocked));
+
+        lm.lock_table(&a, 0, IntentionShared).unwrap();
+        lm.lock_table(&b, 0, IntentionExclusive).unwrap();
+        lm.lock_row(&a, 0, rid, Shared).unwrap();
+        assert_eq!(lm.lock_row(&b, 0, rid, Exclusive), Err(LockError::Timeout));
+
+        // Intention shared isn't enough for an exclusive row lock
+        assert_eq!(lm.lock_row(&a, 0, rid, Exclusive), Err(LockError::ParentNotLocked));
+        assert_eq!(lm.lock_table(&a, 0, IntentionShared), Ok(()));
+
+        tm.commit(&a)?;
+        assert_eq!(lm.mode(a.id(), Resource::Table(0)), None);
+        lm.lock_row(&b, 0, rid, Exclusive).unwrap();
+        assert_eq!(lm.mode(b.id(), Resource::Row(0, rid)), Some(Exclusive));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_wait() -> crate::Result<()> {
+        let tm = setup(Duration::from_secs(5))?;
+        let lm = tm.lock_manager();
+
+        let a = tm.begin();
+        lm.lock_table(&a, 0, Exclusive).unwrap();
+
+        let b = tm.begin();
+        thread::scope(|s| -> crate::Result<()> {
+            let (tx, rx) = mpsc::channel();
+            let handle = s.spawn(|| {
+                let tx = tx;
+                lm.lock_table(&b, 0, Shared).unwrap();
+                tx.send(()).unwrap();
+                tm.commit(&b)
+            });
+
+            // Blocked until `a` commits
+            assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+            tm.commit(&a)?;
+            rx.recv_timeout(Duration::from_secs(5)).expect("lock should be granted");
+
+            handle.join().unwrap()
+        })
+    }
+
+    #[test]
+    fn test_lock_upgrade() -> crate::Result<()> {
+        let tm = setup(Duration::from_millis(50))?;
+        let lm = tm.lock_manager();
+
+        let a = tm.begin();
+        let b = tm.begin();
+
+        lm.lock_table(&a, 0, Shared).unwrap();
+        lm.lock_table(&b, 0, Shared).unwrap();
+        assert_eq!(lm.lock_table(&a, 0, IntentionShared), Ok(()));
+        assert_eq!(lm.lock_table(&a, 0, IntentionExclusive), Err(LockError::InvalidUpgrade));
+
+        // `b` still holds a shared lock
+        assert_eq!(lm.lock_table(&a, 0, Exclusive), Err(LockError::Timeout));
+
+        tm.abort(&b)?;
+        lm.lock_table(&a, 0, Exclusive).unwrap();
+        assert_eq!(lm.mode(a.id(), Resource::Table(0)), Some(Exclusive));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_upgrade_timeout() -> crate::Result<()> {
+        let tm = setup(Duration::from_millis(50))?;
+        let lm = tm.lock_manager();
+
+        let a = tm.begin();
+        let b = tm.begin();
+
+        lm.lock_table(&a, 0, Shared).unwrap();
+        lm.lock_table(&b, 0, Shared).unwrap();
+        assert_eq!(lm.lock_table(&a, 0, Exclusive), Err(LockError::Timeout));
+
+        // `a` keeps the shared lock it had before the upgrade
+        assert_eq!(lm.mode(a.id(), Resource::Table(0)), Some(Shared));
+        tm.commit(&b)?;
+
+        let c = tm.begin();
+        assert_eq!(lm.lock_table(&c, 0, Exclusive), Err(LockError::Timeout));
+        assert_eq!(lm.lock_table(&c, 0, Shared), Ok(()));
+
+        tm.commit(&a)?;
+        assert_eq!(lm.mode(a.id(), Resource::Table(0)), None);
+
+        Ok(())
+    }
+
+    /// Each transaction locks its own row, then the row of the next, so every transaction waits on
+    /// another in a single cycle
+    fn cycle(n: usize) -> crate::Result<Vec<Result<(), LockError>>> {
+        let tm = setup(Duration::from_secs(10))?;
+        let lm = tm.lock_manager();
+        let _detector = lm.start_deadlock_detector(Duration::from_millis(10));
+
+        let rid = |i: usize| RId { page_id: 0, slot_id: (i % n) as u32 };
+        let barrier = Barrier::new(n);
+
+        thread::scope(|s| {
+            let handles = (0..n)
+                .map(|i| {
+                    let (tm, lm, barrier) = (&tm, &lm, &barrier);
+                    let txn = tm.begin();
+                    s.spawn(move || -> crate::Result<Result<(), LockError>> {
+                        lm.lock_table(&txn, 0, IntentionExclusive).unwrap();
+                        lm.lock_row(&txn, 0, rid(i), Exclusive).unwrap();
+                        barrier.wait();
+
+                        let result = lm.lock_row(&txn, 0, rid(i + 1), Exclusive);
+                        match result {
+                            Ok(_) => tm.commit(&txn)?,
+                            Err(_) => tm.abort(&txn)?,
+                        }
+
+                        Ok(result)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn test_deadlock_two() -> crate::Result<()> {
+        // The youngest transaction is aborted, letting the other finish
+        assert_eq!(cycle(2)?, vec![Ok(()), Err(LockError::Deadlock)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadlock_three() -> crate::Result<()> {
+        assert_eq!(cycle(3)?, vec![Ok(()), Ok(()), Err(LockError::Deadlock)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect() -> crate::Result<()> {
+        let tm = setup(Duration::from_millis(100))?;
+        let lm = tm.lock_manager();
+
+        let a = tm.begin();
+        let b = tm.begin();
+        lm.lock_table(&a, 0, Shared).unwrap();
+        lm.lock_table(&b, 0, Shared).unwrap();
+
+        // Waiting without a cycle isn't a deadlock
+        thread::scope(|s| {
+            let handle = s.spawn(|| lm.lock_table(&a, 0, Exclusive));
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(lm.detect(), vec![]);
+            assert_eq!(handle.join().unwrap(), Err(LockError::Timeout));
+        });
+
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
-use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{
+    ops::Range,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::wal::Lsn;
 
 #[macro_export]
 macro_rules! writep {
@@ -13,13 +18,77 @@ macro_rules! writep {
     };
 }
 
+/// Chosen with the `page-8k`, `page-16k` or `page-32k` feature. Pages can't be larger than 32 KiB,
+/// the log stores offsets into them in 16 bits.
+///
+/// The size is fixed per build, not read from each file. `PageBuf` and every page layout are sized
+/// at compile time, so a database created with another size is rejected when it's opened.
+#[cfg(not(any(feature = "page-8k", feature = "page-16k", feature = "page-32k")))]
 pub const PAGE_SIZE: usize = 4 * 1024;
+#[cfg(all(feature = "page-8k", not(any(feature = "page-16k", feature = "page-32k"))))]
+pub const PAGE_SIZE: usize = 8 * 1024;
+#[cfg(all(feature = "page-16k", not(feature = "page-32k")))]
+pub const PAGE_SIZE: usize = 16 * 1024;
+#[cfg(feature = "page-32k")]
+pub const PAGE_SIZE: usize = 32 * 1024;
+
+/*
+    Every page starts with a header owned by the page cache, page layouts begin after it.
+
+    PageHeader:
+    Lsn (8) | Checksum (4)
+
+    The checksum covers the whole page with the checksum field zeroed.
+*/
+pub const PAGE_LSN: Range<usize> = 0..8;
+pub const PAGE_CHECKSUM: Range<usize> = 8..12;
+pub const PAGE_HEADER_SIZE: usize = 12;
+
+pub type PageId = i64;
+/// A table or index whose pages can be stored and dropped on their own. Relation 0 holds the pages
+/// allocated with `PageCache::new_page`.
+pub type RelationId = u32;
+
+/*
+    PageId:
+    Relation (4) | Page (4)
 
-pub type PageId = i32;
+    Relations go up to `i32::MAX` so page ids are never negative.
+*/
+
+pub fn relation_page(relation: RelationId, page: u32) -> PageId {
+    assert!(relation <= i32::MAX as RelationId, "relation {relation} is out of range");
+
+    (PageId::from(relation) << 32) | PageId::from(page)
+}
+
+pub fn page_relation(page_id: PageId) -> RelationId {
+    (page_id >> 32) as RelationId
+}
 pub type PageBuf = [u8; PAGE_SIZE];
 pub type PageReadGuard<'a> = RwLockReadGuard<'a, PageInner>;
 pub type PageWriteGuard<'a> = RwLockWriteGuard<'a, PageInner>;
 
+fn checksum(data: &PageBuf) -> u32 {
+    let crc = crc32c::crc32c(&data[..PAGE_CHECKSUM.start]);
+    let crc = crc32c::crc32c_append(crc, &[0; 4]);
+
+    crc32c::crc32c_append(crc, &data[PAGE_CHECKSUM.end..])
+}
+
+/// Stamp the checksum into the header before the page is written
+pub fn set_checksum(data: &mut PageBuf) {
+    let crc = checksum(data);
+    data[PAGE_CHECKSUM].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// Pages that were never written read back as zeros and have no checksum
+pub fn verify_checksum(data: &PageBuf) -> bool {
+    let crc = u32::from_be_bytes(data[PAGE_CHECKSUM].try_into().unwrap());
+
+    crc == checksum(data) || data.iter().all(|b| *b == 0)
+}
+
 pub struct Page(RwLock<PageInner>);
 
 impl Default for Page {
@@ -38,17 +107,24 @@ impl Page {
     pub fn write(&self) -> PageWriteGuard {
         self.0.write().expect("todo")
     }
+
+    /// `None` if the page is locked
+    pub fn try_write(&self) -> Option<PageWriteGuard<'_>> {
+        self.0.try_write().ok()
+    }
 }
 
 pub struct PageInner {
     pub id: PageId,
     pub dirty: bool,
+    /// LSN of the last log record that modified this page
+    pub lsn: Lsn,
     pub data: PageBuf,
 }
 
 impl Default for PageInner {
     fn default() -> Self {
-        Self { id: -1, dirty: false, data: [0; PAGE_SIZE] }
+        Self { id: -1, dirty: false, lsn: 0, data: [0; PAGE_SIZE] }
     }
 }
 
@@ -56,6 +132,7 @@ impl PageInner {
     pub fn reset(&mut self) {
         self.id = 0;
         self.dirty = false;
+        self.lsn = 0;
         self.data.fill(0);
     }
 }
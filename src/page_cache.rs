@@ -1,38 +1,56 @@
 use std::{
     cell::UnsafeCell,
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
+    ops::{Deref, DerefMut},
     sync::{
-        atomic::{AtomicI32, AtomicUsize, Ordering::*},
-        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        atomic::{AtomicI64, AtomicUsize, Ordering::*},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
     },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
+use futures::{executor, future::BoxFuture};
+
 use crate::{
+    btree,
+    catalog::Schema,
     disk::{Disk, FileSystem},
-    page::{Page, PageId, PageInner},
-    replacer::{AccessType, LRU},
+    free_map::{MapPage, PAGES_PER_MAP},
+    page::{
+        page_relation, relation_page, set_checksum, verify_checksum, Page, PageBuf, PageId,
+        PageInner, RelationId, PAGE_HEADER_SIZE, PAGE_LSN, PAGE_SIZE,
+    },
+    recovery,
+    replacer::{AccessType, Replacer, LRU},
+    storable::Storable,
+    table,
+    transaction::Transaction,
+    wal::{LogRecord, Lsn, Wal},
 };
 
+/// Number of frames used when a capacity isn't given
 pub const CACHE_SIZE: usize = 64;
 
 pub type FrameId = usize;
 
-pub struct FreeList<const SIZE: usize> {
-    free: UnsafeCell<[FrameId; SIZE]>,
+pub struct FreeList {
+    free: UnsafeCell<Box<[FrameId]>>,
     tail: AtomicUsize,
+    size: usize,
 }
 
-unsafe impl<const SIZE: usize> Sync for FreeList<SIZE> {}
+unsafe impl Sync for FreeList {}
 
-impl<const SIZE: usize> Default for FreeList<SIZE> {
-    fn default() -> Self {
-        let free: UnsafeCell<[FrameId; SIZE]> = UnsafeCell::new(std::array::from_fn(|i| i));
+impl FreeList {
+    /// Every frame starts out free
+    pub fn new(size: usize) -> Self {
+        let free = UnsafeCell::new((0..size).collect());
 
-        Self { free, tail: AtomicUsize::new(SIZE) }
+        Self { free, tail: AtomicUsize::new(size), size }
     }
-}
 
-impl<const SIZE: usize> FreeList<SIZE> {
     pub fn pop(&self) -> Option<FrameId> {
         let mut tail = self.tail.load(Relaxed);
         let mut new_tail;
@@ -55,7 +73,7 @@ impl<const SIZE: usize> FreeList<SIZE> {
         let mut tail = self.tail.load(Relaxed);
         let mut new_tail;
         loop {
-            assert!(tail != SIZE);
+            assert!(tail != self.size);
 
             new_tail = tail + 1;
             match self.tail.compare_exchange(tail, new_tail, Relaxed, Relaxed) {
@@ -76,173 +94,841 @@ impl<const SIZE: usize> FreeList<SIZE> {
     }
 }
 
-pub struct Pin<'a> {
+pub struct Pin<'a, R: Replacer = LRU> {
     pub page: &'a Page,
     pub id: PageId,
     i: FrameId,
-    replacer: Arc<LRU>,
+    page_table: &'a PageTable,
+    replacer: &'a R,
 }
 
-impl Drop for Pin<'_> {
+impl<R: Replacer> Drop for Pin<'_, R> {
     fn drop(&mut self) {
+        let _shard = self.page_table.shard(self.id).read().expect("todo");
+        self.page_table.pins[self.i].fetch_sub(1, Relaxed);
         self.replacer.unpin(self.i);
     }
 }
 
-impl<'a> Pin<'a> {
-    pub fn new(page: &'a Page, i: FrameId, id: PageId, replacer: Arc<LRU>) -> Self {
-        Self { page, i, id, replacer }
+impl<'a, R: Replacer> Pin<'a, R> {
+    /// The shard holding `id` has to be locked
+    fn new(
+        page: &'a Page,
+        i: FrameId,
+        id: PageId,
+        page_table: &'a PageTable,
+        replacer: &'a R,
+    ) -> Self {
+        page_table.pins[i].fetch_add(1, Relaxed);
+        replacer.pin(i);
+
+        Self { page, i, id, page_table, replacer }
     }
 
-    pub fn write(&self) -> RwLockWriteGuard<'_, PageInner> {
+    /// The page is marked dirty once it is changed through the guard
+    pub fn write(&self) -> PinWriteGuard<'_> {
         let w = self.page.write();
 
         assert!(self.id == w.id, "page was swapped out whilst a pin was held");
 
-        w
+        PinWriteGuard(w)
     }
 
     pub fn read(&self) -> RwLockReadGuard<'_, PageInner> {
         self.page.read()
     }
+
+    /// Logged one slot at a time, see `Transaction::write_heap`
+    pub fn as_heap_page(&self) -> View<'_, table::node::Node> {
+        self.view(|buf| table::node::Node::from(buf), Transaction::write_heap)
+    }
+
+    /// The page has to hold a node
+    pub fn as_btree_node<'s, V: Storable>(
+        &self,
+        schema: &'s Schema,
+    ) -> View<'_, btree::node::Node<'s, V>> {
+        self.view(|buf| btree::node::Node::from(buf, schema), Transaction::write)
+    }
+
+    fn view<T>(&self, from: impl FnOnce(&PageBuf) -> T, write: LogWrite) -> View<'_, T>
+    where
+        for<'b> &'b T: Into<PageBuf>,
+    {
+        let PinWriteGuard(page) = self.write();
+        let buf = Box::new(page.data);
+        let value = from(&buf);
+
+        View { page, value, changed: false, write, txn: None, _buf: buf }
+    }
+}
+
+pub struct PinWriteGuard<'a>(RwLockWriteGuard<'a, PageInner>);
+
+impl Deref for PinWriteGuard<'_> {
+    type Target = PageInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PinWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.dirty = true;
+        &mut self.0
+    }
+}
+
+type LogWrite = fn(&Transaction, &mut PageInner, &PageBuf);
+
+/// A page read into `T`. If it was changed, `T` is written back to the page when the view is
+/// dropped, through the transaction given to `logged` if there is one.
+pub struct View<'a, T>
+where
+    for<'b> &'b T: Into<PageBuf>,
+{
+    page: RwLockWriteGuard<'a, PageInner>,
+    value: T,
+    changed: bool,
+    /// How the change is logged
+    write: LogWrite,
+    txn: Option<&'a Transaction>,
+    /// What `value` was read from, a heap page writes its tuples straight into it
+    _buf: Box<PageBuf>,
+}
+
+impl<'a, T> View<'a, T>
+where
+    for<'b> &'b T: Into<PageBuf>,
+{
+    pub fn logged(mut self, txn: &'a Transaction) -> Self {
+        self.txn = Some(txn);
+        self
+    }
+
+    /// The change is kept if the transaction aborts, see `Transaction::write_redo_only`
+    pub fn logged_redo_only(mut self, txn: &'a Transaction) -> Self {
+        self.write = Transaction::write_redo_only;
+        self.logged(txn)
+    }
+}
+
+impl<T> Deref for View<'_, T>
+where
+    for<'b> &'b T: Into<PageBuf>,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for View<'_, T>
+where
+    for<'b> &'b T: Into<PageBuf>,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.changed = true;
+        &mut self.value
+    }
+}
+
+impl<T> Drop for View<'_, T>
+where
+    for<'b> &'b T: Into<PageBuf>,
+{
+    fn drop(&mut self) {
+        if !self.changed {
+            return;
+        }
+
+        let data: PageBuf = (&self.value).into();
+        match self.txn {
+            Some(txn) => (self.write)(txn, &mut self.page, &data),
+            None if data[PAGE_HEADER_SIZE..] != self.page.data[PAGE_HEADER_SIZE..] => {
+                // The header is stamped by the page cache
+                self.page.data[PAGE_HEADER_SIZE..].copy_from_slice(&data[PAGE_HEADER_SIZE..]);
+                self.page.dirty = true;
+            }
+            None => {}
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum PageCacheError {
     Disk(std::io::ErrorKind),
     OutOfMemory,
+    /// The file wasn't created by this version of the database
+    Incompatible(String),
+    /// The page didn't match its checksum when read
+    Corrupt {
+        page_id: PageId,
+    },
+    /// The tuple doesn't fit on an empty page
+    TupleTooLarge,
 }
 pub type Result<T> = std::result::Result<T, PageCacheError>;
 
-pub struct PageCache<D: Disk = FileSystem> {
-    pages: Box<[Page; CACHE_SIZE]>,
-    page_table: RwLock<HashMap<PageId, FrameId>>,
-    free: FreeList<CACHE_SIZE>,
+impl From<std::io::Error> for PageCacheError {
+    fn from(e: std::io::Error) -> Self {
+        PageCacheError::Disk(e.kind())
+    }
+}
+
+const SHARDS: usize = 16;
+
+/// Maps pages to the frames holding them. The map is split into shards so threads fetching
+/// different pages rarely wait on each other.
+struct PageTable {
+    shards: Box<[RwLock<HashMap<PageId, FrameId>>]>,
+    /// Pins held on each frame, only changed whilst holding the shard of the page in the frame
+    pins: Box<[AtomicUsize]>,
+    /// The page in each frame, -1 if the frame is empty
+    ids: Box<[AtomicI64]>,
+}
+
+impl PageTable {
+    fn new(frames: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            pins: (0..frames).map(|_| AtomicUsize::new(0)).collect(),
+            ids: (0..frames).map(|_| AtomicI64::new(-1)).collect(),
+        }
+    }
+
+    fn shard(&self, page_id: PageId) -> &RwLock<HashMap<PageId, FrameId>> {
+        &self.shards[page_id as u32 as usize % self.shards.len()]
+    }
+
+    fn page_ids(&self) -> Vec<PageId> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.read().expect("todo").keys().copied().collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+struct FreeMap {
+    /// -1 until a page is deallocated
+    root: PageId,
+    /// Cleared once a search finds nothing so allocating doesn't scan the map every time
+    maybe_free: bool,
+}
+
+pub struct PageCache<D: Disk = FileSystem, R: Replacer = LRU> {
+    pages: Box<[Page]>,
+    page_table: PageTable,
+    free: FreeList,
     disk: D,
-    next_page_id: AtomicI32,
-    replacer: Arc<LRU>,
+    next_page_id: AtomicI64,
+    /// The next page of each relation other than 0, read from the disk when first allocated from
+    relations: Mutex<HashMap<RelationId, u32>>,
+    free_map: Mutex<FreeMap>,
+    replacer: Arc<R>,
+    wal: Option<Arc<Wal>>,
+    /// Reads started by `prefetch` that no fetch has used yet
+    prefetched: Mutex<HashMap<PageId, BoxFuture<'static, std::io::Result<PageBuf>>>>,
 }
-pub type SharedPageCache<D> = Arc<PageCache<D>>;
+pub type SharedPageCache<D, R = LRU> = Arc<PageCache<D, R>>;
+
+impl<D: Disk, R: Replacer> PageCache<D, R> {
+    pub fn new(disk: D, replacer: Arc<R>, next_page_id: PageId) -> Arc<Self> {
+        Self::with_capacity(disk, replacer, next_page_id, CACHE_SIZE)
+    }
+
+    /// Cache up to `frames` pages in memory
+    pub fn with_capacity(
+        disk: D,
+        replacer: Arc<R>,
+        next_page_id: PageId,
+        frames: usize,
+    ) -> Arc<Self> {
+        Self::_new(disk, replacer, next_page_id, None, frames)
+    }
+
+    /// Dirty pages are only written once the log has been flushed up to their LSN
+    pub fn new_with_wal(
+        disk: D,
+        replacer: Arc<R>,
+        next_page_id: PageId,
+        wal: Arc<Wal>,
+    ) -> Arc<Self> {
+        Self::with_capacity_and_wal(disk, replacer, next_page_id, wal, CACHE_SIZE)
+    }
+
+    pub fn with_capacity_and_wal(
+        disk: D,
+        replacer: Arc<R>,
+        next_page_id: PageId,
+        wal: Arc<Wal>,
+        frames: usize,
+    ) -> Arc<Self> {
+        Self::_new(disk, replacer, next_page_id, Some(wal), frames)
+    }
+
+    /// Recover `disk` from the log before using it
+    pub fn open(
+        disk: D,
+        replacer: Arc<R>,
+        next_page_id: PageId,
+        wal: Arc<Wal>,
+    ) -> Result<Arc<Self>> {
+        Self::open_with_capacity(disk, replacer, next_page_id, wal, CACHE_SIZE)
+    }
 
-impl<D: Disk> PageCache<D> {
-    pub fn new(disk: D, replacer: Arc<LRU>, next_page_id: PageId) -> Arc<Self> {
-        let pages = Box::new(std::array::from_fn(|_| Page::default()));
-        let page_table = RwLock::new(HashMap::new());
-        let free = FreeList::default();
-        let next_page_id = AtomicI32::new(next_page_id);
+    pub fn open_with_capacity(
+        disk: D,
+        replacer: Arc<R>,
+        next_page_id: PageId,
+        wal: Arc<Wal>,
+        frames: usize,
+    ) -> Result<Arc<Self>> {
+        let recovered = recovery::recover(&disk, &wal)?;
 
-        Arc::new(Self { pages, page_table, free, disk, next_page_id, replacer })
+        Ok(Self::with_capacity_and_wal(disk, replacer, next_page_id.max(recovered), wal, frames))
     }
 
-    fn allocate_page(&self) -> PageId {
-        self.next_page_id.fetch_add(1, Relaxed)
+    fn _new(
+        disk: D,
+        replacer: Arc<R>,
+        next_page_id: PageId,
+        wal: Option<Arc<Wal>>,
+        frames: usize,
+    ) -> Arc<Self> {
+        assert!(frames > 0, "the page cache needs at least one frame");
+
+        let pages = (0..frames).map(|_| Page::default()).collect();
+        let page_table = PageTable::new(frames);
+        let free = FreeList::new(frames);
+        let next_page_id = AtomicI64::new(next_page_id);
+        let relations = Mutex::new(HashMap::new());
+        let free_map = Mutex::new(FreeMap { root: -1, maybe_free: false });
+        let prefetched = Mutex::new(HashMap::new());
+
+        Arc::new(Self {
+            pages,
+            page_table,
+            free,
+            disk,
+            next_page_id,
+            relations,
+            free_map,
+            replacer,
+            wal,
+            prefetched,
+        })
     }
 
-    pub fn new_page<'a>(&self) -> Result<Pin> {
-        let page_id = self.allocate_page();
+    /// The number of frames in the cache
+    pub fn capacity(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn wal(&self) -> Option<&Arc<Wal>> {
+        self.wal.as_ref()
+    }
+
+    pub fn next_page_id(&self) -> PageId {
+        self.next_page_id.load(Relaxed)
+    }
+
+    pub fn free_map_root(&self) -> Option<PageId> {
+        let free_map = self.free_map.lock().expect("todo");
 
-        self.try_get_page(page_id)
+        (free_map.root != -1).then_some(free_map.root)
+    }
+
+    /// Allocate from an existing free space map, it is created on the first deallocation otherwise
+    pub fn set_free_map_root(&self, root: PageId) -> Result<()> {
+        let mut free_map = self.free_map.lock().expect("todo");
+        *free_map = FreeMap { root, maybe_free: true };
+
+        // Map pages are never in the map themselves, make sure none of them are handed out again
+        let mut map_page_id = root;
+        while map_page_id != 0 {
+            self.next_page_id.fetch_max(map_page_id + 1, Relaxed);
+            map_page_id = MapPage::from(&self.fetch_page(map_page_id)?.read().data).next_page_id;
+        }
+
+        Ok(())
     }
 
-    pub fn fetch_page<'a>(&self, page_id: PageId) -> Result<Pin> {
-        if let Some(i) = self.page_table.read().expect("todo").get(&page_id) {
-            let mut replacer = self.replacer.lock();
-            replacer.record_access(*i, AccessType::Get);
-            replacer.pin(*i);
+    /// Create the free space map if there isn't one, so its root can be stored before anything is
+    /// freed. Returns the root.
+    pub fn init_free_map(&self) -> Result<PageId> {
+        let mut free_map = self.free_map.lock().expect("todo");
+        if free_map.root == -1 {
+            free_map.root = self.next_page_id.fetch_add(1, Relaxed);
+            self.update_map_page(free_map.root, |_| ())?;
+        }
+
+        Ok(free_map.root)
+    }
+
+    fn allocate_page(&self) -> Result<PageId> {
+        match self.pop_free_page()? {
+            Some(page_id) => Ok(page_id),
+            None => Ok(self.next_page_id.fetch_add(1, Relaxed)),
+        }
+    }
+
+    pub fn new_page(&self) -> Result<Pin<'_, R>> {
+        let page_id = self.allocate_page()?;
+
+        self.try_get_page(page_id, AccessType::Get)
+    }
+
+    /// Allocate a page in `relation`, the disk has to store relations separately. Pages of
+    /// relations other than 0 are only reclaimed by dropping the relation.
+    pub fn new_page_in(&self, relation: RelationId) -> Result<Pin<'_, R>> {
+        if relation == 0 {
+            return self.new_page();
+        }
 
-            return Ok(Pin::new(&self.pages[*i], *i, page_id, self.replacer.clone()));
+        let mut relations = self.relations.lock().expect("todo");
+        let next = match relations.entry(relation) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.disk.relation_len(relation)?),
         };
+        let page_id = relation_page(relation, *next);
+        *next += 1;
+        drop(relations);
 
-        self.try_get_page(page_id)
+        self.try_get_page(page_id, AccessType::Get)
     }
 
-    fn try_get_page(&self, page_id: PageId) -> Result<Pin> {
-        let i = match self.free.pop() {
-            Some(i) => i,
-            None => self.replacer.evict().ok_or(PageCacheError::OutOfMemory)?, // All pages are pinned
+    /// Discard the cached pages of `relation` and remove it from the disk. Nothing can be using the
+    /// relation, and it can't be rolled back.
+    pub fn drop_relation(&self, relation: RelationId) -> Result<()> {
+        assert!(relation != 0, "relation 0 can't be dropped");
+
+        // Fail before anything is logged if the disk can't drop relations
+        self.disk.relation_len(relation)?;
+
+        let mut relations = self.relations.lock().expect("todo");
+        for page_id in self.page_table.page_ids() {
+            if page_relation(page_id) == relation {
+                self.discard_page(page_id);
+            }
+        }
+        self.prefetched
+            .lock()
+            .expect("todo")
+            .retain(|page_id, _| page_relation(*page_id) != relation);
+
+        // Recovery repeats the drop if the files weren't removed before a crash
+        if let Some(wal) = &self.wal {
+            wal.flush(wal.append(&LogRecord::Drop { relation }))?;
+        }
+        self.disk.drop_relation(relation)?;
+        relations.remove(&relation);
+
+        Ok(())
+    }
+
+    /// Map page changes are made durable straight away, by logging them or by writing the page
+    /// without a log. A page has to be marked as allocated before it is used, or it could be
+    /// handed out twice after a restart.
+    fn update_map_page<T>(&self, page_id: PageId, f: impl FnOnce(&mut MapPage) -> T) -> Result<T> {
+        let page = self.fetch_page(page_id)?;
+        let mut page_w = page.write();
+        let mut map = MapPage::from(&page_w.data);
+
+        let ret = f(&mut map);
+        let data = PageBuf::from(&map);
+        match &self.wal {
+            Some(wal) => wal.flush(wal.write_redo(&mut page_w, &data))?,
+            None => {
+                page_w.data = data;
+                self.write_page(&mut page_w)?;
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn pop_free_page(&self) -> Result<Option<PageId>> {
+        let mut free_map = self.free_map.lock().expect("todo");
+        if free_map.root == -1 || !free_map.maybe_free {
+            return Ok(None);
+        }
+
+        let mut map_page_id = free_map.root;
+        let mut start = 0;
+        while map_page_id != 0 {
+            let (i, next_page_id) =
+                self.update_map_page(map_page_id, |map| (map.pop(), map.next_page_id))?;
+            if let Some(i) = i {
+                return Ok(Some(start + i as PageId));
+            }
+
+            start += PAGES_PER_MAP as PageId;
+            map_page_id = next_page_id;
+        }
+
+        free_map.maybe_free = false;
+
+        Ok(None)
+    }
+
+    /// Return the page to the free space map so `new_page` can reuse it. The page is zeroed on
+    /// disk so it reads the same as a page that was never allocated, and the zeroing is logged so
+    /// recovery can't bring the old data back. Transactions free pages through
+    /// `Transaction::deallocate`, an abort could still need them.
+    pub(crate) fn deallocate_page(&self, page_id: PageId) -> Result<()> {
+        {
+            let page = self.fetch_page(page_id)?;
+            let mut page_w = page.write();
+            match &self.wal {
+                Some(wal) => _ = wal.write_redo(&mut page_w, &[0; PAGE_SIZE]),
+                None => page_w.data.fill(0),
+            }
+            self.write_page(&mut page_w)?;
+        }
+        self.remove_page(page_id);
+
+        // Pages of other relations are reclaimed by dropping the relation
+        if page_relation(page_id) != 0 {
+            return Ok(());
+        }
+
+        let mut free_map = self.free_map.lock().expect("todo");
+        if free_map.root == -1 {
+            // Map pages are never deallocated, so they always come from the end of the file
+            free_map.root = self.next_page_id.fetch_add(1, Relaxed);
+        }
+
+        let mut map_page_id = free_map.root;
+        for _ in 0..page_id as usize / PAGES_PER_MAP {
+            map_page_id = self.update_map_page(map_page_id, |map| {
+                if map.next_page_id == 0 {
+                    map.next_page_id = self.next_page_id.fetch_add(1, Relaxed);
+                }
+
+                map.next_page_id
+            })?;
+        }
+
+        self.update_map_page(map_page_id, |map| {
+            map.free.set(page_id as usize % PAGES_PER_MAP, true)
+        })?;
+        free_map.maybe_free = true;
+
+        Ok(())
+    }
+
+    pub fn fetch_page(&self, page_id: PageId) -> Result<Pin<'_, R>> {
+        self.fetch_page_with(page_id, AccessType::Get)
+    }
+
+    /// Sequential scans should fetch with `AccessType::Scan` so the pages they read are evicted
+    /// ahead of the rest of the cache
+    pub fn fetch_page_with(&self, page_id: PageId, access_type: AccessType) -> Result<Pin<'_, R>> {
+        let shard = self.page_table.shard(page_id).read().expect("todo");
+        if let Some(&i) = shard.get(&page_id) {
+            self.replacer.record_access(i, page_id, access_type);
+
+            return Ok(self.pin(i, page_id));
         };
+        drop(shard);
 
-        let mut page_w = self.pages[i].write();
-        let mut replacer = self.replacer.lock();
-        replacer.remove(i);
-        replacer.record_access(i, AccessType::Get);
-        replacer.pin(i);
+        self.try_get_page(page_id, access_type)
+    }
 
+    fn pin(&self, i: FrameId, page_id: PageId) -> Pin<'_, R> {
+        Pin::new(&self.pages[i], i, page_id, &self.page_table, &*self.replacer)
+    }
+
+    fn try_get_page(&self, page_id: PageId, access_type: AccessType) -> Result<Pin<'_, R>> {
+        let i = self.take_frame()?;
+
+        let mut shard = self.page_table.shard(page_id).write().expect("todo");
+        if let Some(&j) = shard.get(&page_id) {
+            // Another thread loaded the page whilst a frame was found for it
+            self.free.push(i);
+            self.replacer.record_access(j, page_id, access_type);
+
+            return Ok(self.pin(j, page_id));
+        }
+
+        let mut page_w = self.pages[i].write();
         if page_w.dirty {
-            self.disk
-                .write_page(page_w.id, &page_w.data)
-                .map_err(|e| PageCacheError::Disk(e.kind()))?;
+            // Removed pages are written when their frame is reused
+            if let Err(e) = self.write_page(&mut page_w) {
+                self.free.push(i);
+                return Err(e);
+            }
         }
+        page_w.reset();
 
-        let mut page_table = self.page_table.write().expect("todo");
-        page_table.remove(&page_w.id);
-        page_table.insert(page_id, i);
+        let data = match self.read_page(page_id) {
+            Ok(data) if verify_checksum(&data) => Ok(data),
+            Ok(_) => Err(PageCacheError::Corrupt { page_id }),
+            Err(e) => Err(PageCacheError::Disk(e.kind())),
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                // Leave the frame empty rather than holding bytes that can't be trusted
+                self.free.push(i);
+                return Err(e);
+            }
+        };
 
-        let data = self.disk.read_page(page_id).map_err(|e| PageCacheError::Disk(e.kind()))?;
-        page_w.reset();
         page_w.id = page_id;
+        page_w.lsn = Lsn::from_be_bytes(data[PAGE_LSN].try_into().unwrap());
         page_w.data = data;
+        drop(page_w);
+
+        shard.insert(page_id, i);
+        self.page_table.ids[i].store(page_id, Relaxed);
+        self.replacer.record_access(i, page_id, access_type);
 
-        Ok(Pin::new(&self.pages[i], i, page_id, self.replacer.clone()))
+        Ok(self.pin(i, page_id))
     }
 
-    pub fn remove_page(&self, page_id: PageId) {
-        use std::collections::hash_map::Entry;
-        let i = match self.page_table.write().expect("todo").entry(page_id) {
-            Entry::Occupied(entry) => {
-                let i = *entry.get();
-                entry.remove();
-                i
+    fn read_page(&self, page_id: PageId) -> std::io::Result<PageBuf> {
+        let prefetched = self.prefetched.lock().expect("todo").remove(&page_id);
+        match prefetched {
+            Some(read) => executor::block_on(read),
+            None => self.disk.read_page(page_id),
+        }
+    }
+
+    /// Start reading the page so a later fetch doesn't have to wait for it. Does nothing if the
+    /// page is cached or already being read.
+    pub fn prefetch(&self, page_id: PageId) {
+        if self.page_table.shard(page_id).read().expect("todo").contains_key(&page_id) {
+            return;
+        }
+
+        let mut prefetched = self.prefetched.lock().expect("todo");
+        if prefetched.contains_key(&page_id) {
+            return;
+        }
+
+        // Pages that were prefetched but never fetched are dropped to make room
+        if prefetched.len() >= self.pages.len() {
+            let old = *prefetched.keys().next().unwrap();
+            prefetched.remove(&old);
+        }
+
+        // Started with the lock held so `write_page` can't miss it
+        prefetched.insert(page_id, self.disk.read_page_async(page_id));
+    }
+
+    /// Returns an empty frame that isn't in the page table or tracked by the replacer, writing the
+    /// page it held if it was dirty
+    fn take_frame(&self) -> Result<FrameId> {
+        if let Some(i) = self.free.pop() {
+            return Ok(i);
+        }
+
+        loop {
+            // All pages are pinned
+            let i = self.replacer.evict().ok_or(PageCacheError::OutOfMemory)?;
+            let page_id = self.page_table.ids[i].load(Relaxed);
+
+            let mut shard = self.page_table.shard(page_id).write().expect("todo");
+            if shard.get(&page_id) != Some(&i) {
+                // The page was removed after the frame was chosen, the frame is on the free list
+                continue;
+            }
+
+            // The frame can be pinned between the replacer choosing it and the shard being locked
+            let pins = self.page_table.pins[i].load(Relaxed);
+            if pins != 0 {
+                self.replacer.record_access(i, page_id, AccessType::Get);
+                for _ in 0..pins {
+                    self.replacer.pin(i);
+                }
+                continue;
+            }
+
+            // Nothing else can reach the frame now, and the page can't be read back from disk
+            // until it has been written
+            let mut page_w = self.pages[i].write();
+            if page_w.dirty {
+                if let Err(e) = self.write_page(&mut page_w) {
+                    self.replacer.record_access(i, page_id, AccessType::Get);
+                    return Err(e);
+                }
             }
-            Entry::Vacant(_) => return,
+            page_w.reset();
+
+            shard.remove(&page_id);
+            self.page_table.ids[i].store(-1, Relaxed);
+
+            return Ok(i);
+        }
+    }
+
+    pub fn remove_page(&self, page_id: PageId) {
+        let mut shard = self.page_table.shard(page_id).write().expect("todo");
+        let Some(i) = shard.remove(&page_id) else {
+            return;
+        };
+        self.page_table.ids[i].store(-1, Relaxed);
+
+        self.replacer.remove(i);
+        self.free.push(i);
+    }
+
+    /// Remove the page without writing it
+    fn discard_page(&self, page_id: PageId) {
+        let mut shard = self.page_table.shard(page_id).write().expect("todo");
+        let Some(i) = shard.remove(&page_id) else {
+            return;
         };
+        self.page_table.ids[i].store(-1, Relaxed);
+        self.pages[i].write().reset();
 
         self.replacer.remove(i);
         self.free.push(i);
     }
 
     pub fn flush_page(&self, page_id: PageId) -> Result<()> {
-        let page_table = self.page_table.read().expect("todo");
-        let Some(i) = page_table.get(&page_id) else {
-            return Ok(());
+        // Pin the page so it can't be evicted, without holding the shard whilst waiting for it
+        let pin = {
+            let shard = self.page_table.shard(page_id).read().expect("todo");
+            let Some(&i) = shard.get(&page_id) else {
+                return Ok(());
+            };
+
+            self.pin(i, page_id)
         };
 
-        let mut page_w = self.pages[*i].write();
+        let mut page_w = pin.write();
+
+        self.write_page(&mut page_w)
+    }
+
+    fn write_page(&self, page: &mut PageInner) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            wal.flush(page.lsn).map_err(|e| PageCacheError::Disk(e.kind()))?;
+        }
 
-        self.disk
-            .write_page(page_w.id, &page_w.data)
-            .map_err(|e| PageCacheError::Disk(e.kind()))?;
-        page_w.dirty = false;
+        page.data[PAGE_LSN].copy_from_slice(&page.lsn.to_be_bytes());
+        set_checksum(&mut page.data);
+        self.disk.write_page(page.id, &page.data).map_err(|e| PageCacheError::Disk(e.kind()))?;
+        page.dirty = false;
+
+        // A read started before the write could return the old page
+        self.prefetched.lock().expect("todo").remove(&page.id);
 
         Ok(())
     }
 
     pub fn flush_all_pages(&self) -> Result<()> {
-        for page_id in self.page_table.read().expect("todo").keys() {
-            self.flush_page(*page_id)?;
+        for page_id in self.page_table.page_ids() {
+            self.flush_page(page_id)?;
         }
 
         Ok(())
     }
+
+    /// Make every page written so far durable
+    pub fn sync(&self) -> Result<()> {
+        self.disk.sync().map_err(|e| PageCacheError::Disk(e.kind()))
+    }
+
+    /// Write the dirty pages that aren't pinned, skipping any that are locked. Returns the number
+    /// of pages written.
+    pub fn write_dirty_pages(&self) -> Result<usize> {
+        let mut written = 0;
+        for i in 0..self.pages.len() {
+            let page_id = self.page_table.ids[i].load(Relaxed);
+            if page_id == -1 || self.page_table.pins[i].load(Relaxed) != 0 {
+                continue;
+            }
+
+            let pin = {
+                let shard = self.page_table.shard(page_id).read().expect("todo");
+                if shard.get(&page_id) != Some(&i) {
+                    continue;
+                }
+
+                self.pin(i, page_id)
+            };
+
+            // Never wait on a page that is in use, it will be written next time
+            let Some(mut page_w) = pin.page.try_write() else {
+                continue;
+            };
+            if page_w.dirty {
+                self.write_page(&mut page_w)?;
+                written += 1;
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+impl<D: Disk + Send + Sync + 'static, R: Replacer + 'static> PageCache<D, R> {
+    /// Write dirty pages every `interval` on a background thread until the returned handle is
+    /// dropped, so evicting a page rarely has to wait for it to be written
+    pub fn start_flusher(self: &Arc<Self>, interval: Duration) -> Flusher {
+        let (stop, rx) = mpsc::channel::<()>();
+        let pc = self.clone();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                if let Err(e) = pc.write_dirty_pages() {
+                    eprintln!("ERROR: could not write dirty pages - {e:?}");
+                }
+            }
+        });
+
+        Flusher { stop: Some(stop), handle: Some(handle) }
+    }
+}
+
+pub struct Flusher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the flusher up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{sync::Arc, thread};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering::Relaxed},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use bytes::BytesMut;
+    use rand::{thread_rng, Rng};
 
     use crate::{
-        disk::Memory,
-        page::PAGE_SIZE,
+        disk::{Disk, Faulty, Memory},
+        page::{PageBuf, PageId, PAGE_HEADER_SIZE, PAGE_LSN, PAGE_SIZE},
         page_cache::{FreeList, PageCache, PageCacheError, CACHE_SIZE},
-        replacer::LRU,
+        replacer::{AccessType, Clock, Partitioned, Replacer, TwoQ, ARC, LRU},
+        table::tuple::{RId, TupleMeta},
+        wal::{LogMemory, Lsn, Wal},
         writep,
     };
 
     #[test]
     fn test_pm_read() -> Result<(), PageCacheError> {
-        const MEMORY: usize = PAGE_SIZE * CACHE_SIZE;
         const K: usize = 2;
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let replacer = LRU::new(K);
         let pc = PageCache::new(disk, replacer, 0);
 
@@ -282,9 +968,8 @@ mod test {
 
     #[test]
     fn test_pm_replacer_full() -> Result<(), PageCacheError> {
-        const MEMORY: usize = PAGE_SIZE * CACHE_SIZE;
         const K: usize = 2;
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let replacer = LRU::new(K);
         let pc = PageCache::new(disk, replacer, 0);
 
@@ -299,11 +984,388 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_pm_capacity() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Memory::default();
+        let pc = PageCache::with_capacity(disk, LRU::new(K), 0, 3);
+        assert_eq!(pc.capacity(), 3);
+
+        let mut pages = Vec::new();
+        for _ in 0..3 {
+            pages.push(pc.new_page()?);
+        }
+        assert_eq!(pc.new_page().err(), Some(PageCacheError::OutOfMemory));
+
+        // Unpinning a page frees up a frame for the next one
+        let id = pages.pop().unwrap().id;
+        assert_ne!(pc.new_page()?.id, id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_replacers() -> Result<(), PageCacheError> {
+        fn read_back<R: Replacer>(replacer: Arc<R>) -> Result<(), PageCacheError> {
+            let pc = PageCache::with_capacity(Memory::default(), replacer, 0, 4);
+
+            for i in 0..16 {
+                let page = pc.new_page()?;
+                let mut w = page.write();
+                writep!(w, 100..101, &[i]);
+            }
+            for i in (0..16).rev() {
+                assert_eq!(pc.fetch_page(i as PageId)?.read().data[100], i);
+            }
+
+            Ok(())
+        }
+
+        read_back(LRU::new(2))?;
+        read_back(Clock::new())?;
+        read_back(TwoQ::new(4))?;
+        read_back(ARC::new(4))
+    }
+
+    #[test]
+    fn test_pm_scan() -> Result<(), PageCacheError> {
+        fn scan<R: Replacer>(replacer: Arc<R>) -> Result<(), PageCacheError> {
+            let pc = PageCache::with_capacity(Memory::default(), replacer, 0, 8);
+
+            for _ in 0..2 {
+                for hot in 0..4 {
+                    pc.fetch_page(hot)?;
+                }
+            }
+
+            // A scan larger than the cache leaves the hot pages alone
+            for page_id in 4..64 {
+                pc.fetch_page_with(page_id, AccessType::Scan)?;
+            }
+            assert!((0..4).all(|hot| pc.page_table.shard(hot).read().unwrap().contains_key(&hot)));
+
+            Ok(())
+        }
+
+        scan(LRU::new(2))?;
+        scan(Clock::new())?;
+        scan(TwoQ::new(8))?;
+        scan(ARC::new(8))
+    }
+
+    #[test]
+    fn test_pm_wal() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Memory::default();
+        let replacer = LRU::new(K);
+        let wal = Wal::new(LogMemory::default()).unwrap();
+        let pc = PageCache::new_with_wal(disk, replacer, 0, wal.clone());
+
+        let (id, lsn) = {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            let mut data = w.data;
+            data[100..104].copy_from_slice(b"test");
+
+            (page.id, wal.write(1, 0, &mut w, &data))
+        };
+        assert!(wal.flushed_lsn() <= lsn);
+
+        // Evict the page, the log has to be flushed before it is written
+        for _ in 0..CACHE_SIZE {
+            pc.new_page()?;
+        }
+        assert!(wal.flushed_lsn() > lsn);
+
+        let data = pc.disk.read_page(id).unwrap();
+        assert_eq!(Lsn::from_be_bytes(data[PAGE_LSN].try_into().unwrap()), lsn);
+        assert_eq!(&data[100..104], b"test");
+
+        let page = pc.fetch_page(id)?;
+        assert_eq!(page.read().lsn, lsn);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_deallocate() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Arc::new(Memory::default());
+        let pc = PageCache::new(disk.clone(), LRU::new(K), 0);
+
+        for i in 0..4 {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            writep!(w, 100..101, &[i + 1]);
+        }
+
+        pc.deallocate_page(2)?;
+        pc.deallocate_page(1)?;
+        let root = pc.free_map_root().unwrap();
+        assert_eq!(root, 4);
+
+        // Freed pages are reused first and read back empty
+        for want in [1, 2, 5] {
+            let page = pc.new_page()?;
+            assert_eq!(page.id, want);
+            assert!(page.read().data[PAGE_HEADER_SIZE..].iter().all(|b| *b == 0));
+        }
+
+        // The map is on disk
+        pc.deallocate_page(0)?;
+        pc.flush_all_pages()?;
+        drop(pc);
+        let pc = PageCache::new(disk, LRU::new(K), 6);
+        pc.set_free_map_root(root)?;
+        assert_eq!(pc.new_page()?.id, 0);
+        assert_eq!(pc.new_page()?.id, 6);
+        assert_eq!(pc.fetch_page(3)?.read().data[100], 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_deallocate_crash() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Arc::new(Faulty::new(Memory::default()));
+        let log = Arc::new(LogMemory::default());
+        let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), 0, Wal::new(log.clone())?);
+
+        for _ in 0..4 {
+            pc.new_page()?;
+        }
+        pc.deallocate_page(2)?;
+        pc.deallocate_page(1)?;
+        let root = pc.free_map_root().unwrap();
+
+        // Lose every page written since the last sync, recovery rebuilds the map from the log
+        drop(pc);
+        disk.crash()?;
+        let pc = PageCache::open(disk, LRU::new(K), 0, Wal::new(log)?)?;
+        pc.set_free_map_root(root)?;
+        for want in [1, 2, 5] {
+            assert_eq!(pc.new_page()?.id, want);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_corrupt() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Arc::new(Memory::default());
+        let pc = PageCache::new(disk.clone(), LRU::new(K), 0);
+
+        let id = {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            writep!(w, 100..104, b"test");
+
+            page.id
+        };
+        pc.flush_page(id)?;
+        pc.remove_page(id);
+
+        let mut data = disk.read_page(id).unwrap();
+        data[101] ^= 1;
+        disk.write_page(id, &data).unwrap();
+
+        assert_eq!(pc.fetch_page(id).err(), Some(PageCacheError::Corrupt { page_id: id }));
+        assert_eq!(pc.fetch_page(id).err(), Some(PageCacheError::Corrupt { page_id: id }));
+
+        // Pages that were never written are fine
+        assert!(pc.new_page().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_dirty() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let pc = PageCache::new(Memory::default(), LRU::new(K), 0);
+        let page = pc.new_page()?;
+
+        // Only changes mark the page dirty
+        assert_eq!(page.write().data, [0; PAGE_SIZE]);
+        assert!(!page.read().dirty);
+        page.write().data[100] = 1;
+        assert!(page.read().dirty);
+        pc.flush_page(page.id)?;
+
+        // Views are written back when dropped
+        let r_id = RId { page_id: page.id, slot_id: 0 };
+        assert_eq!(page.as_heap_page().len(), 0);
+        assert!(!page.read().dirty);
+        page.as_heap_page().insert(&BytesMut::from(&b"row"[..]), &TupleMeta::default());
+        assert!(page.read().dirty);
+
+        pc.flush_page(page.id)?;
+        let id = page.id;
+        drop(page);
+        pc.remove_page(id);
+        let (_, tuple) = pc.fetch_page(id)?.as_heap_page().get(&r_id).unwrap();
+        assert_eq!(&tuple.data[..], b"row");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_stress() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        const THREADS: usize = 8;
+        const OPS: usize = 2000;
+        let replacer = Partitioned::new(4, || LRU::new(K));
+        let pc = PageCache::with_capacity(Memory::default(), replacer, 0, 16);
+
+        // Each page holds its id followed by a counter
+        for _ in 0..64 {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            writep!(w, 100..108, &page.id.to_be_bytes());
+        }
+
+        // Far more pages than frames, so threads are constantly evicting each other's pages
+        let writes = thread::scope(|s| {
+            let threads = (0..THREADS).map(|_| {
+                s.spawn(|| {
+                    let mut rng = thread_rng();
+                    let mut writes = 0;
+                    for _ in 0..OPS {
+                        let page_id = rng.gen_range(0..64);
+                        if rng.gen_bool(0.5) {
+                            let page = pc.fetch_page_with(page_id, AccessType::Scan).unwrap();
+                            assert_eq!(page.read().data[100..108], page_id.to_be_bytes());
+                            continue;
+                        }
+
+                        let page = pc.fetch_page(page_id).unwrap();
+                        let mut w = page.write();
+                        assert_eq!(w.data[100..108], page_id.to_be_bytes());
+                        let count = u32::from_be_bytes(w.data[108..112].try_into().unwrap());
+                        writep!(w, 108..112, &(count + 1).to_be_bytes());
+                        writes += 1;
+                    }
+
+                    writes
+                })
+            });
+
+            threads.collect::<Vec<_>>().into_iter().map(|t| t.join().unwrap()).sum::<usize>()
+        });
+
+        let mut total = 0;
+        for page_id in 0..64 {
+            let page = pc.fetch_page(page_id)?;
+            let r = page.read();
+            total += u32::from_be_bytes(r.data[108..112].try_into().unwrap()) as usize;
+        }
+        assert_eq!(total, writes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_prefetch() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+
+        struct Counting {
+            inner: Memory,
+            reads: AtomicUsize,
+        }
+
+        impl Disk for Counting {
+            fn read_page(&self, page_id: PageId) -> std::io::Result<PageBuf> {
+                self.reads.fetch_add(1, Relaxed);
+                self.inner.read_page(page_id)
+            }
+
+            fn write_page(&self, page_id: PageId, data: &PageBuf) -> std::io::Result<()> {
+                self.inner.write_page(page_id, data)
+            }
+        }
+
+        let disk = Arc::new(Counting { inner: Memory::default(), reads: AtomicUsize::new(0) });
+        let pc = PageCache::with_capacity(disk.clone(), LRU::new(K), 0, 4);
+        for i in 0..4 {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            writep!(w, 100..101, &[i + 1]);
+        }
+        pc.flush_all_pages()?;
+        for page_id in 0..4 {
+            pc.remove_page(page_id);
+        }
+        disk.reads.store(0, Relaxed);
+
+        // The page is read once, by the prefetch
+        pc.prefetch(2);
+        pc.prefetch(2);
+        assert_eq!(disk.reads.load(Relaxed), 1);
+        assert_eq!(pc.fetch_page(2)?.read().data[100], 3);
+        assert_eq!(disk.reads.load(Relaxed), 1);
+
+        // Cached pages aren't read again
+        pc.prefetch(2);
+        assert_eq!(disk.reads.load(Relaxed), 1);
+
+        // Prefetched pages that are never fetched don't stop later prefetches
+        for page_id in 0..8 {
+            pc.prefetch(page_id);
+        }
+        assert!(pc.prefetched.lock().unwrap().len() <= pc.capacity());
+        assert_eq!(pc.fetch_page(3)?.read().data[100], 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pm_flusher() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        let disk = Arc::new(Memory::default());
+        let pc = PageCache::with_capacity(disk.clone(), LRU::new(K), 0, 4);
+
+        for i in 0..4 {
+            let page = pc.new_page()?;
+            let mut w = page.write();
+            writep!(w, 100..101, &[i + 1]);
+        }
+
+        // Pinned pages are left alone
+        let pinned = pc.fetch_page(0)?;
+        assert_eq!(pc.write_dirty_pages()?, 3);
+        assert_eq!(pc.write_dirty_pages()?, 0);
+        assert!(pinned.read().dirty);
+        drop(pinned);
+
+        let flusher = pc.start_flusher(Duration::from_millis(1));
+        let start = Instant::now();
+        while pc.pages.iter().any(|page| page.read().dirty) {
+            assert!(start.elapsed() < Duration::from_secs(5), "pages were never written");
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(flusher);
+
+        for i in 0..4 {
+            assert_eq!(disk.read_page(i).unwrap()[100], i as u8 + 1);
+        }
+
+        // Evicting clean pages doesn't write them again
+        let mut data = disk.read_page(3).unwrap();
+        data[100] = 0;
+        disk.write_page(3, &data).unwrap();
+        for _ in 0..4 {
+            pc.new_page()?;
+        }
+        assert_eq!(disk.read_page(3).unwrap()[100], 0);
+
+        Ok(())
+    }
+
     #[test]
     fn test_free_list() {
         thread::scope(|s| {
             const SIZE: usize = 8;
-            let list = Arc::new(FreeList::<SIZE>::default());
+            let list = Arc::new(FreeList::new(SIZE));
 
             // Pop
             let list_a = list.clone();
@@ -352,7 +1414,7 @@ mod test {
             c.join().unwrap();
             d.join().unwrap();
 
-            let mut got = unsafe { *list.free.get() };
+            let mut got = unsafe { (&*list.free.get()).to_vec() };
             got.sort();
 
             assert!(got == [4, 5, 6, 7, 8, 9, 10, 11]);
@@ -0,0 +1,757 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+};
+
+use crate::{
+    disk::Disk,
+    page::{
+        page_relation, set_checksum, verify_checksum, PageBuf, PageId, RelationId, PAGE_LSN,
+        PAGE_SIZE,
+    },
+    page_cache::{PageCacheError, Result, CACHE_SIZE},
+    table::node,
+    wal::{self, LogRecord, Lsn, TxnId, Wal},
+};
+
+#[derive(PartialEq)]
+enum TxnStatus {
+    Running,
+    Committed,
+}
+
+struct TxnEntry {
+    status: TxnStatus,
+    last_lsn: Lsn,
+}
+
+/// Pages held in memory at once during recovery
+const RECOVERY_PAGES: usize = CACHE_SIZE;
+
+/// Pages read during recovery. Once `RECOVERY_PAGES` are held one is written back to make room,
+/// the rest are written once redo and undo are done.
+struct Pages<'a, D: Disk> {
+    disk: &'a D,
+    wal: &'a Wal,
+    dirty: &'a HashMap<PageId, Lsn>,
+    redo_lsn: Lsn,
+    pages: HashMap<PageId, (Lsn, PageBuf)>,
+}
+
+impl<'a, D: Disk> Pages<'a, D> {
+    fn new(disk: &'a D, wal: &'a Wal, dirty: &'a HashMap<PageId, Lsn>, redo_lsn: Lsn) -> Self {
+        Self { disk, wal, dirty, redo_lsn, pages: HashMap::new() }
+    }
+
+    fn get(&mut self, page_id: PageId) -> Result<&mut (Lsn, PageBuf)> {
+        if !self.pages.contains_key(&page_id) && self.pages.len() >= RECOVERY_PAGES {
+            let evict = *self.pages.keys().next().expect("pages should be held");
+            let (lsn, data) = self.pages.remove(&evict).expect("page should be held");
+            self.write(evict, lsn, data)?;
+        }
+
+        match self.pages.entry(page_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let rebuild = self.dirty.contains_key(&page_id);
+                let data = match self.disk.read_page(page_id) {
+                    Ok(data) if verify_checksum(&data) => data,
+                    // A write torn by the crash, or one that fails to decrypt or decompress as the
+                    // crash came before the disk finished writing it. The page's first change
+                    // after the checkpoint logged an image of it, so redo can rebuild it from
+                    // zeros.
+                    Ok(_) if rebuild => [0; PAGE_SIZE],
+                    Err(e) if rebuild && e.kind() == io::ErrorKind::InvalidData => [0; PAGE_SIZE],
+                    Ok(_) => return Err(PageCacheError::Corrupt { page_id }),
+                    Err(e) => return Err(e.into()),
+                };
+
+                let lsn = Lsn::from_be_bytes(data[PAGE_LSN].try_into().unwrap());
+
+                Ok(entry.insert((lsn, data)))
+            }
+        }
+    }
+
+    fn apply(&mut self, lsn: Lsn, page_id: PageId, offset: u16, bytes: &[u8]) -> Result<()> {
+        let (page_lsn, data) = self.get(page_id)?;
+        let offset = offset as usize;
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        *page_lsn = lsn;
+
+        Ok(())
+    }
+
+    /// Log an image of the page if this is its first change since the checkpoint, so a write torn
+    /// by another crash can be repaired
+    fn image(&mut self, page_id: PageId) -> Result<()> {
+        let (redo_lsn, wal) = (self.redo_lsn, self.wal);
+        let (page_lsn, data) = self.get(page_id)?;
+        if *page_lsn < redo_lsn {
+            if let Some(image) = LogRecord::image(page_id, data) {
+                *page_lsn = wal.append(&image);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The log is flushed up to the page's LSN first, undo appends records as it goes
+    fn write(&self, page_id: PageId, lsn: Lsn, mut data: PageBuf) -> Result<()> {
+        self.wal.flush(lsn)?;
+
+        data[PAGE_LSN].copy_from_slice(&lsn.to_be_bytes());
+        set_checksum(&mut data);
+        self.disk.write_page(page_id, &data)?;
+
+        Ok(())
+    }
+
+    fn write_all(mut self) -> Result<()> {
+        for (page_id, (lsn, data)) in std::mem::take(&mut self.pages) {
+            self.write(page_id, lsn, data)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// ARIES style recovery, run before the page cache is created. Replays the log against `disk` and
+/// rolls back every transaction that did not commit.
+///
+/// Returns one past the highest page id of relation 0 referenced by the log.
+pub fn recover<D: Disk>(disk: &D, wal: &Wal) -> Result<PageId> {
+    // Find the last checkpoint, page ids are collected from the whole log as pages allocated
+    // before it may not be recorded anywhere else. Checkpoints hold the next page id from before
+    // the log was truncated.
+    let mut next_page_id = 0;
+    let mut checkpoint = None;
+    for result in wal.iter() {
+        match result? {
+            (
+                _,
+                LogRecord::Update { page_id, .. }
+                | LogRecord::Clr { page_id, .. }
+                | LogRecord::HeapUpdate { page_id, .. }
+                | LogRecord::Redo { page_id, .. },
+            ) if page_relation(page_id) == 0 => {
+                next_page_id = next_page_id.max(page_id + 1);
+            }
+            (_, LogRecord::Checkpoint { redo_lsn, next_page_id: page_id, txns, .. }) => {
+                next_page_id = next_page_id.max(page_id);
+                checkpoint = Some((redo_lsn, txns));
+            }
+            _ => {}
+        }
+    }
+
+    // Analysis: find the transactions that were in flight and the first LSN that dirtied each page.
+    // Everything logged before the checkpoint's redo LSN is already on disk.
+    let mut txns: HashMap<TxnId, TxnEntry> = HashMap::new();
+    let mut dirty: HashMap<PageId, Lsn> = HashMap::new();
+    let mut dropped: HashMap<RelationId, Lsn> = HashMap::new();
+    let redo_lsn = checkpoint.as_ref().map_or(0, |(redo_lsn, _)| *redo_lsn);
+    let records = match checkpoint {
+        Some((redo_lsn, running)) => {
+            for (txn_id, last_lsn) in running {
+                txns.insert(txn_id, TxnEntry { status: TxnStatus::Running, last_lsn });
+            }
+
+            wal.iter_from(redo_lsn)
+        }
+        None => wal.iter(),
+    };
+
+    for result in records {
+        let (lsn, record) = result?;
+
+        match &record {
+            LogRecord::End { txn_id, .. } => {
+                txns.remove(txn_id);
+                continue;
+            }
+            LogRecord::Checkpoint { .. } => continue,
+            LogRecord::Drop { relation } => {
+                dropped.insert(*relation, lsn);
+                continue;
+            }
+            LogRecord::Redo { page_id, .. } => {
+                dirty.entry(*page_id).or_insert(lsn);
+                continue;
+            }
+            LogRecord::Update { page_id, .. }
+            | LogRecord::Clr { page_id, .. }
+            | LogRecord::HeapUpdate { page_id, .. } => {
+                dirty.entry(*page_id).or_insert(lsn);
+            }
+            _ => {}
+        }
+
+        let entry = txns
+            .entry(record.txn_id())
+            .or_insert(TxnEntry { status: TxnStatus::Running, last_lsn: lsn });
+        // The checkpoint can hold a later record than the ones logged just after its redo LSN
+        entry.last_lsn = entry.last_lsn.max(lsn);
+        if let LogRecord::Commit { .. } = record {
+            entry.status = TxnStatus::Committed;
+        }
+    }
+
+    // Changes logged before a relation was dropped are never replayed. The drop may not have
+    // finished before the crash, so it is repeated.
+    let was_dropped = |page_id: PageId, lsn: Lsn| {
+        dropped.get(&page_relation(page_id)).is_some_and(|dropped| lsn < *dropped)
+    };
+    for relation in dropped.keys() {
+        disk.drop_relation(*relation)?;
+    }
+
+    let mut pages = Pages::new(disk, wal, &dirty, redo_lsn);
+
+    // Redo: repeat history, including the updates of transactions that will be undone
+    if let Some(start) = dirty.values().min() {
+        for result in wal.iter().skip_while(|r| matches!(r, Ok((lsn, _)) if lsn < start)) {
+            let (lsn, record) = result?;
+
+            let (page_id, offset, after) = match &record {
+                LogRecord::Update { page_id, offset, after, .. }
+                | LogRecord::Clr { page_id, offset, after, .. }
+                | LogRecord::HeapUpdate { page_id, offset, after, .. }
+                | LogRecord::Redo { page_id, offset, after } => (*page_id, *offset, after),
+                _ => continue,
+            };
+
+            if lsn < dirty[&page_id] || was_dropped(page_id, lsn) || pages.get(page_id)?.0 >= lsn {
+                continue;
+            }
+
+            pages.apply(lsn, page_id, offset, after)?;
+        }
+    }
+
+    // Undo: roll back losers, always undoing the most recent record first
+    let mut undo: HashMap<TxnId, Lsn> = txns
+        .iter()
+        .filter(|(_, entry)| entry.status == TxnStatus::Running)
+        .map(|(txn_id, entry)| (*txn_id, entry.last_lsn))
+        .collect();
+    let mut last: HashMap<TxnId, Lsn> = undo.clone();
+
+    while let Some((&txn_id, &lsn)) = undo.iter().max_by_key(|(_, lsn)| **lsn) {
+        let record = wal.read(lsn)?.ok_or(PageCacheError::Disk(io::ErrorKind::UnexpectedEof))?;
+
+        let next = match record {
+            LogRecord::Update { prev_lsn, page_id, offset, before, .. } => {
+                if !was_dropped(page_id, lsn) {
+                    pages.image(page_id)?;
+                }
+                let clr = wal.append(&LogRecord::Clr {
+                    txn_id,
+                    prev_lsn: last[&txn_id],
+                    page_id,
+                    offset,
+                    after: before.clone(),
+                    undo_next: prev_lsn,
+                });
+                last.insert(txn_id, clr);
+                if !was_dropped(page_id, lsn) {
+                    pages.apply(clr, page_id, offset, &before)?;
+                }
+
+                prev_lsn
+            }
+            LogRecord::HeapUpdate { prev_lsn, page_id, slot_id, before, .. }
+                if !was_dropped(page_id, lsn) =>
+            {
+                pages.image(page_id)?;
+                let (_, data) = pages.get(page_id)?;
+                let undone = node::undo_slot(data, slot_id, &before);
+                if let Some((start, end)) = wal::diff(data, &undone) {
+                    let clr = wal.append(&LogRecord::Clr {
+                        txn_id,
+                        prev_lsn: last[&txn_id],
+                        page_id,
+                        offset: start as u16,
+                        after: undone[start..end].to_vec(),
+                        undo_next: prev_lsn,
+                    });
+                    last.insert(txn_id, clr);
+                    pages.apply(clr, page_id, start as u16, &undone[start..end])?;
+                }
+
+                prev_lsn
+            }
+            LogRecord::Clr { undo_next, .. } => undo_next,
+            record => record.prev_lsn(),
+        };
+
+        if next == 0 {
+            wal.append(&LogRecord::End { txn_id, prev_lsn: last[&txn_id] });
+            undo.remove(&txn_id);
+        } else {
+            undo.insert(txn_id, next);
+        }
+    }
+
+    // Committed transactions don't need an end record, but it saves analysing them next time
+    for (txn_id, entry) in txns.iter().filter(|(_, entry)| entry.status == TxnStatus::Committed) {
+        wal.append(&LogRecord::End { txn_id: *txn_id, prev_lsn: entry.last_lsn });
+    }
+
+    wal.flush(wal.next_lsn())?;
+    pages.write_all()?;
+
+    Ok(next_page_id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use rand::Rng;
+
+    use crate::{
+        disk::{Disk, Faulty, Memory},
+        page::{PageBuf, PageId, PAGE_HEADER_SIZE, PAGE_SIZE},
+        page_cache::{PageCache, PageCacheError},
+        recovery::RECOVERY_PAGES,
+        replacer::LRU,
+        table::{list::List, node::Node, tuple::TupleMeta},
+        transaction::{Transaction, TransactionManager},
+        wal::{LogMemory, LogRecord, LogStore, Wal},
+    };
+
+    const PAGES: usize = 8;
+
+    fn fill(page_id: PageId, b: u8) -> PageBuf {
+        let mut ret = [0; PAGE_SIZE];
+        ret[64 + page_id as usize * 8..][..256].fill(b);
+
+        ret
+    }
+
+    /// Only the header and the start of the body reach the disk, so the page fails its checksum
+    const TORN_LEN: usize = PAGE_HEADER_SIZE + 16;
+
+    #[test]
+    fn test_recovery() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+
+        for (writes, torn) in (0..=PAGES).flat_map(|w| [(w, false), (w, true)]) {
+            let disk = Arc::new(Memory::default());
+            let log = Arc::new(LogMemory::default());
+
+            {
+                let wal = Wal::new(log.clone()).unwrap();
+                let faulty = Faulty::new(disk.clone());
+                if torn {
+                    faulty.tear_write(writes, TORN_LEN);
+                    faulty.fail_write(writes + 1);
+                } else {
+                    faulty.fail_write(writes);
+                }
+                let pc = PageCache::new_with_wal(faulty, LRU::new(K), 0, wal.clone());
+
+                // Transaction 1 commits
+                let mut prev = wal.append(&LogRecord::Begin { txn_id: 1 });
+                for _ in 0..PAGES / 2 {
+                    let page = pc.new_page()?;
+                    let mut w = page.write();
+                    prev = wal.write(1, prev, &mut w, &fill(page.id, 1));
+                }
+                let commit = wal.append(&LogRecord::Commit { txn_id: 1, prev_lsn: prev });
+                wal.flush(commit).unwrap();
+
+                // Transaction 2 overwrites some of transaction 1's pages and never commits
+                let mut prev = wal.append(&LogRecord::Begin { txn_id: 2 });
+                for page_id in (0..PAGES as PageId).step_by(2) {
+                    let page = pc.fetch_page(page_id)?;
+                    let mut w = page.write();
+                    prev = wal.write(2, prev, &mut w, &fill(page.id, 2));
+                }
+
+                // Crash part way through writing the pages, just after a torn write if `torn`
+                let _ = pc.flush_all_pages();
+            }
+
+            let wal = Wal::new(log.clone()).unwrap();
+            let next_page_id = crate::recovery::recover(&disk, &wal).unwrap();
+            assert!(next_page_id <= PAGES as PageId);
+
+            for page_id in 0..PAGES as PageId / 2 {
+                let have = disk.read_page(page_id).unwrap();
+                assert!(
+                    have[PAGE_HEADER_SIZE..] == fill(page_id, 1)[PAGE_HEADER_SIZE..],
+                    "page {page_id} not recovered with {writes} writes, torn: {torn}"
+                );
+            }
+
+            // Recovering twice changes nothing
+            let wal = Wal::new(log).unwrap();
+            crate::recovery::recover(&disk, &wal).unwrap();
+            for page_id in 0..PAGES as PageId / 2 {
+                assert!(
+                    disk.read_page(page_id).unwrap()[PAGE_HEADER_SIZE..]
+                        == fill(page_id, 1)[PAGE_HEADER_SIZE..]
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_many_pages() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+        const PAGES: usize = RECOVERY_PAGES * 3;
+
+        let disk = Arc::new(Memory::default());
+        let log = Arc::new(LogMemory::default());
+        {
+            let wal = Wal::new(log.clone()).unwrap();
+            let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), 0, wal.clone());
+            let tm = TransactionManager::new(pc.clone())?;
+            let write = |txn: &Transaction, page_id: PageId, b: u8| -> crate::Result<()> {
+                let page = pc.fetch_page(page_id)?;
+                txn.write(&mut page.write(), &fill(page_id, b));
+
+                Ok(())
+            };
+
+            // Transaction 2 touches more pages than recovery holds at once and never commits
+            let t1 = tm.begin();
+            for _ in 0..PAGES {
+                write(&t1, pc.new_page()?.id, 1)?;
+            }
+            tm.commit(&t1)?;
+
+            let t2 = tm.begin();
+            for page_id in 0..PAGES as PageId {
+                write(&t2, page_id, 2)?;
+            }
+            wal.flush(wal.next_lsn()).unwrap();
+        }
+
+        let wal = Wal::new(log).unwrap();
+        crate::recovery::recover(&disk, &wal).unwrap();
+        for page_id in 0..PAGES as PageId {
+            let have = disk.read_page(page_id).unwrap();
+            assert!(have[PAGE_HEADER_SIZE..] == fill(page_id, 1)[PAGE_HEADER_SIZE..]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_random() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+
+        let disk = Arc::new(Memory::default());
+        let log = Arc::new(LogMemory::default());
+        let mut want: Vec<PageBuf> = (0..PAGES as PageId).map(|_| [0; PAGE_SIZE]).collect();
+
+        for txn_id in 1..20 {
+            let commit = rand::thread_rng().gen_bool(0.5);
+            let writes = rand::thread_rng().gen_range(0..PAGES);
+
+            let wal = Wal::new(log.clone()).unwrap();
+            crate::recovery::recover(&disk, &wal).unwrap();
+
+            let faulty = Faulty::new(disk.clone());
+            let retry = match rand::thread_rng().gen_range(0..3) {
+                // Crash part way through writing the pages
+                0 => {
+                    faulty.fail_write(writes);
+                    false
+                }
+                // Crash just after a torn write
+                1 => {
+                    faulty.tear_write(writes, TORN_LEN);
+                    faulty.fail_write(writes + 1);
+                    false
+                }
+                // A write fails and is retried, then crash
+                _ => {
+                    faulty.fail_write(writes);
+                    true
+                }
+            };
+            let pc = PageCache::new_with_wal(faulty, LRU::new(K), PAGES as PageId, wal.clone());
+
+            let mut prev = wal.append(&LogRecord::Begin { txn_id });
+            for page_id in 0..PAGES as PageId {
+                if rand::thread_rng().gen_bool(0.5) {
+                    continue;
+                }
+
+                let page = pc.fetch_page(page_id)?;
+                let mut w = page.write();
+                let mut data = w.data;
+                data[64 + txn_id as usize * 16..][..16].fill(txn_id as u8);
+                prev = wal.write(txn_id, prev, &mut w, &data);
+
+                if commit {
+                    want[page_id as usize][64 + txn_id as usize * 16..][..16].fill(txn_id as u8);
+                }
+            }
+
+            if commit {
+                let lsn = wal.append(&LogRecord::Commit { txn_id, prev_lsn: prev });
+                wal.flush(lsn).unwrap();
+            }
+
+            if retry {
+                let _ = pc.flush_all_pages();
+            }
+            let _ = pc.flush_all_pages();
+        }
+
+        let wal = Wal::new(log).unwrap();
+        crate::recovery::recover(&disk, &wal).unwrap();
+        for (page_id, want) in want.iter().enumerate() {
+            let have = disk.read_page(page_id as PageId).unwrap();
+            assert!(have[PAGE_HEADER_SIZE..] == want[PAGE_HEADER_SIZE..], "page {page_id} differs");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_checkpoint() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+
+        let disk = Arc::new(Faulty::new(Memory::default()));
+        let log = Arc::new(LogMemory::default());
+
+        {
+            let wal = Wal::new(log.clone()).unwrap();
+            let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), 0, wal);
+            let tm = TransactionManager::new(pc.clone())?;
+
+            let write = |txn: &Transaction, page_id: PageId, b: u8| -> Result<(), PageCacheError> {
+                let page = pc.fetch_page(page_id)?;
+                txn.write(&mut page.write(), &fill(page_id, b));
+
+                Ok(())
+            };
+
+            // Transaction 2 is running across the checkpoint and never commits
+            let t1 = tm.begin();
+            for page_id in 0..PAGES as PageId / 2 {
+                write(&t1, page_id, 1)?;
+            }
+            tm.commit(&t1)?;
+
+            let t2 = tm.begin();
+            write(&t2, 0, 2)?;
+            let checkpoint = tm.checkpoint()?;
+            assert!(matches!(
+                pc.wal().unwrap().read(checkpoint).unwrap(),
+                Some(LogRecord::Checkpoint { txns, .. }) if txns.len() == 1 && txns[0].0 == t2.id()
+            ));
+            write(&t2, 1, 2)?;
+
+            let t3 = tm.begin();
+            for page_id in PAGES as PageId / 2..PAGES as PageId {
+                write(&t3, page_id, 3)?;
+            }
+            tm.commit(&t3)?;
+
+            // Pages written since the checkpoint was synced are lost
+            pc.flush_all_pages()?;
+        }
+        disk.crash().unwrap();
+        assert!(disk.read_page(0).unwrap()[PAGE_HEADER_SIZE..] == fill(0, 2)[PAGE_HEADER_SIZE..]);
+
+        let wal = Wal::new(log).unwrap();
+        crate::recovery::recover(&disk, &wal).unwrap();
+        for page_id in 0..PAGES as PageId {
+            let b = if page_id < PAGES as PageId / 2 { 1 } else { 3 };
+            let have = disk.read_page(page_id).unwrap();
+            assert!(have[PAGE_HEADER_SIZE..] == fill(page_id, b)[PAGE_HEADER_SIZE..]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_torn() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+
+        let disk = Arc::new(Faulty::new(Memory::default()));
+        let log = Arc::new(LogMemory::default());
+        let mut changed = fill(0, 1);
+        changed[PAGE_SIZE - 16..].fill(2);
+
+        {
+            let wal = Wal::new(log.clone()).unwrap();
+            let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), 0, wal);
+            let tm = TransactionManager::new(pc.clone())?;
+
+            let write = |txn: &Transaction, page_id: PageId, b: u8| -> Result<(), PageCacheError> {
+                let page = pc.fetch_page(page_id)?;
+                txn.write(&mut page.write(), &fill(page_id, b));
+
+                Ok(())
+            };
+
+            let t1 = tm.begin();
+            for _ in 0..PAGES / 2 {
+                write(&t1, pc.new_page()?.id, 1)?;
+            }
+            tm.commit(&t1)?;
+            tm.checkpoint()?;
+
+            // Page 0 was written before the checkpoint, the new page never was
+            let t2 = tm.begin();
+            let page = pc.fetch_page(0)?;
+            t2.write(&mut page.write(), &changed);
+            drop(page);
+            let new = pc.new_page()?.id;
+            write(&t2, new, 2)?;
+            tm.commit(&t2)?;
+
+            let t3 = tm.begin();
+            write(&t3, 1, 3)?;
+
+            // Only the header of each write reaches the disk
+            for page_id in [0, new, 1] {
+                disk.tear_write(0, PAGE_HEADER_SIZE);
+                pc.flush_page(page_id)?;
+            }
+        }
+        assert!(matches!(
+            PageCache::new(disk.clone(), LRU::new(K), 0).fetch_page(0),
+            Err(PageCacheError::Corrupt { page_id: 0 })
+        ));
+
+        let wal = Wal::new(log).unwrap();
+        crate::recovery::recover(&disk, &wal).unwrap();
+        for page_id in 0..=PAGES as PageId / 2 {
+            let want = match page_id {
+                0 => changed,
+                page_id if page_id == PAGES as PageId / 2 => fill(page_id, 2),
+                page_id => fill(page_id, 1),
+            };
+            let have = disk.read_page(page_id).unwrap();
+            assert!(
+                have[PAGE_HEADER_SIZE..] == want[PAGE_HEADER_SIZE..],
+                "page {page_id} not repaired"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_truncate() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+
+        let disk = Arc::new(Faulty::new(Memory::default()));
+        let log = Arc::new(LogMemory::default());
+
+        let t3_id = {
+            let wal = Wal::new(log.clone()).unwrap();
+            let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), 0, wal);
+            let tm = TransactionManager::new(pc.clone())?;
+
+            let write = |txn: &Transaction, page_id: PageId, b: u8| -> Result<(), PageCacheError> {
+                let page = pc.fetch_page(page_id)?;
+                txn.write(&mut page.write(), &fill(page_id, b));
+
+                Ok(())
+            };
+
+            for b in 1..=4 {
+                let txn = tm.begin();
+                for page_id in 0..PAGES as PageId {
+                    if b == 1 {
+                        pc.new_page()?;
+                    }
+                    write(&txn, page_id, b)?;
+                }
+                tm.commit(&txn)?;
+            }
+
+            // Transaction 2 is running across the checkpoint, its records are kept
+            let t1 = tm.begin();
+            tm.commit(&t1)?;
+            let t2 = tm.begin();
+            write(&t2, 0, 5)?;
+            let size = log.size().unwrap();
+            tm.checkpoint()?;
+            assert!(log.size().unwrap() < size / 2);
+
+            write(&t2, 1, 5)?;
+            let t3 = tm.begin();
+            write(&t3, 2, 6)?;
+            tm.commit(&t3)?;
+            pc.flush_all_pages()?;
+
+            t3.id()
+        };
+        disk.crash().unwrap();
+
+        let wal = Wal::new(log.clone()).unwrap();
+        let next_page_id = crate::recovery::recover(&disk, &wal).unwrap();
+        assert_eq!(next_page_id, PAGES as PageId);
+        for page_id in 0..PAGES as PageId {
+            let b = if page_id == 2 { 6 } else { 4 };
+            let have = disk.read_page(page_id).unwrap();
+            assert!(have[PAGE_HEADER_SIZE..] == fill(page_id, b)[PAGE_HEADER_SIZE..]);
+        }
+
+        // Once the log holds nothing but the checkpoint, ids still aren't reused
+        let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), next_page_id, wal.clone());
+        let tm = TransactionManager::new(pc)?;
+        let t4 = tm.begin();
+        assert!(t4.id() > t3_id);
+        tm.commit(&t4)?;
+        tm.checkpoint()?;
+        assert!(wal.iter().all(|r| matches!(r, Ok((_, LogRecord::Checkpoint { .. })))));
+
+        let wal = Wal::new(log).unwrap();
+        assert_eq!(crate::recovery::recover(&disk, &wal).unwrap(), PAGES as PageId);
+        let pc = PageCache::new_with_wal(disk, LRU::new(K), next_page_id, wal);
+        assert!(TransactionManager::new(pc)?.begin().id() > t4.id());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_heap() -> Result<(), PageCacheError> {
+        const K: usize = 2;
+
+        let disk = Arc::new(Memory::default());
+        let log = Arc::new(LogMemory::default());
+        let row = |i: u8| BytesMut::from(&[i; 16][..]);
+
+        let (loser, winner) = {
+            let wal = Wal::new(log.clone()).unwrap();
+            let pc = PageCache::new_with_wal(disk.clone(), LRU::new(K), 0, wal);
+            let tm = TransactionManager::new(pc.clone())?;
+            let list = List::default(pc.clone())?;
+
+            // Transaction 1 never commits, transaction 2 commits a row on the same page
+            let t1 = tm.begin();
+            let t2 = tm.begin();
+            let loser = list.insert(&row(1), &TupleMeta::default(), &t1)?.unwrap();
+            let winner = list.insert(&row(2), &TupleMeta::default(), &t2)?.unwrap();
+            tm.commit(&t2)?;
+            pc.flush_all_pages()?;
+
+            (loser, winner)
+        };
+
+        let wal = Wal::new(log).unwrap();
+        crate::recovery::recover(&disk, &wal).unwrap();
+
+        let data = disk.read_page(loser.page_id).unwrap();
+        let node = Node::from(&data);
+        assert!(node.get(&loser).unwrap().0.deleted);
+        assert_eq!(node.get(&winner).unwrap().1.data, row(2));
+
+        Ok(())
+    }
+}
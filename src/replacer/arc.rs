@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    page::PageId,
+    page_cache::FrameId,
+    replacer::{first_unpinned, AccessType, Frame, Queue, Replacer},
+};
+
+/// Adaptive Replacement Cache (Megiddo & Modha). T1 holds pages accessed once recently and T2
+/// pages accessed at least twice. B1 and B2 remember pages evicted from each, a hit in one of them
+/// moves the target size of T1 towards the list that would have kept the page.
+pub struct ARCReplacer {
+    frames: HashMap<FrameId, Frame>,
+    t1: Queue<FrameId>,
+    t2: Queue<FrameId>,
+    b1: Queue<PageId>,
+    b2: Queue<PageId>,
+    /// Frames only accessed by scans, evicted before T1 and T2 and never remembered
+    scans: Queue<FrameId>,
+    /// Target size of T1
+    p: usize,
+    c: usize,
+}
+
+impl ARCReplacer {
+    pub fn new(frames: usize) -> Self {
+        Self {
+            frames: HashMap::new(),
+            t1: Queue::default(),
+            t2: Queue::default(),
+            b1: Queue::default(),
+            b2: Queue::default(),
+            scans: Queue::default(),
+            p: 0,
+            c: frames.max(1),
+        }
+    }
+
+    pub fn evict(&mut self) -> Option<FrameId> {
+        if let Some(i) = first_unpinned(&self.scans, &self.frames) {
+            self.remove(i);
+            return Some(i);
+        }
+
+        let t1 = first_unpinned(&self.t1, &self.frames);
+        let t2 = first_unpinned(&self.t2, &self.frames);
+
+        let (i, from_t1) = match (t1, t2) {
+            (Some(i), _) if self.t1.len() > self.p => (i, true),
+            (_, Some(i)) => (i, false),
+            (Some(i), None) => (i, true),
+            (None, None) => return None,
+        };
+
+        let page_id = self.frames.remove(&i).expect("evicted frame should be tracked").page_id;
+        match from_t1 {
+            true => {
+                self.t1.remove(&i);
+                self.b1.push(page_id);
+            }
+            false => {
+                self.t2.remove(&i);
+                self.b2.push(page_id);
+            }
+        }
+
+        // The cache and its history together never remember more than twice its size
+        while self.b1.len() > self.c.saturating_sub(self.t1.len()) {
+            self.b1.pop_front();
+        }
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > self.c * 2 {
+            self.b2.pop_front();
+        }
+
+        Some(i)
+    }
+
+    pub fn record_access(&mut self, i: FrameId, page_id: PageId, access_type: AccessType) {
+        if self.frames.get(&i).is_some_and(|f| f.page_id == page_id) {
+            match access_type {
+                AccessType::Scan => {}
+                AccessType::Get if self.scans.remove(&i) => self.admit(i, page_id),
+                AccessType::Get => {
+                    self.t1.remove(&i);
+                    self.t2.push(i);
+                }
+            }
+
+            return;
+        }
+
+        self.remove(i);
+        self.frames.insert(i, Frame { page_id, pin: 0 });
+        match access_type {
+            AccessType::Get => self.admit(i, page_id),
+            AccessType::Scan => self.scans.push(i),
+        }
+    }
+
+    fn admit(&mut self, i: FrameId, page_id: PageId) {
+        if self.b1.contains(&page_id) {
+            let delta = (self.b2.len() / self.b1.len()).max(1);
+            self.p = (self.p + delta).min(self.c);
+            self.b1.remove(&page_id);
+            self.t2.push(i);
+        } else if self.b2.contains(&page_id) {
+            let delta = (self.b1.len() / self.b2.len()).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.b2.remove(&page_id);
+            self.t2.push(i);
+        } else {
+            self.t1.push(i);
+        }
+    }
+
+    pub fn pin(&mut self, i: FrameId) {
+        if let Some(frame) = self.frames.get_mut(&i) {
+            frame.pin += 1;
+        }
+    }
+
+    pub fn unpin(&mut self, i: FrameId) {
+        if let Some(frame) = self.frames.get_mut(&i) {
+            frame.pin -= 1;
+        }
+    }
+
+    pub fn remove(&mut self, i: FrameId) {
+        if self.frames.remove(&i).is_some() {
+            self.t1.remove(&i);
+            self.t2.remove(&i);
+            self.scans.remove(&i);
+        }
+    }
+}
+
+pub struct ARC {
+    inner: Mutex<ARCReplacer>,
+}
+
+impl ARC {
+    pub fn new(frames: usize) -> Arc<Self> {
+        Arc::new(Self { inner: Mutex::new(ARCReplacer::new(frames)) })
+    }
+}
+
+impl Replacer for ARC {
+    fn record_access(&self, i: FrameId, page_id: PageId, access_type: AccessType) {
+        self.inner.lock().expect("todo").record_access(i, page_id, access_type)
+    }
+
+    fn pin(&self, i: FrameId) {
+        self.inner.lock().expect("todo").pin(i)
+    }
+
+    fn unpin(&self, i: FrameId) {
+        self.inner.lock().expect("todo").unpin(i)
+    }
+
+    fn evict(&self) -> Option<FrameId> {
+        self.inner.lock().expect("todo").evict()
+    }
+
+    fn remove(&self, i: FrameId) {
+        self.inner.lock().expect("todo").remove(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        page::PageId,
+        replacer::{AccessType, Replacer, ARC},
+    };
+
+    #[test]
+    fn test_arc() {
+        let replacer = ARC::new(4);
+        for i in 0..4 {
+            replacer.record_access(i, i as PageId, AccessType::Get);
+        }
+        replacer.record_access(3, 3, AccessType::Get);
+
+        // T1 is over its target of 0, so pages seen once go first
+        assert_eq!(replacer.evict(), Some(0));
+
+        // Page 0 comes back, the cache learns that T1 was too small
+        replacer.record_access(0, 0, AccessType::Get);
+        assert_eq!(replacer.inner.lock().unwrap().p, 1);
+
+        // T1 = [1, 2] shrinks to its target, then T2 = [3, 0] is evicted from
+        assert_eq!(replacer.evict(), Some(1));
+        assert_eq!(replacer.evict(), Some(3));
+        assert_eq!(replacer.evict(), Some(0));
+        assert_eq!(replacer.evict(), Some(2));
+        assert_eq!(replacer.evict(), None);
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    page::PageId,
+    page_cache::FrameId,
+    replacer::{AccessType, Queue, Replacer},
+};
+
+#[derive(Debug)]
+struct ClockFrame {
+    referenced: bool,
+    pin: u64,
+}
+
+/// Frames sit on a circle, the hand clears the reference bit of each frame it passes and evicts
+/// the first unpinned frame that hasn't been accessed since the last pass
+#[derive(Default, Debug)]
+pub struct ClockReplacer {
+    frames: Vec<Option<ClockFrame>>,
+    hand: usize,
+    /// Frames only accessed by scans, evicted in FIFO order before the hand moves
+    scans: Queue<FrameId>,
+}
+
+impl ClockReplacer {
+    pub fn evict(&mut self) -> Option<FrameId> {
+        let frames = &self.frames;
+        let scanned = self.scans.iter().find(|i| frames[**i].as_ref().is_some_and(|f| f.pin == 0));
+        if let Some(&i) = scanned {
+            self.remove(i);
+            return Some(i);
+        }
+
+        // Two passes are enough to clear every reference bit
+        for _ in 0..self.frames.len() * 2 {
+            let i = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+
+            match &mut self.frames[i] {
+                Some(frame) if frame.pin == 0 && frame.referenced => frame.referenced = false,
+                Some(frame) if frame.pin == 0 => {
+                    self.remove(i);
+                    return Some(i);
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    pub fn record_access(&mut self, i: FrameId, access_type: AccessType) {
+        if i >= self.frames.len() {
+            self.frames.resize_with(i + 1, || None);
+        }
+
+        match (&mut self.frames[i], access_type) {
+            (Some(_), AccessType::Scan) => {}
+            (Some(frame), AccessType::Get) => {
+                frame.referenced = true;
+                self.scans.remove(&i);
+            }
+            (None, AccessType::Scan) => {
+                self.frames[i] = Some(ClockFrame { referenced: false, pin: 0 });
+                self.scans.push(i);
+            }
+            (None, AccessType::Get) => {
+                self.frames[i] = Some(ClockFrame { referenced: true, pin: 0 })
+            }
+        }
+    }
+
+    pub fn pin(&mut self, i: FrameId) {
+        if let Some(Some(frame)) = self.frames.get_mut(i) {
+            frame.pin += 1;
+        }
+    }
+
+    pub fn unpin(&mut self, i: FrameId) {
+        if let Some(Some(frame)) = self.frames.get_mut(i) {
+            frame.pin -= 1;
+        }
+    }
+
+    pub fn remove(&mut self, i: FrameId) {
+        if let Some(frame) = self.frames.get_mut(i) {
+            *frame = None;
+            self.scans.remove(&i);
+        }
+    }
+}
+
+pub struct Clock {
+    inner: Mutex<ClockReplacer>,
+}
+
+impl Clock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { inner: Mutex::new(ClockReplacer::default()) })
+    }
+}
+
+impl Replacer for Clock {
+    fn record_access(&self, i: FrameId, _page_id: PageId, access_type: AccessType) {
+        self.inner.lock().expect("todo").record_access(i, access_type)
+    }
+
+    fn pin(&self, i: FrameId) {
+        self.inner.lock().expect("todo").pin(i)
+    }
+
+    fn unpin(&self, i: FrameId) {
+        self.inner.lock().expect("todo").unpin(i)
+    }
+
+    fn evict(&self) -> Option<FrameId> {
+        self.inner.lock().expect("todo").evict()
+    }
+
+    fn remove(&self, i: FrameId) {
+        self.inner.lock().expect("todo").remove(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        page::PageId,
+        replacer::{AccessType, Clock, Replacer},
+    };
+
+    #[test]
+    fn test_clock() {
+        let replacer = Clock::new();
+        for i in 0..4 {
+            replacer.record_access(i, i as PageId, AccessType::Get);
+        }
+
+        // Every frame has been referenced, so the first pass only clears the bits
+        assert_eq!(replacer.evict(), Some(0));
+
+        // 1 is accessed again before the hand reaches it, so it survives the next pass
+        replacer.record_access(1, 1, AccessType::Get);
+        replacer.record_access(0, 4, AccessType::Get);
+        assert_eq!(replacer.evict(), Some(2));
+        assert_eq!(replacer.evict(), Some(3));
+        assert_eq!(replacer.evict(), Some(1));
+        assert_eq!(replacer.evict(), Some(0));
+        assert_eq!(replacer.evict(), None);
+    }
+}
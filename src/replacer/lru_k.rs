@@ -0,0 +1,251 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    page::PageId,
+    page_cache::FrameId,
+    replacer::{AccessType, Replacer},
+};
+
+#[derive(Debug)]
+struct LRUKNode {
+    /// The last k accesses, oldest first
+    history: VecDeque<u64>,
+    pin: u64,
+    /// Only accessed by scans so far, these are evicted before anything else
+    scan: bool,
+}
+
+impl LRUKNode {
+    pub fn new(ts: u64, scan: bool) -> Self {
+        Self { history: VecDeque::from([ts]), pin: 0, scan }
+    }
+
+    /// The queue the node is evicted from and its position in it. Nodes with k accesses are
+    /// ordered by their kth most recent access, so the first has the largest backward k-distance.
+    /// Nodes with fewer have an infinite k-distance and are evicted first, in FIFO order.
+    fn key(&self, k: usize) -> (Class, u64) {
+        let queue = match self.scan {
+            true => Class::Scan,
+            false if self.history.len() < k => Class::Young,
+            false => Class::Old,
+        };
+
+        (queue, self.history[0])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Class {
+    Scan,
+    Young,
+    Old,
+}
+
+/// Only unpinned nodes are queued, so evicting is taking the first node of the first non-empty
+/// queue
+#[derive(Default, Debug)]
+pub struct LRUKReplacer {
+    nodes: HashMap<FrameId, LRUKNode>,
+    scan: BTreeSet<(u64, FrameId)>,
+    young: BTreeSet<(u64, FrameId)>,
+    old: BTreeSet<(u64, FrameId)>,
+    current_ts: u64,
+    k: usize,
+}
+
+impl LRUKReplacer {
+    pub fn new(k: usize) -> Self {
+        assert!(k > 0);
+
+        Self { k, ..Default::default() }
+    }
+
+    fn queue(&mut self, queue: Class) -> &mut BTreeSet<(u64, FrameId)> {
+        match queue {
+            Class::Scan => &mut self.scan,
+            Class::Young => &mut self.young,
+            Class::Old => &mut self.old,
+        }
+    }
+
+    fn enqueue(&mut self, i: FrameId) {
+        let (queue, ts) = self.nodes[&i].key(self.k);
+        self.queue(queue).insert((ts, i));
+    }
+
+    fn dequeue(&mut self, i: FrameId) {
+        let (queue, ts) = self.nodes[&i].key(self.k);
+        self.queue(queue).remove(&(ts, i));
+    }
+
+    pub fn evict(&mut self) -> Option<FrameId> {
+        let (_, i) = self
+            .scan
+            .pop_first()
+            .or_else(|| self.young.pop_first())
+            .or_else(|| self.old.pop_first())?;
+        self.nodes.remove(&i);
+
+        Some(i)
+    }
+
+    pub fn record_access(&mut self, i: FrameId, access_type: AccessType) {
+        let ts = self.current_ts;
+        self.current_ts += 1;
+
+        let Some(node) = self.nodes.get(&i) else {
+            self.nodes.insert(i, LRUKNode::new(ts, access_type == AccessType::Scan));
+            self.enqueue(i);
+            return;
+        };
+
+        // A scan passing over a cached page says nothing about how hot it is
+        if access_type == AccessType::Scan {
+            return;
+        }
+
+        let unpinned = node.pin == 0;
+        if unpinned {
+            self.dequeue(i);
+        }
+
+        let node = self.nodes.get_mut(&i).expect("node should exist");
+        node.scan = false;
+        node.history.push_back(ts);
+        if node.history.len() > self.k {
+            node.history.pop_front();
+        }
+
+        if unpinned {
+            self.enqueue(i);
+        }
+    }
+
+    pub fn pin(&mut self, i: FrameId) {
+        let Some(node) = self.nodes.get_mut(&i) else {
+            return;
+        };
+        node.pin += 1;
+
+        if node.pin == 1 {
+            self.dequeue(i);
+        }
+    }
+
+    pub fn unpin(&mut self, i: FrameId) {
+        let Some(node) = self.nodes.get_mut(&i) else {
+            return;
+        };
+        node.pin -= 1;
+
+        if node.pin == 0 {
+            self.enqueue(i);
+        }
+    }
+
+    pub fn remove(&mut self, i: FrameId) {
+        let Some(node) = self.nodes.get(&i) else {
+            return;
+        };
+
+        match node.pin {
+            0 => self.dequeue(i),
+            pins => eprintln!("WARN: frame {} is still pinned, {} pins", i, pins),
+        }
+        self.nodes.remove(&i);
+    }
+}
+
+pub struct LRU {
+    inner: Mutex<LRUKReplacer>,
+}
+
+impl LRU {
+    pub fn new(k: usize) -> Arc<Self> {
+        Arc::new(Self { inner: Mutex::new(LRUKReplacer::new(k)) })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, LRUKReplacer> {
+        self.inner.lock().expect("todo")
+    }
+}
+
+impl Replacer for LRU {
+    fn record_access(&self, i: FrameId, _page_id: PageId, a: AccessType) {
+        let mut replacer = self.inner.lock().expect("todo");
+        replacer.record_access(i, a)
+    }
+
+    fn pin(&self, i: FrameId) {
+        let mut replacer = self.inner.lock().expect("todo");
+        replacer.pin(i)
+    }
+
+    fn unpin(&self, i: FrameId) {
+        let mut replacer = self.inner.lock().expect("todo");
+        replacer.unpin(i)
+    }
+
+    fn evict(&self) -> Option<FrameId> {
+        let mut replacer = self.inner.lock().expect("todo");
+        replacer.evict()
+    }
+
+    fn remove(&self, i: FrameId) {
+        let mut replacer = self.inner.lock().expect("todo");
+        replacer.remove(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        page::PageId,
+        replacer::{AccessType, LRUKReplacer, Replacer, LRU},
+    };
+
+    #[test]
+    fn test_evict() {
+        const K: usize = 2;
+        let replacer = LRU::new(K);
+
+        {
+            for i in 0..8 {
+                replacer.remove(i);
+                replacer.record_access(i, i as PageId, AccessType::Get);
+                replacer.pin(i);
+            }
+
+            for i in (0..8).rev() {
+                replacer.unpin(i);
+
+                let have = replacer.evict();
+                let want = Some(i);
+                assert!(want == have, "Want: {want:?}, Have: {have:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_k_distance() {
+        const K: usize = 2;
+        let mut replacer = LRUKReplacer::new(K);
+
+        // Accesses: 0, 1, 2, 1, 0, 1, 3
+        for i in [0, 1, 2, 1, 0, 1, 3] {
+            replacer.record_access(i, AccessType::Get);
+        }
+        assert!(replacer.nodes.values().all(|node| node.history.len() <= K));
+
+        // 2 and 3 have fewer than k accesses and go first, then 0 whose second most recent access
+        // is older than 1's
+        assert_eq!(replacer.evict(), Some(2));
+        assert_eq!(replacer.evict(), Some(3));
+        assert_eq!(replacer.evict(), Some(0));
+        assert_eq!(replacer.evict(), Some(1));
+        assert_eq!(replacer.evict(), None);
+    }
+}
@@ -0,0 +1,182 @@
+pub mod arc;
+pub mod clock;
+pub mod lru_k;
+pub mod partitioned;
+pub mod two_q;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+use crate::{page::PageId, page_cache::FrameId};
+
+pub use arc::ARC;
+pub use clock::Clock;
+pub use lru_k::{LRUKReplacer, LRU};
+pub use partitioned::Partitioned;
+pub use two_q::TwoQ;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AccessType {
+    Get,
+    /// Part of a sequential scan, pages only seen by scans are evicted first so a large scan
+    /// can't flush the rest of the cache
+    Scan,
+}
+
+/// Chooses which frame the page cache reuses once it is full. A frame is tracked from its first
+/// access until it is evicted or removed, pinned frames are never evicted.
+pub trait Replacer: Send + Sync {
+    /// `page_id` is the page currently held by the frame, policies can use it to remember pages
+    /// after they have been evicted
+    fn record_access(&self, i: FrameId, page_id: PageId, access_type: AccessType);
+
+    fn pin(&self, i: FrameId);
+
+    fn unpin(&self, i: FrameId);
+
+    /// Stop tracking an unpinned frame and return it
+    fn evict(&self) -> Option<FrameId>;
+
+    fn remove(&self, i: FrameId);
+}
+
+#[derive(Debug)]
+struct Frame {
+    page_id: PageId,
+    pin: u64,
+}
+
+/// Keys ordered from least to most recently pushed
+#[derive(Debug)]
+struct Queue<T> {
+    order: BTreeMap<u64, T>,
+    index: HashMap<T, u64>,
+    ts: u64,
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Self { order: BTreeMap::new(), index: HashMap::new(), ts: 0 }
+    }
+}
+
+impl<T: Copy + Eq + Hash> Queue<T> {
+    /// Push `t` to the back, moving it if it is already queued
+    fn push(&mut self, t: T) {
+        if let Some(ts) = self.index.insert(t, self.ts) {
+            self.order.remove(&ts);
+        }
+        self.order.insert(self.ts, t);
+        self.ts += 1;
+    }
+
+    fn remove(&mut self, t: &T) -> bool {
+        match self.index.remove(t) {
+            Some(ts) => self.order.remove(&ts).is_some(),
+            None => false,
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        let (_, t) = self.order.pop_first()?;
+        self.index.remove(&t);
+
+        Some(t)
+    }
+
+    fn contains(&self, t: &T) -> bool {
+        self.index.contains_key(t)
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.order.values()
+    }
+}
+
+/// The least recently pushed frame in `queue` that isn't pinned
+fn first_unpinned(queue: &Queue<FrameId>, frames: &HashMap<FrameId, Frame>) -> Option<FrameId> {
+    queue.iter().find(|i| frames.get(i).is_some_and(|f| f.pin == 0)).copied()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{
+        page::PageId,
+        replacer::{AccessType, Clock, Replacer, TwoQ, ARC, LRU},
+    };
+
+    fn replacers() -> Vec<(&'static str, Arc<dyn Replacer>)> {
+        const K: usize = 2;
+        const FRAMES: usize = 8;
+
+        vec![
+            ("lru-k", LRU::new(K)),
+            ("clock", Clock::new()),
+            ("2q", TwoQ::new(FRAMES)),
+            ("arc", ARC::new(FRAMES)),
+        ]
+    }
+
+    #[test]
+    fn test_replacer_pins() {
+        for (name, replacer) in replacers() {
+            for i in 0..8 {
+                replacer.record_access(i, i as PageId, AccessType::Get);
+                replacer.pin(i);
+            }
+            assert_eq!(replacer.evict(), None, "{name}");
+
+            replacer.unpin(3);
+            replacer.unpin(5);
+            replacer.remove(5);
+
+            // Only unpinned frames are evicted, and only once
+            assert_eq!(replacer.evict(), Some(3), "{name}");
+            assert_eq!(replacer.evict(), None, "{name}");
+
+            for i in (0..8).filter(|i| ![3, 5].contains(i)) {
+                replacer.unpin(i);
+            }
+
+            let mut evicted = std::iter::from_fn(|| replacer.evict()).collect::<Vec<_>>();
+            evicted.sort();
+            assert_eq!(evicted, vec![0, 1, 2, 4, 6, 7], "{name}");
+        }
+    }
+
+    #[test]
+    fn test_replacer_scan() {
+        for (name, replacer) in replacers() {
+            for i in 0..4 {
+                replacer.record_access(i, i as PageId, AccessType::Get);
+                replacer.record_access(i, i as PageId, AccessType::Get);
+            }
+            for i in 4..8 {
+                replacer.record_access(i, i as PageId, AccessType::Scan);
+            }
+
+            // Scanning a cached page doesn't demote it
+            replacer.record_access(0, 0, AccessType::Scan);
+
+            // Pages only seen by the scan go first, oldest first
+            for i in 4..8 {
+                assert_eq!(replacer.evict(), Some(i), "{name}");
+            }
+
+            // A scanned page that is read again is treated like any other page
+            replacer.record_access(4, 4, AccessType::Scan);
+            replacer.record_access(4, 4, AccessType::Get);
+            let mut evicted = std::iter::from_fn(|| replacer.evict()).collect::<Vec<_>>();
+            evicted.sort();
+            assert_eq!(evicted, vec![0, 1, 2, 3, 4], "{name}");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering::Relaxed},
+    Arc,
+};
+
+use crate::{
+    page::PageId,
+    page_cache::FrameId,
+    replacer::{AccessType, Replacer},
+};
+
+/// Splits the frames between several replacers by frame id, so threads working on different
+/// frames don't wait on the same lock. Each partition only sees its own frames, so eviction
+/// approximates the policy rather than following it exactly.
+pub struct Partitioned<R> {
+    partitions: Box<[Arc<R>]>,
+    /// Where the next eviction starts looking, spreads evictions across the partitions
+    next: AtomicUsize,
+}
+
+impl<R: Replacer> Partitioned<R> {
+    pub fn new(partitions: usize, new: impl Fn() -> Arc<R>) -> Arc<Self> {
+        assert!(partitions > 0);
+
+        let partitions = (0..partitions).map(|_| new()).collect();
+
+        Arc::new(Self { partitions, next: AtomicUsize::new(0) })
+    }
+
+    fn partition(&self, i: FrameId) -> &R {
+        &self.partitions[i % self.partitions.len()]
+    }
+}
+
+impl<R: Replacer> Replacer for Partitioned<R> {
+    fn record_access(&self, i: FrameId, page_id: PageId, access_type: AccessType) {
+        self.partition(i).record_access(i, page_id, access_type)
+    }
+
+    fn pin(&self, i: FrameId) {
+        self.partition(i).pin(i)
+    }
+
+    fn unpin(&self, i: FrameId) {
+        self.partition(i).unpin(i)
+    }
+
+    fn evict(&self) -> Option<FrameId> {
+        let len = self.partitions.len();
+        let start = self.next.fetch_add(1, Relaxed);
+
+        (start..start + len).find_map(|p| self.partitions[p % len].evict())
+    }
+
+    fn remove(&self, i: FrameId) {
+        self.partition(i).remove(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        page::PageId,
+        replacer::{AccessType, Partitioned, Replacer, LRU},
+    };
+
+    #[test]
+    fn test_partitioned() {
+        const K: usize = 2;
+        let replacer = Partitioned::new(4, || LRU::new(K));
+
+        for i in 0..8 {
+            replacer.record_access(i, i as PageId, AccessType::Get);
+            replacer.pin(i);
+        }
+        assert_eq!(replacer.evict(), None);
+
+        // Frames are found whichever partition the eviction starts from
+        replacer.unpin(6);
+        for _ in 0..4 {
+            assert_eq!(replacer.evict(), Some(6));
+            replacer.record_access(6, 6, AccessType::Get);
+        }
+
+        replacer.remove(6);
+        assert_eq!(replacer.evict(), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    page::PageId,
+    page_cache::FrameId,
+    replacer::{first_unpinned, AccessType, Frame, Queue, Replacer},
+};
+
+/// Full 2Q (Johnson & Shasha). Pages enter a FIFO (A1in) on their first access and are remembered
+/// in a ghost queue (A1out) after eviction. Only a page accessed again whilst in A1out is promoted
+/// to the main LRU queue (Am), so pages read once can't push out the hot set.
+pub struct TwoQReplacer {
+    frames: HashMap<FrameId, Frame>,
+    a1in: Queue<FrameId>,
+    am: Queue<FrameId>,
+    a1out: Queue<PageId>,
+    /// Frames only accessed by scans, evicted before A1in and never remembered in A1out
+    scans: Queue<FrameId>,
+    /// Frames A1in can hold before it is evicted from ahead of Am
+    kin: usize,
+    /// Evicted pages remembered by A1out
+    kout: usize,
+}
+
+impl TwoQReplacer {
+    /// Tuned as recommended by the paper, A1in gets a quarter of the frames and A1out remembers
+    /// half as many pages as there are frames
+    pub fn new(frames: usize) -> Self {
+        Self {
+            frames: HashMap::new(),
+            a1in: Queue::default(),
+            am: Queue::default(),
+            a1out: Queue::default(),
+            scans: Queue::default(),
+            kin: (frames / 4).max(1),
+            kout: (frames / 2).max(1),
+        }
+    }
+
+    pub fn evict(&mut self) -> Option<FrameId> {
+        if let Some(i) = first_unpinned(&self.scans, &self.frames) {
+            self.remove(i);
+            return Some(i);
+        }
+
+        let from_a1in =
+            self.a1in.len() > self.kin || first_unpinned(&self.am, &self.frames).is_none();
+        let i = match from_a1in {
+            true => first_unpinned(&self.a1in, &self.frames),
+            false => None,
+        };
+
+        let i = match i {
+            Some(i) => {
+                self.a1in.remove(&i);
+                self.a1out.push(self.frames[&i].page_id);
+                while self.a1out.len() > self.kout {
+                    self.a1out.pop_front();
+                }
+
+                i
+            }
+            None => {
+                let i = first_unpinned(&self.am, &self.frames)
+                    .or_else(|| first_unpinned(&self.a1in, &self.frames))?;
+                self.am.remove(&i);
+                self.a1in.remove(&i);
+
+                i
+            }
+        };
+
+        self.frames.remove(&i);
+        Some(i)
+    }
+
+    pub fn record_access(&mut self, i: FrameId, page_id: PageId, access_type: AccessType) {
+        match self.frames.get(&i) {
+            Some(frame) if frame.page_id == page_id => {
+                if access_type == AccessType::Scan {
+                    return;
+                }
+
+                // Accesses whilst in A1in are treated as correlated and ignored
+                if self.am.contains(&i) {
+                    self.am.push(i);
+                } else if self.scans.remove(&i) {
+                    self.admit(i, page_id);
+                }
+            }
+            _ => {
+                self.remove(i);
+                self.frames.insert(i, Frame { page_id, pin: 0 });
+
+                match access_type {
+                    AccessType::Get => self.admit(i, page_id),
+                    AccessType::Scan => self.scans.push(i),
+                }
+            }
+        }
+    }
+
+    fn admit(&mut self, i: FrameId, page_id: PageId) {
+        match self.a1out.remove(&page_id) {
+            true => self.am.push(i),
+            false => self.a1in.push(i),
+        }
+    }
+
+    pub fn pin(&mut self, i: FrameId) {
+        if let Some(frame) = self.frames.get_mut(&i) {
+            frame.pin += 1;
+        }
+    }
+
+    pub fn unpin(&mut self, i: FrameId) {
+        if let Some(frame) = self.frames.get_mut(&i) {
+            frame.pin -= 1;
+        }
+    }
+
+    pub fn remove(&mut self, i: FrameId) {
+        if self.frames.remove(&i).is_some() {
+            self.a1in.remove(&i);
+            self.am.remove(&i);
+            self.scans.remove(&i);
+        }
+    }
+}
+
+pub struct TwoQ {
+    inner: Mutex<TwoQReplacer>,
+}
+
+impl TwoQ {
+    pub fn new(frames: usize) -> Arc<Self> {
+        Arc::new(Self { inner: Mutex::new(TwoQReplacer::new(frames)) })
+    }
+}
+
+impl Replacer for TwoQ {
+    fn record_access(&self, i: FrameId, page_id: PageId, access_type: AccessType) {
+        self.inner.lock().expect("todo").record_access(i, page_id, access_type)
+    }
+
+    fn pin(&self, i: FrameId) {
+        self.inner.lock().expect("todo").pin(i)
+    }
+
+    fn unpin(&self, i: FrameId) {
+        self.inner.lock().expect("todo").unpin(i)
+    }
+
+    fn evict(&self) -> Option<FrameId> {
+        self.inner.lock().expect("todo").evict()
+    }
+
+    fn remove(&self, i: FrameId) {
+        self.inner.lock().expect("todo").remove(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        page::PageId,
+        replacer::{AccessType, Replacer, TwoQ},
+    };
+
+    #[test]
+    fn test_two_q() {
+        // A1in holds 1 frame and A1out remembers 2 pages
+        let replacer = TwoQ::new(4);
+        for i in 0..4 {
+            replacer.record_access(i, i as PageId, AccessType::Get);
+        }
+
+        // Pages seen once leave in FIFO order
+        assert_eq!(replacer.evict(), Some(0));
+        assert_eq!(replacer.evict(), Some(1));
+
+        // Page 0 was remembered, so it goes straight into Am when it is read back
+        replacer.record_access(0, 0, AccessType::Get);
+        replacer.record_access(1, 10, AccessType::Get);
+        replacer.record_access(1, 10, AccessType::Get);
+        assert_eq!(replacer.evict(), Some(2));
+        assert_eq!(replacer.evict(), Some(3));
+
+        // A1in is back within its share, so Am is evicted from next
+        assert_eq!(replacer.evict(), Some(0));
+        assert_eq!(replacer.evict(), Some(1));
+        assert_eq!(replacer.evict(), None);
+    }
+}
@@ -1,14 +1,16 @@
-use std::sync::Mutex;
+use std::{collections::HashSet, sync::Mutex};
 
 use bytes::BytesMut;
 
 use crate::{
     disk::{Disk, FileSystem},
-    page::{PageBuf, PageId},
-    page_cache::{Result, SharedPageCache},
-    table::node::Node,
+    page::PageId,
+    page_cache::{PageCacheError, Result, SharedPageCache},
+    replacer::{AccessType, Replacer, LRU},
+    table::node::{Node, MAX_TUPLE_LEN},
     table::tuple::{RId, Tuple, TupleMeta},
-    writep,
+    transaction::{Snapshot, Transaction},
+    wal::TxnId,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -23,17 +25,25 @@ impl Default for TableMeta {
     }
 }
 
-pub struct List<D: Disk = FileSystem> {
-    pc: SharedPageCache<D>,
+#[derive(Debug, Default, PartialEq)]
+pub struct VacuumStats {
+    pub tuples_removed: usize,
+    pub bytes_freed: usize,
+    /// Pages left with no tuples, unlinked from the list and deallocated once the vacuum commits
+    pub pages_freed: usize,
+}
+
+pub struct List<D: Disk = FileSystem, R: Replacer = LRU> {
+    pc: SharedPageCache<D, R>,
     first_page_id: PageId,
     last_page_id: Mutex<PageId>,
 }
 
-impl<D: Disk> List<D> {
+impl<D: Disk, R: Replacer> List<D, R> {
     pub fn new(
-        pc: SharedPageCache<D>,
+        pc: SharedPageCache<D, R>,
         TableMeta { mut first_page_id, mut last_page_id }: TableMeta,
-    ) -> crate::Result<List<D>> {
+    ) -> crate::Result<List<D, R>> {
         assert!(
             (first_page_id == -1 && last_page_id == -1)
                 || (first_page_id != -1 && last_page_id != -1)
@@ -48,7 +58,7 @@ impl<D: Disk> List<D> {
         Ok(Self { pc, first_page_id, last_page_id: Mutex::new(last_page_id) })
     }
 
-    pub fn default(pc: SharedPageCache<D>) -> crate::Result<List<D>> {
+    pub fn default(pc: SharedPageCache<D, R>) -> crate::Result<List<D, R>> {
         let page = pc.new_page()?;
         let first_page_id = page.id;
         let last_page_id = page.id;
@@ -65,56 +75,69 @@ impl<D: Disk> List<D> {
         self.last_page_id.lock().expect("todo")
     }
 
-    pub fn iter(&self) -> Result<Iter<'_, D>> {
+    /// Iterate over the rows visible to `snapshot`
+    pub fn iter<'a>(&'a self, snapshot: &'a Snapshot) -> Result<Iter<'a, D, R>> {
         let last_page_id = self.last_page_id();
-        let page = self.pc.fetch_page(last_page_id)?;
+        let page = self.pc.fetch_page_with(last_page_id, AccessType::Scan)?;
         let page_r = page.read();
         let node = Node::from(&page_r.data);
 
         Ok(Iter {
             list: self,
+            snapshot,
             r_id: RId { page_id: self.first_page_id, slot_id: 0 },
             end: RId { page_id: last_page_id, slot_id: node.len() },
         })
     }
 
-    pub fn insert(&self, tuple_data: &BytesMut, meta: &TupleMeta) -> Result<Option<RId>> {
+    pub fn insert(
+        &self,
+        tuple_data: &BytesMut,
+        meta: &TupleMeta,
+        txn: &Transaction,
+    ) -> Result<Option<RId>> {
+        self._insert(tuple_data, &TupleMeta { xmin: txn.id(), xmax: 0, ..*meta }, txn)
+    }
+
+    fn _insert(
+        &self,
+        tuple_data: &BytesMut,
+        meta: &TupleMeta,
+        txn: &Transaction,
+    ) -> Result<Option<RId>> {
+        if tuple_data.len() > MAX_TUPLE_LEN {
+            return Err(PageCacheError::TupleTooLarge);
+        }
+
         let mut last_page_id = self.last_page_id_mut();
         let page = self.pc.fetch_page(*last_page_id)?;
-        let mut page_w = page.write();
-        let mut node = Node::from(&page_w.data);
+        let mut node = page.as_heap_page().logged(txn);
 
         if let Some(slot_id) = node.insert(tuple_data, meta) {
-            writep!(page_w, &PageBuf::from(&node));
             return Ok(Some(RId { page_id: *last_page_id, slot_id }));
         }
 
-        if node.len() == 0 {
-            todo!("tuple too large error")
-        }
-
         // Insert into a new page and set the next pointer
         let npage = self.pc.new_page()?;
-        let mut npage_w = npage.write();
+        let mut nnode = npage.as_heap_page().logged(txn);
         node.next_page_id = npage.id;
         *last_page_id = npage.id;
 
-        // Write the next page id on first node
+        // Write the next page id on first node. The link isn't undone if the transaction aborts,
+        // other transactions can insert into the new page as soon as the last page is released, so
+        // it stays the last page.
         // TODO: just write the page id instead of the entire page?
-        writep!(page_w, &PageBuf::from(&node));
+        drop(node);
 
-        let mut node = Node::from(&npage_w.data);
-        match node.insert(tuple_data, meta) {
-            Some(slot_id) => {
-                writep!(npage_w, &PageBuf::from(&node));
-                Ok(Some(RId { page_id: *last_page_id, slot_id }))
-            }
-            None => unreachable!(),
+        match nnode.insert(tuple_data, meta) {
+            Some(slot_id) => Ok(Some(RId { page_id: *last_page_id, slot_id })),
+            None => unreachable!("an empty page holds any tuple up to MAX_TUPLE_LEN"),
         }
     }
 
-    pub fn get(&self, r_id: RId) -> Result<Option<(TupleMeta, Tuple)>> {
-        let page = self.pc.fetch_page(r_id.page_id)?;
+    /// Read the tuple stored in the slot regardless of visibility
+    fn read(&self, r_id: RId, access_type: AccessType) -> Result<Option<(TupleMeta, Tuple)>> {
+        let page = self.pc.fetch_page_with(r_id.page_id, access_type)?;
         let page_r = page.read();
         let node = Node::from(&page_r.data);
 
@@ -126,97 +149,298 @@ impl<D: Disk> List<D> {
         Ok(tuple)
     }
 
-    pub fn update(&mut self, _meta: &TupleMeta) -> Result<()> {
-        todo!()
+    /// Returns the version of the row visible to `snapshot`, following the chain of older
+    /// versions if the row was changed after the snapshot was taken
+    pub fn get(&self, r_id: RId, snapshot: &Snapshot) -> Result<Option<(TupleMeta, Tuple)>> {
+        self._get(r_id, snapshot, AccessType::Get)
+    }
+
+    fn _get(
+        &self,
+        r_id: RId,
+        snapshot: &Snapshot,
+        access_type: AccessType,
+    ) -> Result<Option<(TupleMeta, Tuple)>> {
+        let mut version = match self.read(r_id, access_type)? {
+            Some((meta, _)) if meta.version => return Ok(None),
+            v => v,
+        };
+
+        while let Some((meta, mut tuple)) = version {
+            if snapshot.sees(meta.xmin) {
+                if !meta.visible(snapshot) {
+                    return Ok(None);
+                }
+
+                tuple.rid = r_id;
+                return Ok(Some((meta, tuple)));
+            }
+
+            version = match meta.prev {
+                Some(prev) => self.read(prev, access_type)?,
+                None => None,
+            };
+        }
+
+        Ok(None)
+    }
+
+    /// Another transaction has changed the row since `txn` began, first updater wins
+    fn conflicts(meta: &TupleMeta, txn: &Transaction) -> bool {
+        meta.deleted || !txn.snapshot().sees(meta.xmin) || (meta.xmax != 0 && meta.xmax != txn.id())
+    }
+
+    /// Replace the row with a new version, keeping the old one for readers that can't see `txn`.
+    /// Returns the row's id, which changes if the new version doesn't fit on the row's page, or
+    /// None if the row was deleted or changed by a concurrent transaction.
+    pub fn update(
+        &self,
+        r_id: RId,
+        tuple_data: &BytesMut,
+        txn: &Transaction,
+    ) -> Result<Option<RId>> {
+        if tuple_data.len() > MAX_TUPLE_LEN {
+            return Err(PageCacheError::TupleTooLarge);
+        }
+
+        let Some((meta, old)) = self.read(r_id, AccessType::Get)? else {
+            return Ok(None);
+        };
+        if meta.version || meta.xmax == txn.id() || Self::conflicts(&meta, txn) {
+            return Ok(None);
+        }
+
+        // A version created by this transaction isn't visible to anyone else, so it can be
+        // overwritten
+        let (prev, copy) = if meta.xmin == txn.id() {
+            (meta.prev, None)
+        } else {
+            let meta = TupleMeta { version: true, xmax: txn.id(), ..meta };
+            let copy = self._insert(&old.data, &meta, txn)?;
+            (copy, copy)
+        };
+
+        let page = self.pc.fetch_page(r_id.page_id)?;
+        let mut node = page.as_heap_page().logged(txn);
+
+        // The row could have changed while the old version was being copied
+        let (current, _) = node.get(&r_id).expect("row should exist");
+        if current != meta {
+            drop(node);
+
+            // Nothing points at the copy yet
+            if let Some(copy) = copy {
+                let page = self.pc.fetch_page(copy.page_id)?;
+                page.as_heap_page().logged(txn).remove(copy.slot_id);
+            }
+
+            return Ok(None);
+        }
+
+        let new = TupleMeta { xmin: txn.id(), xmax: 0, prev, ..TupleMeta::default() };
+        if node.replace(r_id.slot_id, tuple_data, &new) {
+            return Ok(Some(r_id));
+        }
+
+        // Not enough room, delete the row and insert the new version elsewhere. The new version
+        // doesn't point back at the old one, a scan that has already returned the old row would
+        // return it again through the chain. The copy made above is left for vacuum.
+        node.set_meta(r_id.slot_id, &TupleMeta { xmax: txn.id(), ..meta });
+        drop(node);
+
+        self._insert(tuple_data, &TupleMeta { xmin: txn.id(), ..TupleMeta::default() }, txn)
+    }
+
+    /// Mark the row as deleted by `txn`, readers that can't see `txn` still see the row.
+    /// Returns false if the row was already deleted or changed by a concurrent transaction.
+    pub fn delete(&self, r_id: RId, txn: &Transaction) -> Result<bool> {
+        let page = self.pc.fetch_page(r_id.page_id)?;
+        let mut node = page.as_heap_page().logged(txn);
+
+        let Some((meta, _)) = node.get(&r_id) else {
+            return Ok(false);
+        };
+        if meta.version || meta.xmax == txn.id() || Self::conflicts(&meta, txn) {
+            return Ok(false);
+        }
+
+        node.set_meta(r_id.slot_id, &TupleMeta { xmax: txn.id(), ..meta });
+
+        Ok(true)
+    }
+
+    /// Remove versions replaced or deleted before `horizon` and compact the pages they were on.
+    /// Pages changed by transactions that could still abort are skipped, their undo records expect
+    /// the tuples to be where they were written. Pages left empty are freed unless a version chain
+    /// or an index, through the pages in `indexed`, still points into them.
+    pub fn vacuum(
+        &self,
+        horizon: TxnId,
+        indexed: &HashSet<PageId>,
+        txn: &Transaction,
+    ) -> Result<VacuumStats> {
+        let mut stats = VacuumStats::default();
+
+        // Hold the last page so inserts wait until the pages are relinked. Nothing else can add a
+        // version chain without inserting.
+        let last_page_id = self.last_page_id_mut();
+        let mut pages = Vec::new();
+        let mut chained = HashSet::new();
+        let mut page_id = self.first_page_id;
+        loop {
+            // Only versions no reader can see are removed, so there is nothing to undo. Undoing it
+            // could also overwrite tuples written into the space it freed.
+            let page = self.pc.fetch_page(page_id)?;
+            let mut node = page.as_heap_page().logged_redo_only(txn);
+            let next_page_id = node.next_page_id;
+
+            if !node.changed_since(horizon) {
+                let free = node.free_space();
+                let removed = node.prune(horizon);
+                if removed > 0 {
+                    node.compact();
+                    stats.tuples_removed += removed as usize;
+                    stats.bytes_freed += node.free_space() - free;
+                }
+            }
+
+            chained.extend(node.prev_page_ids());
+            let empty = node.len() > 0 && node.deleted_len() == node.len();
+            pages.push((page_id, next_page_id, empty));
+            drop(node);
+
+            if page_id == *last_page_id || next_page_id == 0 {
+                break;
+            }
+            page_id = next_page_id;
+        }
+
+        let mut prev_page_id = None;
+        for (page_id, next_page_id, empty) in pages {
+            let unreachable = !chained.contains(&page_id) && !indexed.contains(&page_id);
+            match prev_page_id {
+                Some(prev_page_id) if empty && unreachable && page_id != *last_page_id => {
+                    let page = self.pc.fetch_page(prev_page_id)?;
+                    page.as_heap_page().logged_redo_only(txn).next_page_id = next_page_id;
+
+                    txn.deallocate(page_id);
+                    stats.pages_freed += 1;
+                }
+                _ => prev_page_id = Some(page_id),
+            }
+        }
+
+        Ok(stats)
     }
 }
 
 // Iter should hold a read lock and deserialised page?
-pub struct Iter<'a, D: Disk = FileSystem> {
-    list: &'a List<D>,
+pub struct Iter<'a, D: Disk = FileSystem, R: Replacer = LRU> {
+    list: &'a List<D, R>,
+    snapshot: &'a Snapshot,
     r_id: RId,
     end: RId,
 }
 
-impl<'a, D: Disk> Iterator for Iter<'a, D> {
-    type Item = Result<(TupleMeta, Tuple)>;
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a, D: Disk, R: Replacer> Iter<'a, D, R> {
+    /// Returns the next slot regardless of whether it's visible
+    fn next_slot(&mut self) -> Option<Result<RId>> {
         if self.end == self.r_id {
             return None;
         }
 
-        let result = match self.list.get(self.r_id) {
-            Ok(opt) => match opt {
-                Some(t) => Ok(t),
-                None => return None,
-            },
-            Err(e) => Err(e),
-        };
-
-        let page = match self.list.pc.fetch_page(self.r_id.page_id) {
+        let r_id = self.r_id;
+        let page = match self.list.pc.fetch_page_with(self.r_id.page_id, AccessType::Scan) {
             Ok(p) => p,
             Err(e) => return Some(Err(e)),
         };
         let page_r = page.read();
         let node = Node::from(&page_r.data);
 
+        // Read the next page whilst this one's rows are returned
+        if r_id.slot_id == 0 && r_id.page_id != self.end.page_id && node.next_page_id != 0 {
+            self.list.pc.prefetch(node.next_page_id);
+        }
+
         if self.r_id.page_id == self.end.page_id && self.r_id.slot_id == self.end.slot_id - 1 {
             // Last tuple, increment (so the next iteration returns None) and return result
             self.r_id.slot_id += 1;
-            return Some(result);
         } else if self.r_id.slot_id + 1 < node.len() {
             self.r_id.slot_id += 1;
         } else if node.next_page_id == 0 {
-            return None;
+            self.r_id = self.end;
         } else {
             self.r_id = RId { page_id: node.next_page_id, slot_id: 0 }
         }
 
-        Some(result)
+        Some(Ok(r_id))
+    }
+}
+
+impl<'a, D: Disk, R: Replacer> Iterator for Iter<'a, D, R> {
+    type Item = Result<(TupleMeta, Tuple)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let r_id = match self.next_slot()? {
+                Ok(r_id) => r_id,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match self.list._get(r_id, self.snapshot, AccessType::Scan) {
+                Ok(Some(t)) => return Some(Ok(t)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashSet;
+
     use bytes::BytesMut;
 
     use crate::{
         disk::Memory,
         page::PAGE_SIZE,
-        page_cache::PageCache,
+        page_cache::PageCacheError,
         replacer::LRU,
         table::list::List,
         table::{
-            list::TableMeta,
+            list::{TableMeta, VacuumStats},
+            node::MAX_TUPLE_LEN,
             tuple::{Tuple, TupleMeta},
         },
+        test::page_cache,
+        transaction::Snapshot,
     };
 
     #[test]
     fn test_table() -> crate::Result<()> {
-        const MEMORY: usize = PAGE_SIZE * 1;
         const K: usize = 2;
 
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let lru = LRU::new(K);
-        let pc = PageCache::new(disk, lru, 0);
+        let (pc, tm) = page_cache(disk, lru)?;
+        let txn = tm.begin();
 
         let list = List::default(pc.clone())?;
-        let meta = TupleMeta { deleted: false };
+        let meta = TupleMeta::default();
         let tuple_a = BytesMut::from(&std::array::from_fn::<u8, 10, _>(|i| (i * 2) as u8)[..]);
         let tuple_b = BytesMut::from(&std::array::from_fn::<u8, 15, _>(|i| (i * 3) as u8)[..]);
 
-        let r_id_a = list.insert(&tuple_a, &meta)?.unwrap();
-        let r_id_b = list.insert(&tuple_b, &meta)?.unwrap();
+        let r_id_a = list.insert(&tuple_a, &meta, &txn)?.unwrap();
+        let r_id_b = list.insert(&tuple_b, &meta, &txn)?.unwrap();
 
         let list = List::new(
             pc,
             TableMeta { first_page_id: list.first_page_id, last_page_id: list.last_page_id() },
         )?;
 
-        let (_, have_a) = list.get(r_id_a)?.unwrap();
-        let (_, have_b) = list.get(r_id_b)?.unwrap();
+        let (_, have_a) = list.get(r_id_a, txn.snapshot())?.unwrap();
+        let (_, have_b) = list.get(r_id_b, txn.snapshot())?.unwrap();
 
         assert_eq!(tuple_a, have_a.data);
         assert_eq!(tuple_b, have_b.data);
@@ -226,27 +450,29 @@ mod test {
 
     #[test]
     fn test_iter() -> crate::Result<()> {
-        const MEMORY: usize = PAGE_SIZE * 4;
         const K: usize = 2;
 
-        let disk = Memory::new::<MEMORY>();
+        let disk = Memory::default();
         let lru = LRU::new(K);
-        let pc = PageCache::new(disk, lru, 0);
+        let (pc, tm) = page_cache(disk, lru)?;
+        let txn = tm.begin();
 
         let first_page_id = pc.new_page()?.id;
         let list = List::new(pc.clone(), TableMeta { first_page_id, last_page_id: first_page_id })?;
 
         const WANT_LEN: usize = 100;
-        let meta = TupleMeta { deleted: false };
+        let meta = TupleMeta::default();
         let mut tuples = Vec::new();
         for i in 0..WANT_LEN {
             let tuple = BytesMut::from(&std::array::from_fn::<u8, 150, _>(|j| (j * i) as u8)[..]);
-            list.insert(&tuple, &meta)?;
+            list.insert(&tuple, &meta, &txn)?;
             tuples.push(tuple);
         }
 
-        let have =
-            list.iter()?.enumerate().collect::<Vec<(usize, crate::Result<(TupleMeta, Tuple)>)>>();
+        let have = list
+            .iter(txn.snapshot())?
+            .enumerate()
+            .collect::<Vec<(usize, crate::Result<(TupleMeta, Tuple)>)>>();
 
         assert_eq!(have.len(), WANT_LEN);
 
@@ -258,4 +484,291 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_mvcc() -> crate::Result<()> {
+        const K: usize = 2;
+
+        let disk = Memory::default();
+        let (pc, tm) = page_cache(disk, LRU::new(K))?;
+        let list = List::default(pc.clone())?;
+
+        let row = |i: u8| BytesMut::from(&[i; 8][..]);
+        fn rows(list: &List<Memory>, snapshot: &Snapshot) -> crate::Result<Vec<BytesMut>> {
+            list.iter(snapshot)?.map(|r| r.map(|(_, t)| t.data)).collect()
+        }
+
+        let a = tm.begin();
+        let rids = (0..3)
+            .map(|i| list.insert(&row(i), &TupleMeta::default(), &a).map(Option::unwrap))
+            .collect::<crate::Result<Vec<_>>>()?;
+        tm.commit(&a)?;
+
+        let reader = tm.begin();
+        let b = tm.begin();
+        assert!(list.update(rids[0], &row(10), &b)?.is_some());
+        assert!(list.update(rids[0], &row(11), &b)?.is_some());
+        assert!(list.delete(rids[1], &b)?);
+        list.insert(&row(3), &TupleMeta::default(), &b)?;
+
+        assert_eq!(rows(&list, b.snapshot())?, vec![row(11), row(2), row(3)]);
+        assert_eq!(rows(&list, reader.snapshot())?, vec![row(0), row(1), row(2)]);
+        assert_eq!(rows(&list, &tm.snapshot())?, vec![row(0), row(1), row(2)]);
+
+        // First updater wins
+        let c = tm.begin();
+        assert!(list.update(rids[0], &row(20), &c)?.is_none());
+        assert!(!list.delete(rids[1], &c)?);
+        assert!(list.update(rids[2], &row(22), &c)?.is_some());
+        tm.abort(&c)?;
+
+        tm.commit(&b)?;
+        assert_eq!(rows(&list, reader.snapshot())?, vec![row(0), row(1), row(2)]);
+        assert_eq!(list.get(rids[1], reader.snapshot())?.map(|(_, t)| t.data), Some(row(1)));
+        assert_eq!(rows(&list, &tm.snapshot())?, vec![row(11), row(2), row(3)]);
+        assert_eq!(list.get(rids[1], &tm.snapshot())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_grow() -> crate::Result<()> {
+        const K: usize = 2;
+        const LEN: usize = PAGE_SIZE / 8;
+
+        let disk = Memory::default();
+        let (pc, tm) = page_cache(disk, LRU::new(K))?;
+        let list = List::default(pc.clone())?;
+
+        let row = |i: u8, len: usize| BytesMut::from(&vec![i; len][..]);
+        fn rows(list: &List<Memory>, snapshot: &Snapshot) -> crate::Result<Vec<BytesMut>> {
+            list.iter(snapshot)?.map(|r| r.map(|(_, t)| t.data)).collect()
+        }
+
+        // Fill the first page
+        let txn = tm.begin();
+        let mut rids = Vec::new();
+        let mut i = 0;
+        loop {
+            let rid = list.insert(&row(i, LEN), &TupleMeta::default(), &txn)?.unwrap();
+            if rid.page_id != list.first_page_id {
+                break;
+            }
+            rids.push(rid);
+            i += 1;
+        }
+        tm.commit(&txn)?;
+        let want = (0..=i).map(|i| row(i, LEN)).collect::<Vec<_>>();
+
+        // An aborted move leaves the row where it was
+        let txn = tm.begin();
+        let moved = list.update(rids[0], &row(100, LEN * 2), &txn)?.unwrap();
+        assert_ne!(moved, rids[0]);
+        tm.abort(&txn)?;
+        assert_eq!(rows(&list, &tm.snapshot())?, want);
+        assert_eq!(list.get(moved, &tm.snapshot())?, None);
+
+        let reader = tm.begin();
+        let txn = tm.begin();
+        let moved = list.update(rids[0], &row(100, LEN * 2), &txn)?.unwrap();
+        assert_ne!(moved, rids[0]);
+        assert_eq!(list.get(moved, txn.snapshot())?.map(|(_, t)| t.data), Some(row(100, LEN * 2)));
+        assert_eq!(list.get(rids[0], txn.snapshot())?, None);
+
+        // The row has moved, it can't be changed through its old id
+        assert!(list.update(rids[0], &row(101, LEN), &txn)?.is_none());
+        assert!(list.update(moved, &row(101, LEN * 2), &txn)?.is_some());
+        tm.commit(&txn)?;
+
+        assert_eq!(rows(&list, reader.snapshot())?, want);
+        let mut want = want[1..].to_vec();
+        want.push(row(101, LEN * 2));
+        assert_eq!(rows(&list, &tm.snapshot())?, want);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_too_large() -> crate::Result<()> {
+        let (pc, tm) = page_cache(Memory::default(), LRU::new(2))?;
+        let list = List::default(pc.clone())?;
+        let txn = tm.begin();
+        let meta = TupleMeta::default();
+
+        let rid = list.insert(&BytesMut::zeroed(MAX_TUPLE_LEN), &meta, &txn)?.unwrap();
+        assert_eq!(
+            list.insert(&BytesMut::zeroed(MAX_TUPLE_LEN + 1), &meta, &txn),
+            Err(PageCacheError::TupleTooLarge)
+        );
+        assert_eq!(
+            list.update(rid, &BytesMut::zeroed(MAX_TUPLE_LEN + 1), &txn),
+            Err(PageCacheError::TupleTooLarge)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum() -> crate::Result<()> {
+        const K: usize = 2;
+        const ROWS: u8 = 60;
+        // About 40 rows to a page
+        const LEN: usize = PAGE_SIZE / 40;
+
+        let disk = Memory::default();
+        let (pc, tm) = page_cache(disk, LRU::new(K))?;
+        let list = List::default(pc.clone())?;
+
+        let row = |i: u8| BytesMut::from(&[i; LEN][..]);
+
+        let txn = tm.begin();
+        let rids = (0..ROWS)
+            .map(|i| list.insert(&row(i), &TupleMeta::default(), &txn).map(Option::unwrap))
+            .collect::<crate::Result<Vec<_>>>()?;
+        tm.commit(&txn)?;
+
+        let reader = tm.begin();
+        let txn = tm.begin();
+        for (i, rid) in rids.iter().enumerate() {
+            assert_eq!(list.update(*rid, &row(i as u8 + ROWS), &txn)?, Some(*rid));
+        }
+        tm.commit(&txn)?;
+
+        // The reader still needs the old versions
+        let txn = tm.begin();
+        assert_eq!(list.vacuum(tm.horizon(), &HashSet::new(), &txn)?, VacuumStats::default());
+        tm.commit(&txn)?;
+        for (i, rid) in rids.iter().enumerate() {
+            let (_, tuple) = list.get(*rid, reader.snapshot())?.unwrap();
+            assert_eq!(tuple.data, row(i as u8));
+        }
+        tm.commit(&reader)?;
+
+        let txn = tm.begin();
+        for rid in &rids[..10] {
+            assert!(list.delete(*rid, &txn)?);
+        }
+        tm.commit(&txn)?;
+
+        let txn = tm.begin();
+        let stats = list.vacuum(tm.horizon(), &HashSet::new(), &txn)?;
+        tm.commit(&txn)?;
+        assert_eq!(stats.tuples_removed, ROWS as usize + 10);
+        assert!(stats.bytes_freed >= (ROWS as usize + 10) * LEN);
+        assert!(stats.pages_freed > 0);
+
+        // Row ids don't change
+        let snapshot = tm.snapshot();
+        for (i, rid) in rids.iter().enumerate() {
+            let have = list.get(*rid, &snapshot)?.map(|(_, t)| t.data);
+            let want = (i >= 10).then(|| row(i as u8 + ROWS));
+            assert_eq!(have, want);
+        }
+        let have = list
+            .iter(&snapshot)?
+            .map(|r| r.map(|(_, t)| t.data))
+            .collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!(have, (10..ROWS).map(|i| row(i + ROWS)).collect::<Vec<_>>());
+
+        // Nothing left to remove
+        let txn = tm.begin();
+        assert_eq!(list.vacuum(tm.horizon(), &HashSet::new(), &txn)?, VacuumStats::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum_snapshot() -> crate::Result<()> {
+        const ROWS: u8 = 10;
+
+        let (pc, tm) = page_cache(Memory::default(), LRU::new(2))?;
+        let list = List::default(pc.clone())?;
+        let row = |i: u8| BytesMut::from(&[i; 8][..]);
+
+        let txn = tm.begin();
+        let rids = (0..ROWS)
+            .map(|i| list.insert(&row(i), &TupleMeta::default(), &txn).map(Option::unwrap))
+            .collect::<crate::Result<Vec<_>>>()?;
+        tm.commit(&txn)?;
+
+        let snapshot = tm.snapshot();
+        let txn = tm.begin();
+        for (i, rid) in rids.iter().enumerate() {
+            assert_eq!(list.update(*rid, &row(i as u8 + ROWS), &txn)?, Some(*rid));
+        }
+        tm.commit(&txn)?;
+
+        // The snapshot isn't tied to a transaction but still needs the old versions
+        let txn = tm.begin();
+        assert_eq!(list.vacuum(tm.horizon(), &HashSet::new(), &txn)?, VacuumStats::default());
+        tm.commit(&txn)?;
+        for (i, rid) in rids.iter().enumerate() {
+            assert_eq!(list.get(*rid, &snapshot)?.map(|(_, t)| t.data), Some(row(i as u8)));
+        }
+
+        // A clone holds the horizon back as well
+        let clone = snapshot.clone();
+        drop(snapshot);
+        let txn = tm.begin();
+        assert_eq!(list.vacuum(tm.horizon(), &HashSet::new(), &txn)?, VacuumStats::default());
+        tm.commit(&txn)?;
+
+        drop(clone);
+        let txn = tm.begin();
+        let stats = list.vacuum(tm.horizon(), &HashSet::new(), &txn)?;
+        tm.commit(&txn)?;
+        assert_eq!(stats.tuples_removed, ROWS as usize);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum_indexed() -> crate::Result<()> {
+        const LEN: usize = PAGE_SIZE / 8;
+
+        let (pc, tm) = page_cache(Memory::default(), LRU::new(2))?;
+        let list = List::default(pc.clone())?;
+        let row = |i: u8| BytesMut::from(&vec![i; LEN][..]);
+
+        // Fill two pages and start a third
+        let txn = tm.begin();
+        let mut rids = Vec::new();
+        let mut pages = Vec::new();
+        while pages.len() < 3 {
+            let rid = list.insert(&row(rids.len() as u8), &TupleMeta::default(), &txn)?.unwrap();
+            if !pages.contains(&rid.page_id) {
+                pages.push(rid.page_id);
+            }
+            rids.push(rid);
+        }
+        tm.commit(&txn)?;
+
+        let middle = pages[1];
+        let txn = tm.begin();
+        for rid in rids.iter().filter(|r| r.page_id == middle) {
+            assert!(list.delete(*rid, &txn)?);
+        }
+        tm.commit(&txn)?;
+        let deleted = rids.iter().filter(|r| r.page_id == middle).count();
+
+        // An index still points into the emptied page
+        let txn = tm.begin();
+        let stats = list.vacuum(tm.horizon(), &HashSet::from([middle]), &txn)?;
+        tm.commit(&txn)?;
+        assert_eq!(stats.tuples_removed, deleted);
+        assert_eq!(stats.pages_freed, 0);
+
+        let txn = tm.begin();
+        let stats = list.vacuum(tm.horizon(), &HashSet::new(), &txn)?;
+        tm.commit(&txn)?;
+        assert_eq!(stats.pages_freed, 1);
+
+        let have = list
+            .iter(&tm.snapshot())?
+            .map(|r| r.map(|(_, t)| t.rid))
+            .collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!(have, rids.into_iter().filter(|r| r.page_id != middle).collect::<Vec<_>>());
+
+        Ok(())
+    }
 }
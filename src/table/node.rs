@@ -3,13 +3,14 @@ use std::ops::Range;
 use bytes::BytesMut;
 
 use crate::{
-    page::{PageBuf, PageId, PAGE_SIZE},
+    page::{PageBuf, PageId, PAGE_HEADER_SIZE, PAGE_SIZE},
     table::tuple::{RId, Slot, Tuple, TupleInfoBuf, TupleMeta},
+    wal::TxnId,
 };
 
 /*
     TablePage:
-    NextPageID | NumTuples | NumDeletedTuples | Slots | Free | Tuples
+    PageHeader | NextPageID | NumTuples | NumDeletedTuples | Slots | Free | Tuples
 
     Slot:
     TupleInfo
@@ -18,10 +19,13 @@ use crate::{
     RId | Data
 */
 
-pub const NEXT_PAGE_ID: Range<usize> = 0..4;
-pub const TUPLES_LEN: Range<usize> = 4..8;
-pub const DELETED_TUPLES_LEN: Range<usize> = 8..12;
-pub const SLOTS_START: usize = 12;
+pub const NEXT_PAGE_ID: Range<usize> = PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8;
+pub const TUPLES_LEN: Range<usize> = PAGE_HEADER_SIZE + 8..PAGE_HEADER_SIZE + 12;
+pub const DELETED_TUPLES_LEN: Range<usize> = PAGE_HEADER_SIZE + 12..PAGE_HEADER_SIZE + 16;
+pub const SLOTS_START: usize = PAGE_HEADER_SIZE + 16;
+
+/// Largest tuple an empty page can hold
+pub const MAX_TUPLE_LEN: usize = PAGE_SIZE - SLOTS_START - Slot::SIZE;
 
 #[derive(Debug, PartialEq)]
 pub struct Node {
@@ -34,7 +38,7 @@ pub struct Node {
 impl From<&PageBuf> for Node {
     fn from(buf: &PageBuf) -> Self {
         let page_start = buf.as_ptr() as *mut u8;
-        let next_page_id = i32::from_be_bytes(buf[NEXT_PAGE_ID].try_into().unwrap());
+        let next_page_id = PageId::from_be_bytes(buf[NEXT_PAGE_ID].try_into().unwrap());
         let tuples_len = u32::from_be_bytes(buf[TUPLES_LEN].try_into().unwrap());
         let deleted_tuples_len = u32::from_be_bytes(buf[DELETED_TUPLES_LEN].try_into().unwrap());
 
@@ -71,8 +75,8 @@ impl From<&Node> for PageBuf {
             from += SLOT_SIZE;
         }
 
-        let offset = match table.slots.last() {
-            Some(o) => o.offset as usize,
+        let offset = match table.tuples_start() {
+            Some(o) => o,
             None => return ret,
         };
         assert!(offset < PAGE_SIZE, "tuple being written at PAGE_SIZE or greater");
@@ -87,23 +91,84 @@ impl From<&Node> for PageBuf {
     }
 }
 
-impl Node {
-    const HEADER_SIZE: usize = 12;
+fn tuples_len(buf: &PageBuf) -> u32 {
+    u32::from_be_bytes(buf[TUPLES_LEN].try_into().unwrap())
+}
+
+fn slot_range(slot_id: u32) -> Range<usize> {
+    let start = SLOTS_START + slot_id as usize * Slot::SIZE;
+    start..start + Slot::SIZE
+}
+
+/// The slot and its tuple, empty if the page doesn't have the slot
+pub fn slot_image(buf: &PageBuf, slot_id: u32) -> Vec<u8> {
+    if slot_id >= tuples_len(buf) {
+        return Vec::new();
+    }
+
+    let slot = &buf[slot_range(slot_id)];
+    let Slot { offset, len, .. } = Slot::from(slot);
+    let mut ret = slot.to_vec();
+    ret.extend_from_slice(&buf[offset as usize..][..len as usize]);
+
+    ret
+}
+
+/// Write a slot and its tuple as returned by `slot_image`, adding the slot if the page doesn't have
+/// it yet
+pub fn set_slot(buf: &mut PageBuf, slot_id: u32, image: &[u8]) {
+    let (slot, tuple) = image.split_at(Slot::SIZE);
+    let offset = Slot::from(slot).offset as usize;
+    buf[slot_range(slot_id)].copy_from_slice(slot);
+    buf[offset..offset + tuple.len()].copy_from_slice(tuple);
+
+    if slot_id >= tuples_len(buf) {
+        buf[TUPLES_LEN].copy_from_slice(&(slot_id + 1).to_be_bytes());
+    }
+}
+
+/// The slots whose entry or tuple differ between two versions of the page
+pub fn changed_slots(old: &PageBuf, new: &PageBuf) -> Vec<u32> {
+    (0..tuples_len(new)).filter(|i| slot_image(old, *i) != slot_image(new, *i)).collect()
+}
+
+/// Put the slot back to `before`, leaving the other slots alone. A slot that was added is marked
+/// as deleted rather than removed, so the slots after it keep their ids.
+pub fn undo_slot(buf: &PageBuf, slot_id: u32, before: &[u8]) -> PageBuf {
+    let mut ret = *buf;
+    if !before.is_empty() {
+        set_slot(&mut ret, slot_id, before);
+        return ret;
+    }
+
+    let deleted = Slot {
+        offset: PAGE_SIZE as u32,
+        len: 0,
+        meta: TupleMeta { deleted: true, ..Default::default() },
+    };
+    ret[slot_range(slot_id)].copy_from_slice(&TupleInfoBuf::from(&deleted));
+    let deleted_len = u32::from_be_bytes(ret[DELETED_TUPLES_LEN].try_into().unwrap()) + 1;
+    ret[DELETED_TUPLES_LEN].copy_from_slice(&deleted_len.to_be_bytes());
+
+    ret
+}
 
+impl Node {
     pub fn len(&self) -> u32 {
         self.slots.len() as u32
     }
 
-    pub fn next_tuple_offset(&self, tuple_data: &BytesMut) -> Option<usize> {
-        let offset = match self.slots.last() {
-            Some(slot) => slot.offset as usize,
-            None => PAGE_SIZE,
-        };
+    /// Updated tuples can be moved, so the last slot doesn't always have the lowest offset
+    fn tuples_start(&self) -> Option<usize> {
+        self.slots.iter().filter(|slot| !slot.meta.deleted).map(|slot| slot.offset as usize).min()
+    }
 
-        let tuple_offset = offset - tuple_data.len();
+    fn free_offset(&self, len: usize, slots: usize) -> Option<usize> {
+        let offset = self.tuples_start().unwrap_or(PAGE_SIZE);
+        let tuple_offset = offset.checked_sub(len)?;
 
         // Ensure tuple isn't written over header/slots
-        let size = Self::HEADER_SIZE + Slot::SIZE * (self.len() as usize + 1);
+        let size = SLOTS_START + Slot::SIZE * slots;
         if tuple_offset < size {
             return None;
         }
@@ -111,13 +176,18 @@ impl Node {
         Some(tuple_offset)
     }
 
+    pub fn next_tuple_offset(&self, tuple_data: &BytesMut) -> Option<usize> {
+        self.free_offset(tuple_data.len(), self.len() as usize + 1)
+    }
+
     pub fn insert(&mut self, tuple_data: &BytesMut, meta: &TupleMeta) -> Option<u32> {
         let offset = self.next_tuple_offset(tuple_data)?;
         let slot_id = self.len();
         self.slots.push(Slot { offset: offset as u32, len: tuple_data.len() as u32, meta: *meta });
 
+        // Written to the buffer the node was read from, `Pin::as_heap_page` writes it back to the
+        // page
         unsafe {
-            // TODO: This writes to the page buffer but doesn't set the dirty flag
             let tuples_ptr = self.page_start.add(offset);
             let tuples = std::slice::from_raw_parts_mut(tuples_ptr, PAGE_SIZE - offset);
             tuples[..tuple_data.len()].copy_from_slice(&tuple_data);
@@ -126,6 +196,121 @@ impl Node {
         Some(slot_id)
     }
 
+    pub fn deleted_len(&self) -> u32 {
+        self.deleted_tuples_len
+    }
+
+    /// Bytes between the slots and the tuples
+    pub fn free_space(&self) -> usize {
+        self.tuples_start().unwrap_or(PAGE_SIZE) - (SLOTS_START + Slot::SIZE * self.slots.len())
+    }
+
+    /// Whether a transaction at or after `horizon` created or replaced any tuple on the page
+    pub fn changed_since(&self, horizon: TxnId) -> bool {
+        self.slots
+            .iter()
+            .filter(|slot| !slot.meta.deleted)
+            .any(|slot| slot.meta.xmin >= horizon || slot.meta.xmax >= horizon)
+    }
+
+    /// Pages holding the older versions of this page's tuples
+    pub fn prev_page_ids(&self) -> impl Iterator<Item = PageId> + '_ {
+        self.slots
+            .iter()
+            .filter(|slot| !slot.meta.deleted)
+            .filter_map(|slot| slot.meta.prev)
+            .map(|prev| prev.page_id)
+    }
+
+    /// Mark tuples replaced or deleted before `horizon` as deleted. Slots are kept so the `RId`s of
+    /// the remaining tuples don't change. Returns the number of tuples removed.
+    pub fn prune(&mut self, horizon: TxnId) -> u32 {
+        let mut removed = 0;
+        for slot in self.slots.iter_mut().filter(|slot| !slot.meta.deleted) {
+            if slot.meta.xmax != 0 && slot.meta.xmax < horizon {
+                *slot = Slot {
+                    offset: PAGE_SIZE as u32,
+                    len: 0,
+                    meta: TupleMeta { deleted: true, ..Default::default() },
+                };
+                removed += 1;
+            } else if slot.meta.xmin < horizon {
+                // Every reader sees this version, older ones are never visited
+                slot.meta.prev = None;
+            }
+        }
+
+        self.deleted_tuples_len += removed;
+
+        removed
+    }
+
+    /// Move the tuples to the end of the page so the free space is in one block
+    pub fn compact(&mut self) {
+        let tuples = self
+            .slots
+            .iter()
+            .map(|slot| unsafe {
+                let tuple_ptr = self.page_start.add(slot.offset as usize);
+                BytesMut::from(std::slice::from_raw_parts(tuple_ptr, slot.len as usize))
+            })
+            .collect::<Vec<_>>();
+
+        let mut offset = PAGE_SIZE;
+        for (slot, tuple) in self.slots.iter_mut().zip(tuples) {
+            if slot.meta.deleted {
+                continue;
+            }
+
+            offset -= tuple.len();
+            slot.offset = offset as u32;
+
+            unsafe {
+                let tuple_ptr = self.page_start.add(offset);
+                std::slice::from_raw_parts_mut(tuple_ptr, tuple.len()).copy_from_slice(&tuple);
+            }
+        }
+    }
+
+    /// Free the slot's tuple, the slot is kept so the slots after it keep their ids
+    pub fn remove(&mut self, slot_id: u32) {
+        self.slots[slot_id as usize] = Slot {
+            offset: PAGE_SIZE as u32,
+            len: 0,
+            meta: TupleMeta { deleted: true, ..Default::default() },
+        };
+        self.deleted_tuples_len += 1;
+    }
+
+    pub fn set_meta(&mut self, slot_id: u32, meta: &TupleMeta) {
+        self.slots[slot_id as usize].meta = *meta;
+    }
+
+    /// Replace the tuple in `slot_id`, written in place if it fits, otherwise into free space.
+    /// Returns false if there isn't enough room on the page.
+    pub fn replace(&mut self, slot_id: u32, tuple_data: &BytesMut, meta: &TupleMeta) -> bool {
+        let Slot { offset, len, .. } = self.slots[slot_id as usize];
+        let offset = if tuple_data.len() <= len as usize {
+            offset as usize
+        } else {
+            match self.free_offset(tuple_data.len(), self.len() as usize) {
+                Some(offset) => offset,
+                None => return false,
+            }
+        };
+
+        self.slots[slot_id as usize] =
+            Slot { offset: offset as u32, len: tuple_data.len() as u32, meta: *meta };
+
+        unsafe {
+            let tuples_ptr = self.page_start.add(offset);
+            let tuples = std::slice::from_raw_parts_mut(tuples_ptr, PAGE_SIZE - offset);
+            tuples[..tuple_data.len()].copy_from_slice(tuple_data);
+        }
+
+        true
+    }
+
     pub fn get(&self, r_id: &RId) -> Option<(TupleMeta, Tuple)> {
         let slot_id = r_id.slot_id;
         if slot_id > self.len() {
@@ -169,16 +354,8 @@ mod test {
             next_page_id: 10,
             deleted_tuples_len: 0,
             slots: vec![
-                Slot {
-                    offset: (PAGE_SIZE - 10) as u32,
-                    len: 10,
-                    meta: TupleMeta { deleted: false },
-                },
-                Slot {
-                    offset: (PAGE_SIZE - 25) as u32,
-                    len: 15,
-                    meta: TupleMeta { deleted: false },
-                },
+                Slot { offset: (PAGE_SIZE - 10) as u32, len: 10, meta: TupleMeta::default() },
+                Slot { offset: (PAGE_SIZE - 25) as u32, len: 15, meta: TupleMeta::default() },
             ],
         };
 
@@ -211,7 +388,7 @@ mod test {
             slots: Vec::new(),
         };
 
-        let meta = TupleMeta { deleted: false };
+        let meta = TupleMeta::default();
 
         let r_id_a = RId { page_id: 0, slot_id: 0 };
         let tuple_a = BytesMut::from(&std::array::from_fn::<u8, 10, _>(|i| (i * 2) as u8)[..]);
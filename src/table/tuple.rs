@@ -10,6 +10,8 @@ use crate::{
     catalog::{Column, Schema, Type},
     page::PageId,
     storable::Storable,
+    transaction::Snapshot,
+    wal::TxnId,
 };
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -82,7 +84,7 @@ impl std::fmt::Display for Value {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Hash)]
 pub struct RId {
     pub page_id: PageId,
     pub slot_id: u32,
@@ -90,22 +92,22 @@ pub struct RId {
 
 // TODO
 impl Storable for RId {
-    const SIZE: usize = 8;
+    const SIZE: usize = 12;
 
     type ByteArray = [u8; Self::SIZE];
 
     fn into_bytes(self) -> Self::ByteArray {
-        let mut ret = [0; 8];
-        ret[0..4].copy_from_slice(&self.page_id.into_bytes());
-        ret[4..8].copy_from_slice(&self.slot_id.into_bytes());
+        let mut ret = [0; 12];
+        ret[0..8].copy_from_slice(&self.page_id.into_bytes());
+        ret[8..12].copy_from_slice(&self.slot_id.into_bytes());
 
         ret
     }
 
     // TODO: this is reading the wrong bytes
     fn from_bytes(bytes: &[u8]) -> Self {
-        let page_id = i32::from_be_bytes(bytes[0..4].try_into().unwrap());
-        let slot_id = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        let page_id = PageId::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let slot_id = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
 
         Self { page_id, slot_id }
     }
@@ -218,16 +220,74 @@ impl Tuple {
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+const DELETED: u8 = 1 << 0;
+const VERSION: u8 = 1 << 1;
+
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
 pub struct TupleMeta {
     pub deleted: bool,
+    /// An older version of a row, only reachable through `prev`
+    pub version: bool,
+    /// Transaction that created this version
+    pub xmin: TxnId,
+    /// Transaction that deleted or replaced this version, 0 if it hasn't been
+    pub xmax: TxnId,
+    /// The version this one replaced
+    pub prev: Option<RId>,
+}
+
+/*
+    TupleMeta:
+    Flags (1) | XMin (8) | XMax (8) | Prev (12)
+*/
+
+const FLAGS: usize = 0;
+const XMIN: Range<usize> = 1..9;
+const XMAX: Range<usize> = 9..17;
+const PREV: Range<usize> = 17..TupleMeta::SIZE;
+
+impl TupleMeta {
+    pub const SIZE: usize = 29;
+
+    /// Whether this version is the one visible to `snapshot`, older versions have to be checked if
+    /// the row was changed by a transaction the snapshot can't see
+    pub fn visible(&self, snapshot: &Snapshot) -> bool {
+        !self.deleted && snapshot.sees(self.xmin) && (self.xmax == 0 || !snapshot.sees(self.xmax))
+    }
 }
 
 impl From<&[u8]> for TupleMeta {
     fn from(value: &[u8]) -> Self {
-        let deleted = u8::from_be_bytes(value[0..1].try_into().unwrap()) > 1;
+        let flags = value[FLAGS];
+        let xmin = TxnId::from_be_bytes(value[XMIN].try_into().unwrap());
+        let xmax = TxnId::from_be_bytes(value[XMAX].try_into().unwrap());
+        let prev = RId::from_bytes(&value[PREV]);
+
+        Self {
+            deleted: flags & DELETED != 0,
+            version: flags & VERSION != 0,
+            xmin,
+            xmax,
+            prev: (prev.page_id != -1).then_some(prev),
+        }
+    }
+}
+
+impl TupleMeta {
+    fn write_to(&self, dst: &mut [u8]) {
+        let mut flags = 0;
+        if self.deleted {
+            flags |= DELETED;
+        }
+        if self.version {
+            flags |= VERSION;
+        }
 
-        Self { deleted }
+        dst[FLAGS] = flags;
+        dst[XMIN].copy_from_slice(&self.xmin.to_be_bytes());
+        dst[XMAX].copy_from_slice(&self.xmax.to_be_bytes());
+        let prev = self.prev.unwrap_or(RId { page_id: -1, slot_id: 0 });
+        dst[PREV].copy_from_slice(&prev.into_bytes());
     }
 }
 
@@ -253,7 +313,7 @@ impl From<&[u8]> for Slot {
 }
 
 impl Slot {
-    pub const SIZE: usize = 9;
+    pub const SIZE: usize = 8 + TupleMeta::SIZE;
 }
 
 pub type TupleInfoBuf = [u8; Slot::SIZE];
@@ -263,7 +323,7 @@ impl From<&Slot> for TupleInfoBuf {
 
         ret[OFFSET].copy_from_slice(&value.offset.to_be_bytes());
         ret[LEN].copy_from_slice(&value.len.to_be_bytes());
-        ret[META].copy_from_slice(&[value.meta.deleted as u8]);
+        value.meta.write_to(&mut ret[META]);
 
         ret
     }
@@ -0,0 +1,604 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    disk::{Disk, FileSystem},
+    lock::LockManager,
+    page::{PageBuf, PageId, PageInner},
+    page_cache::{PageCacheError, SharedPageCache},
+    replacer::{Replacer, LRU},
+    table::node,
+    wal::{LogRecord, Lsn, TxnId, Wal},
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransactionState {
+    Running,
+    Committed,
+    Aborted,
+}
+
+type OnAbort = Box<dyn FnOnce(&Transaction) -> crate::Result<()> + Send>;
+
+struct TransactionInner {
+    state: TransactionState,
+    /// Last log record written by this transaction
+    prev_lsn: Lsn,
+    /// Pages to free once the transaction commits
+    deallocated: Vec<PageId>,
+    /// Run after the transaction's page changes have been undone, most recent first
+    on_abort: Vec<OnAbort>,
+}
+
+/// The transactions whose changes are visible to a reader. Aborted changes are undone in place, so
+/// a transaction that started before the snapshot and wasn't running at the time has committed.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    txn_id: TxnId,
+    /// Transactions from this one onwards began after the snapshot was taken
+    xmax: TxnId,
+    active: BTreeSet<TxnId>,
+    /// Set for snapshots taken outside of a transaction, holds back the horizon until dropped
+    _reader: Option<Arc<Reader>>,
+}
+
+/// Snapshots taken outside of a transaction, counted by their horizon
+type Readers = Arc<Mutex<BTreeMap<TxnId, usize>>>;
+
+#[derive(Debug)]
+struct Reader {
+    horizon: TxnId,
+    readers: Readers,
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut readers = self.readers.lock().expect("todo");
+        if let Some(count) = readers.get_mut(&self.horizon) {
+            *count -= 1;
+            if *count == 0 {
+                readers.remove(&self.horizon);
+            }
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn txn_id(&self) -> TxnId {
+        self.txn_id
+    }
+
+    /// Whether the changes made by `txn_id` are visible, 0 is used for changes made outside a
+    /// transaction
+    pub fn sees(&self, txn_id: TxnId) -> bool {
+        txn_id == 0
+            || txn_id == self.txn_id
+            || (txn_id < self.xmax && !self.active.contains(&txn_id))
+    }
+}
+
+pub struct Transaction {
+    id: TxnId,
+    inner: Arc<Mutex<TransactionInner>>,
+    snapshot: Snapshot,
+    wal: Arc<Wal>,
+}
+
+impl Transaction {
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.inner.lock().expect("todo").state
+    }
+
+    /// Log the change to the page so it can be undone, then apply it
+    pub fn write(&self, page: &mut PageInner, data: &PageBuf) {
+        let mut inner = self.inner.lock().expect("todo");
+        assert!(inner.state == TransactionState::Running, "transaction {} is not running", self.id);
+
+        inner.prev_lsn = self.wal.write(self.id, inner.prev_lsn, page, data);
+    }
+
+    /// Log the change to a heap page one slot at a time, so undoing it leaves slots changed by
+    /// other transactions alone. Anything else that changed, such as the link to the next page, is
+    /// kept if the transaction aborts.
+    pub fn write_heap(&self, page: &mut PageInner, data: &PageBuf) {
+        let mut inner = self.inner.lock().expect("todo");
+        assert!(inner.state == TransactionState::Running, "transaction {} is not running", self.id);
+
+        for slot_id in node::changed_slots(&page.data, data) {
+            let before = node::slot_image(&page.data, slot_id);
+            let mut step = page.data;
+            node::set_slot(&mut step, slot_id, &node::slot_image(data, slot_id));
+            inner.prev_lsn =
+                self.wal.write_slot(self.id, inner.prev_lsn, page, &step, slot_id, &before);
+        }
+
+        inner.prev_lsn = self.wal.compensate(self.id, inner.prev_lsn, page, data, inner.prev_lsn);
+    }
+
+    /// Log a change that is kept if the transaction aborts, such as removing versions no reader
+    /// can see
+    pub fn write_redo_only(&self, page: &mut PageInner, data: &PageBuf) {
+        let mut inner = self.inner.lock().expect("todo");
+        assert!(inner.state == TransactionState::Running, "transaction {} is not running", self.id);
+
+        inner.prev_lsn = self.wal.compensate(self.id, inner.prev_lsn, page, data, inner.prev_lsn);
+    }
+
+    /// Undo a change that can't be put back a page at a time if the transaction aborts, such as a
+    /// key added to an index page that other transactions have written to since. Changes made
+    /// through the transaction passed in are kept.
+    pub fn on_abort(&self, f: impl FnOnce(&Transaction) -> crate::Result<()> + Send + 'static) {
+        let mut inner = self.inner.lock().expect("todo");
+        assert!(inner.state == TransactionState::Running, "transaction {} is not running", self.id);
+
+        inner.on_abort.push(Box::new(f));
+    }
+
+    /// Free the page when the transaction commits, an abort could still need it
+    pub fn deallocate(&self, page_id: PageId) {
+        let mut inner = self.inner.lock().expect("todo");
+        assert!(inner.state == TransactionState::Running, "transaction {} is not running", self.id);
+
+        inner.deallocated.push(page_id);
+    }
+}
+
+struct Active {
+    /// The oldest transaction the snapshot can't see
+    horizon: TxnId,
+    /// The transaction's first record, the log can't be truncated past it
+    begin_lsn: Lsn,
+    inner: Arc<Mutex<TransactionInner>>,
+}
+
+pub struct TransactionManager<D: Disk = FileSystem, R: Replacer = LRU> {
+    pc: SharedPageCache<D, R>,
+    wal: Arc<Wal>,
+    lock_manager: Arc<LockManager>,
+    next_txn_id: AtomicU64,
+    active: Mutex<BTreeMap<TxnId, Active>>,
+    readers: Readers,
+}
+
+impl<D: Disk, R: Replacer> TransactionManager<D, R> {
+    pub fn new(pc: SharedPageCache<D, R>) -> crate::Result<Self> {
+        Self::new_with_lock_manager(pc, Arc::new(LockManager::default()))
+    }
+
+    pub fn new_with_lock_manager(
+        pc: SharedPageCache<D, R>,
+        lock_manager: Arc<LockManager>,
+    ) -> crate::Result<Self> {
+        let wal = pc.wal().expect("transactions require a write-ahead log").clone();
+
+        // Checkpoints hold the next id from before the log was truncated
+        let mut next_txn_id = 1;
+        for result in wal.iter() {
+            next_txn_id = match result? {
+                (_, LogRecord::Checkpoint { next_txn_id: id, .. }) => next_txn_id.max(id),
+                (_, record) => next_txn_id.max(record.txn_id() + 1),
+            };
+        }
+
+        Ok(Self {
+            pc,
+            wal,
+            lock_manager,
+            next_txn_id: AtomicU64::new(next_txn_id),
+            active: Mutex::new(BTreeMap::new()),
+            readers: Readers::default(),
+        })
+    }
+
+    pub fn lock_manager(&self) -> &Arc<LockManager> {
+        &self.lock_manager
+    }
+
+    pub fn begin(&self) -> Transaction {
+        // Allocate the id under the lock so the snapshot can't miss a transaction with a lower id
+        let mut active = self.active.lock().expect("todo");
+        let id = self.next_txn_id.fetch_add(1, Relaxed);
+        let snapshot = Snapshot {
+            txn_id: id,
+            xmax: id,
+            active: active.keys().copied().collect(),
+            _reader: None,
+        };
+
+        // A checkpoint can't see the transaction before it has logged something
+        let prev_lsn = self.wal.append(&LogRecord::Begin { txn_id: id });
+        let inner = Arc::new(Mutex::new(TransactionInner {
+            state: TransactionState::Running,
+            prev_lsn,
+            deallocated: Vec::new(),
+            on_abort: Vec::new(),
+        }));
+
+        let horizon = snapshot.active.first().copied().unwrap_or(id);
+        active.insert(id, Active { horizon, begin_lsn: prev_lsn, inner: inner.clone() });
+        drop(active);
+
+        Transaction { id, inner, snapshot, wal: self.wal.clone() }
+    }
+
+    /// A snapshot for reading outside of a transaction, it holds back the horizon until dropped
+    pub fn snapshot(&self) -> Snapshot {
+        // Register under the lock so a concurrent horizon can't miss the snapshot
+        let active = self.active.lock().expect("todo");
+        let xmax = self.next_txn_id.load(Relaxed);
+        let horizon = active.keys().next().copied().unwrap_or(xmax);
+        *self.readers.lock().expect("todo").entry(horizon).or_default() += 1;
+        let reader = Reader { horizon, readers: self.readers.clone() };
+
+        Snapshot {
+            txn_id: 0,
+            xmax,
+            active: active.keys().copied().collect(),
+            _reader: Some(Arc::new(reader)),
+        }
+    }
+
+    /// Changes made by transactions before the horizon are visible to every running transaction
+    /// and live snapshot, so versions they replaced can be removed
+    pub fn horizon(&self) -> TxnId {
+        let active = self.active.lock().expect("todo");
+        let readers = self.readers.lock().expect("todo");
+
+        active
+            .values()
+            .map(|a| a.horizon)
+            .chain(readers.keys().next().copied())
+            .min()
+            .unwrap_or_else(|| self.next_txn_id.load(Relaxed))
+    }
+
+    /// Write every dirty page, then log the running transactions so recovery can start from here
+    /// rather than the beginning of the log. The log before the redo LSN is truncated, apart from
+    /// the records of transactions that are still running. Returns the LSN of the checkpoint
+    /// record.
+    pub fn checkpoint(&self) -> crate::Result<Lsn> {
+        let redo_lsn = self.wal.begin_checkpoint();
+        self.pc.flush_all_pages()?;
+        self.pc.sync()?;
+
+        let active = self.active.lock().expect("todo");
+        let running: Vec<_> =
+            active.iter().map(|(id, a)| (*id, a.begin_lsn, a.inner.clone())).collect();
+        let next_txn_id = self.next_txn_id.load(Relaxed);
+        drop(active);
+
+        let mut keep = redo_lsn;
+        let txns = running
+            .into_iter()
+            .filter_map(|(txn_id, begin_lsn, inner)| {
+                let inner = inner.lock().expect("todo");
+                keep = keep.min(begin_lsn);
+                (inner.state == TransactionState::Running).then_some((txn_id, inner.prev_lsn))
+            })
+            .collect();
+
+        let lsn = self.wal.append(&LogRecord::Checkpoint {
+            redo_lsn,
+            next_txn_id,
+            next_page_id: self.pc.next_page_id(),
+            txns,
+        });
+        self.wal.flush(lsn)?;
+        self.wal.truncate(keep)?;
+
+        Ok(lsn)
+    }
+
+    pub fn commit(&self, txn: &Transaction) -> crate::Result<()> {
+        let mut inner = txn.inner.lock().expect("todo");
+        assert!(inner.state == TransactionState::Running);
+
+        let deallocated = std::mem::take(&mut inner.deallocated);
+        let lsn = self.wal.append(&LogRecord::Commit { txn_id: txn.id, prev_lsn: inner.prev_lsn });
+        self.wal.flush(lsn)?;
+        for page_id in deallocated {
+            self.pc.deallocate_page(page_id)?;
+        }
+        inner.prev_lsn = self.wal.append(&LogRecord::End { txn_id: txn.id, prev_lsn: lsn });
+        inner.state = TransactionState::Committed;
+        inner.on_abort.clear();
+        drop(inner);
+
+        self.active.lock().expect("todo").remove(&txn.id);
+
+        // Strict two phase locking, nothing is released until the transaction is over
+        self.lock_manager.release_all(txn.id);
+
+        Ok(())
+    }
+
+    /// Undo every change made by the transaction, most recent first
+    pub fn abort(&self, txn: &Transaction) -> crate::Result<()> {
+        let mut inner = txn.inner.lock().expect("todo");
+        assert!(inner.state == TransactionState::Running);
+
+        inner.deallocated.clear();
+
+        let mut lsn = inner.prev_lsn;
+        let mut last = self.wal.append(&LogRecord::Abort { txn_id: txn.id, prev_lsn: lsn });
+        while lsn != 0 {
+            let record = self
+                .wal
+                .read(lsn)?
+                .ok_or(PageCacheError::Disk(std::io::ErrorKind::UnexpectedEof))?;
+
+            lsn = match record {
+                LogRecord::Update { prev_lsn, page_id, offset, before, .. } => {
+                    let page = self.pc.fetch_page(page_id)?;
+                    let mut w = page.write();
+
+                    let mut data = w.data;
+                    let offset = offset as usize;
+                    data[offset..offset + before.len()].copy_from_slice(&before);
+                    last = self.wal.compensate(txn.id, last, &mut w, &data, prev_lsn);
+
+                    prev_lsn
+                }
+                LogRecord::HeapUpdate { prev_lsn, page_id, slot_id, before, .. } => {
+                    let page = self.pc.fetch_page(page_id)?;
+                    let mut w = page.write();
+
+                    let data = node::undo_slot(&w.data, slot_id, &before);
+                    last = self.wal.compensate(txn.id, last, &mut w, &data, prev_lsn);
+
+                    prev_lsn
+                }
+                LogRecord::Clr { undo_next, .. } => undo_next,
+                record => record.prev_lsn(),
+            };
+        }
+
+        // The transaction is still running for the changes that undo it by key
+        inner.prev_lsn = last;
+        let on_abort = std::mem::take(&mut inner.on_abort);
+        drop(inner);
+        for f in on_abort.into_iter().rev() {
+            f(txn)?;
+        }
+
+        let mut inner = txn.inner.lock().expect("todo");
+        let last = inner.prev_lsn;
+        inner.prev_lsn = self.wal.append(&LogRecord::End { txn_id: txn.id, prev_lsn: last });
+        inner.state = TransactionState::Aborted;
+        drop(inner);
+
+        // Only once every change has been undone can readers treat the transaction as finished
+        self.active.lock().expect("todo").remove(&txn.id);
+
+        self.lock_manager.release_all(txn.id);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use crate::{
+        btree::{slot::Either, BTree},
+        catalog::{Column, Schema, Type},
+        disk::Memory,
+        hash_table::extendible::ExtendibleHashTable,
+        page::PAGE_SIZE,
+        replacer::LRU,
+        table::{list::List, tuple::TupleMeta},
+        test::page_cache,
+        transaction::TransactionState,
+    };
+
+    #[test]
+    fn test_abort() -> crate::Result<()> {
+        const K: usize = 2;
+
+        let disk = Memory::default();
+        let (pc, tm) = page_cache(disk, LRU::new(K))?;
+
+        let list = List::default(pc.clone())?;
+        let schema = Schema::new(vec![Column { name: "".into(), ty: Type::Int, offset: 0 }]);
+        let mut btree = BTree::new(pc.clone(), &schema);
+        let ht_page = pc.new_page()?.id;
+        let ht = ExtendibleHashTable::new(ht_page, pc.clone());
+
+        let meta = TupleMeta::default();
+        let row = |i: u8| BytesMut::from(&[i; 16][..]);
+
+        let txn = tm.begin();
+        for i in 0..10 {
+            list.insert(&row(i), &meta, &txn)?;
+            btree.insert(&(i as i32).into(), &(i as i32), &txn)?;
+            ht.insert(&(i as i32), &(i as i32), &txn)?;
+        }
+        tm.commit(&txn)?;
+        assert_eq!(txn.state(), TransactionState::Committed);
+
+        let txn = tm.begin();
+        for i in 10..20 {
+            list.insert(&row(i), &meta, &txn)?;
+            btree.insert(&(i as i32).into(), &(i as i32), &txn)?;
+            ht.insert(&(i as i32), &(i as i32), &txn)?;
+        }
+        for i in 0..5 {
+            assert!(btree.delete(&i.into(), &txn)?);
+            assert!(ht.remove(&i, &i, &txn)?);
+        }
+        tm.abort(&txn)?;
+        assert_eq!(txn.state(), TransactionState::Aborted);
+
+        let have = list
+            .iter(&tm.snapshot())?
+            .map(|r| r.map(|(_, t)| t.data))
+            .collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!((0..10).map(row).collect::<Vec<_>>(), have);
+
+        let have = btree.scan()?;
+        assert_eq!((0..10).map(|i| (i.into(), i)).collect::<Vec<_>>(), have);
+
+        for i in 0..20 {
+            let want = if i < 10 { vec![i] } else { vec![] };
+            assert_eq!(want, ht.get(&i)?);
+
+            let want = if i < 10 { Some(Either::Value(i)) } else { None };
+            assert_eq!(want, btree.get(&i.into())?.map(|s| s.1));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort_concurrent() -> crate::Result<()> {
+        const K: usize = 2;
+
+        let (pc, tm) = page_cache(Memory::default(), LRU::new(K))?;
+        let list = List::default(pc.clone())?;
+
+        let meta = TupleMeta::default();
+        let row = |i: u8| BytesMut::from(&[i; 16][..]);
+        let rows = || -> crate::Result<Vec<_>> {
+            list.iter(&tm.snapshot())?.map(|r| r.map(|(_, t)| t.data)).collect()
+        };
+
+        let t = tm.begin();
+        let rid = list.insert(&row(0), &meta, &t)?.unwrap();
+        tm.commit(&t)?;
+
+        // Both write to the same page, undoing the first leaves the second's rows alone
+        let t1 = tm.begin();
+        let t2 = tm.begin();
+        list.insert(&row(1), &meta, &t1)?;
+        list.insert(&row(2), &meta, &t2)?;
+        assert!(list.update(rid, &row(3), &t1)?.is_some());
+        list.insert(&row(4), &meta, &t2)?;
+        tm.commit(&t2)?;
+        tm.abort(&t1)?;
+        assert_eq!(rows()?, vec![row(0), row(2), row(4)]);
+
+        let t3 = tm.begin();
+        list.insert(&row(5), &meta, &t3)?;
+        tm.commit(&t3)?;
+        assert_eq!(rows()?, vec![row(0), row(2), row(4), row(5)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort_new_pages() -> crate::Result<()> {
+        const K: usize = 2;
+
+        let (pc, tm) = page_cache(Memory::default(), LRU::new(K))?;
+
+        // An aborted insert that moved the list onto a new page
+        let list = List::default(pc.clone())?;
+        let meta = TupleMeta::default();
+        let row = |i: u8| BytesMut::from(&vec![i; PAGE_SIZE / 64][..]);
+        let rows = || -> crate::Result<Vec<_>> {
+            list.iter(&tm.snapshot())?.map(|r| r.map(|(_, t)| t.data)).collect()
+        };
+
+        let t = tm.begin();
+        let first = list.insert(&row(0), &meta, &t)?.unwrap();
+        tm.commit(&t)?;
+
+        let t = tm.begin();
+        let mut i = 1;
+        while list.insert(&row(i), &meta, &t)?.unwrap().page_id == first.page_id {
+            i += 1;
+        }
+        tm.abort(&t)?;
+
+        let t = tm.begin();
+        list.insert(&row(1), &meta, &t)?;
+        tm.commit(&t)?;
+        assert_eq!(rows()?, vec![row(0), row(1)]);
+
+        // An aborted insert that created the root, then one that split it
+        let schema = Schema::new(vec![Column { name: "".into(), ty: Type::Int, offset: 0 }]);
+        let mut btree = BTree::new(pc.clone(), &schema);
+
+        let t = tm.begin();
+        btree.insert(&0.into(), &0, &t)?;
+        tm.abort(&t)?;
+        assert_eq!(btree.scan()?, vec![]);
+
+        let t = tm.begin();
+        btree.insert(&0.into(), &0, &t)?;
+        tm.commit(&t)?;
+
+        let root = btree.root();
+        let t = tm.begin();
+        let mut i = 1;
+        while btree.root() == root {
+            btree.insert(&i.into(), &i, &t)?;
+            i += 1;
+        }
+        tm.abort(&t)?;
+
+        // The split is kept, only the keys are taken out
+        assert_ne!(btree.root(), root);
+        assert_eq!(btree.scan()?, vec![(0.into(), 0)]);
+
+        let t = tm.begin();
+        btree.insert(&1.into(), &1, &t)?;
+        tm.commit(&t)?;
+        assert_eq!(btree.scan()?, vec![(0.into(), 0), (1.into(), 1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort_index_concurrent() -> crate::Result<()> {
+        const K: usize = 2;
+
+        let (pc, tm) = page_cache(Memory::default(), LRU::new(K))?;
+
+        let schema = Schema::new(vec![Column { name: "".into(), ty: Type::Int, offset: 0 }]);
+        let mut btree = BTree::new(pc.clone(), &schema);
+        let ht_page = pc.new_page()?.id;
+        let ht = ExtendibleHashTable::new(ht_page, pc.clone());
+
+        let t = tm.begin();
+        btree.insert(&0.into(), &0, &t)?;
+        ht.insert(&0, &0, &t)?;
+        tm.commit(&t)?;
+
+        // Both write to the same leaf and bucket, undoing the first leaves the second's keys alone
+        let t1 = tm.begin();
+        let t2 = tm.begin();
+        for i in 1..10 {
+            let txn = if i % 2 == 1 { &t1 } else { &t2 };
+            btree.insert(&i.into(), &i, txn)?;
+            ht.insert(&i, &i, txn)?;
+        }
+        assert!(btree.delete(&0.into(), &t1)?);
+        assert!(ht.remove(&0, &0, &t1)?);
+        tm.commit(&t2)?;
+        tm.abort(&t1)?;
+
+        let want = [0, 2, 4, 6, 8];
+        assert_eq!(btree.scan()?, want.iter().map(|i| ((*i).into(), *i)).collect::<Vec<_>>());
+        for i in 0..10 {
+            let want = if want.contains(&i) { vec![i] } else { vec![] };
+            assert_eq!(ht.get(&i)?, want);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,957 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use nix::sys::uio;
+
+use crate::{
+    disk::Durability,
+    page::{PageBuf, PageId, PageInner, RelationId, PAGE_HEADER_SIZE},
+};
+
+pub type Lsn = u64;
+pub type TxnId = u64;
+
+/// Every log starts with this header, so no record is ever written at LSN 0 and a page with an LSN
+/// of 0 has never been logged
+const LOG_MAGIC: &[u8; 8] = b"BASELOG\x01";
+
+/*
+    Magic (8) | Start (8) | Record*
+
+    Start is the LSN of the first record. Truncating the front of the log moves it forward, so a
+    record's LSN doesn't change.
+*/
+const LOG_HEADER_SIZE: usize = 8 + 8;
+
+fn log_header(start: Lsn) -> [u8; LOG_HEADER_SIZE] {
+    let mut ret = [0; LOG_HEADER_SIZE];
+    ret[..8].copy_from_slice(LOG_MAGIC);
+    ret[8..].copy_from_slice(&start.to_be_bytes());
+
+    ret
+}
+
+pub trait LogStore: Send + Sync {
+    fn append(&self, buf: &[u8]) -> io::Result<()>;
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    fn size(&self) -> io::Result<u64>;
+    fn truncate(&self, len: u64) -> io::Result<()>;
+    /// Replace everything in the store with `buf` in one step, a crash leaves either the old or
+    /// the new contents
+    fn replace(&self, buf: &[u8]) -> io::Result<()>;
+    fn sync(&self) -> io::Result<()>;
+}
+
+impl<L: LogStore> LogStore for Arc<L> {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        (**self).append(buf)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        (**self).truncate(len)
+    }
+
+    fn replace(&self, buf: &[u8]) -> io::Result<()> {
+        (**self).replace(buf)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        (**self).sync()
+    }
+}
+
+pub struct LogFile {
+    path: PathBuf,
+    /// Swapped for a new file when the log is replaced
+    file: Mutex<File>,
+    durability: Durability,
+}
+
+impl LogFile {
+    pub fn new(file: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_durability(file, Durability::default())
+    }
+
+    /// How the log is synced when a transaction commits
+    pub fn with_durability(file: impl AsRef<Path>, durability: Durability) -> io::Result<Self> {
+        let path = file.as_ref().to_path_buf();
+        let file = Mutex::new(Self::open(&path)?);
+
+        Ok(Self { path, file, durability })
+    }
+
+    fn open(path: &Path) -> io::Result<File> {
+        OpenOptions::new().read(true).append(true).create(true).open(path)
+    }
+}
+
+impl LogStore for LogFile {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.file.lock().expect("todo").write_all(buf)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.file.lock().expect("todo");
+        Ok(uio::pread(file.as_raw_fd(), buf, offset as i64)?)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.lock().expect("todo").metadata()?.len())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.file.lock().expect("todo").set_len(len)
+    }
+
+    fn replace(&self, buf: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().expect("todo");
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut new = File::create(&tmp)?;
+        new.write_all(buf)?;
+        self.durability.sync(&new)?;
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            self.durability.sync(&File::open(dir)?)?;
+        }
+
+        *file = Self::open(&self.path)?;
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.durability.sync(&self.file.lock().expect("todo"))
+    }
+}
+
+#[derive(Default)]
+pub struct LogMemory {
+    buf: Mutex<Vec<u8>>,
+}
+
+impl LogStore for LogMemory {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.buf.lock().expect("todo").extend_from_slice(buf);
+
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let log = self.buf.lock().expect("todo");
+        let offset = (offset as usize).min(log.len());
+        let len = buf.len().min(log.len() - offset);
+        buf[..len].copy_from_slice(&log[offset..offset + len]);
+
+        Ok(len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.buf.lock().expect("todo").len() as u64)
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.buf.lock().expect("todo").truncate(len as usize);
+
+        Ok(())
+    }
+
+    fn replace(&self, buf: &[u8]) -> io::Result<()> {
+        *self.buf.lock().expect("todo") = buf.to_vec();
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LogRecord {
+    Begin {
+        txn_id: TxnId,
+    },
+    Commit {
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+    },
+    Abort {
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+    },
+    Update {
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+        page_id: PageId,
+        offset: u16,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    /// Compensation log record, written when an update is undone. Redo only, `undo_next` is the
+    /// next record of the transaction that still has to be undone.
+    Clr {
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+        page_id: PageId,
+        offset: u16,
+        after: Vec<u8>,
+        undo_next: Lsn,
+    },
+    /// The transaction is finished, nothing of it has to be undone
+    End {
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+    },
+    /// Every change logged before `redo_lsn` is on disk. `txns` holds the transactions that were
+    /// running after `redo_lsn` and the last record each had written by then. The next ids are
+    /// kept as the records they were found from can be truncated.
+    Checkpoint {
+        redo_lsn: Lsn,
+        next_txn_id: TxnId,
+        next_page_id: PageId,
+        txns: Vec<(TxnId, Lsn)>,
+    },
+    /// Every page of the relation was discarded and its storage removed
+    Drop {
+        relation: RelationId,
+    },
+    /// A change to one slot of a heap page. Redone like an update, but undone by putting back only
+    /// the slot and its tuple, as other transactions can have changed the rest of the page since.
+    /// `before` is empty if the slot was added.
+    HeapUpdate {
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+        page_id: PageId,
+        offset: u16,
+        after: Vec<u8>,
+        slot_id: u32,
+        before: Vec<u8>,
+    },
+    /// A change made outside of any transaction, such as to the free space map or an image of a
+    /// page. Redone but never undone.
+    Redo {
+        page_id: PageId,
+        offset: u16,
+        after: Vec<u8>,
+    },
+}
+
+const BEGIN: u8 = 1;
+const COMMIT: u8 = 2;
+const ABORT: u8 = 3;
+const UPDATE: u8 = 4;
+const CLR: u8 = 5;
+const END: u8 = 6;
+const HEAP_UPDATE: u8 = 7;
+const CHECKPOINT: u8 = 8;
+const DROP: u8 = 9;
+const REDO: u8 = 10;
+
+// | Len (4) | Type (1) | TxnId (8) | PrevLsn (8) | Body
+// Update body: | PageId (8) | Offset (2) | Len (2) | Before | After
+// Clr body: | PageId (8) | Offset (2) | Len (2) | UndoNext (8) | After
+// Checkpoint body: | RedoLsn (8) | NextTxnId (8) | NextPageId (8) | Count (4)
+//                  | (TxnId (8) | LastLsn (8))*
+// Drop body: | Relation (4)
+// HeapUpdate body: | PageId (8) | Offset (2) | Len (2) | SlotId (4) | BeforeLen (2) | After
+//                  | Before
+// Redo body: | PageId (8) | Offset (2) | Len (2) | After
+const RECORD_HEADER_SIZE: usize = 4 + 1 + 8 + 8;
+
+impl LogRecord {
+    /// The whole page, logged before its first change after a checkpoint so a torn write can be
+    /// repaired. It is never undone. A page that is still all zeros doesn't need one, recovery
+    /// rebuilds a torn page starting from zeros.
+    pub fn image(page_id: PageId, data: &PageBuf) -> Option<Self> {
+        let after = &data[PAGE_HEADER_SIZE..];
+        if after.iter().all(|b| *b == 0) {
+            return None;
+        }
+
+        Some(LogRecord::Redo { page_id, offset: PAGE_HEADER_SIZE as u16, after: after.to_vec() })
+    }
+
+    pub fn txn_id(&self) -> TxnId {
+        match self {
+            LogRecord::Begin { txn_id }
+            | LogRecord::Commit { txn_id, .. }
+            | LogRecord::Abort { txn_id, .. }
+            | LogRecord::Update { txn_id, .. }
+            | LogRecord::Clr { txn_id, .. }
+            | LogRecord::End { txn_id, .. }
+            | LogRecord::HeapUpdate { txn_id, .. } => *txn_id,
+            LogRecord::Checkpoint { .. } | LogRecord::Drop { .. } | LogRecord::Redo { .. } => 0,
+        }
+    }
+
+    pub fn prev_lsn(&self) -> Lsn {
+        match self {
+            LogRecord::Begin { .. }
+            | LogRecord::Checkpoint { .. }
+            | LogRecord::Drop { .. }
+            | LogRecord::Redo { .. } => 0,
+            LogRecord::Commit { prev_lsn, .. }
+            | LogRecord::Abort { prev_lsn, .. }
+            | LogRecord::Update { prev_lsn, .. }
+            | LogRecord::Clr { prev_lsn, .. }
+            | LogRecord::End { prev_lsn, .. }
+            | LogRecord::HeapUpdate { prev_lsn, .. } => *prev_lsn,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            LogRecord::Update { before, after, .. } => {
+                RECORD_HEADER_SIZE + 12 + before.len() + after.len()
+            }
+            LogRecord::Clr { after, .. } => RECORD_HEADER_SIZE + 20 + after.len(),
+            LogRecord::Checkpoint { txns, .. } => RECORD_HEADER_SIZE + 28 + txns.len() * 16,
+            LogRecord::Drop { .. } => RECORD_HEADER_SIZE + 4,
+            LogRecord::HeapUpdate { after, before, .. } => {
+                RECORD_HEADER_SIZE + 18 + after.len() + before.len()
+            }
+            LogRecord::Redo { after, .. } => RECORD_HEADER_SIZE + 12 + after.len(),
+            _ => RECORD_HEADER_SIZE,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.size());
+        ret.resize(RECORD_HEADER_SIZE, 0);
+        let t = match self {
+            LogRecord::Begin { .. } => BEGIN,
+            LogRecord::Commit { .. } => COMMIT,
+            LogRecord::Abort { .. } => ABORT,
+            LogRecord::Update { page_id, offset, before, after, .. } => {
+                assert!(before.len() == after.len());
+
+                ret.extend_from_slice(&page_id.to_be_bytes());
+                ret.extend_from_slice(&offset.to_be_bytes());
+                ret.extend_from_slice(&(before.len() as u16).to_be_bytes());
+                ret.extend_from_slice(before);
+                ret.extend_from_slice(after);
+                UPDATE
+            }
+            LogRecord::Clr { page_id, offset, after, undo_next, .. } => {
+                ret.extend_from_slice(&page_id.to_be_bytes());
+                ret.extend_from_slice(&offset.to_be_bytes());
+                ret.extend_from_slice(&(after.len() as u16).to_be_bytes());
+                ret.extend_from_slice(&undo_next.to_be_bytes());
+                ret.extend_from_slice(after);
+                CLR
+            }
+            LogRecord::End { .. } => END,
+            LogRecord::Checkpoint { redo_lsn, next_txn_id, next_page_id, txns } => {
+                ret.extend_from_slice(&redo_lsn.to_be_bytes());
+                ret.extend_from_slice(&next_txn_id.to_be_bytes());
+                ret.extend_from_slice(&next_page_id.to_be_bytes());
+                ret.extend_from_slice(&(txns.len() as u32).to_be_bytes());
+                for (txn_id, last_lsn) in txns {
+                    ret.extend_from_slice(&txn_id.to_be_bytes());
+                    ret.extend_from_slice(&last_lsn.to_be_bytes());
+                }
+                CHECKPOINT
+            }
+            LogRecord::Drop { relation } => {
+                ret.extend_from_slice(&relation.to_be_bytes());
+                DROP
+            }
+            LogRecord::HeapUpdate { page_id, offset, after, slot_id, before, .. } => {
+                ret.extend_from_slice(&page_id.to_be_bytes());
+                ret.extend_from_slice(&offset.to_be_bytes());
+                ret.extend_from_slice(&(after.len() as u16).to_be_bytes());
+                ret.extend_from_slice(&slot_id.to_be_bytes());
+                ret.extend_from_slice(&(before.len() as u16).to_be_bytes());
+                ret.extend_from_slice(after);
+                ret.extend_from_slice(before);
+                HEAP_UPDATE
+            }
+            LogRecord::Redo { page_id, offset, after } => {
+                ret.extend_from_slice(&page_id.to_be_bytes());
+                ret.extend_from_slice(&offset.to_be_bytes());
+                ret.extend_from_slice(&(after.len() as u16).to_be_bytes());
+                ret.extend_from_slice(after);
+                REDO
+            }
+        };
+
+        let len = ret.len() as u32;
+        ret[0..4].copy_from_slice(&len.to_be_bytes());
+        ret[4] = t;
+        ret[5..13].copy_from_slice(&self.txn_id().to_be_bytes());
+        ret[13..21].copy_from_slice(&self.prev_lsn().to_be_bytes());
+
+        ret
+    }
+
+    /// `buf` must hold exactly one record, including its length prefix
+    fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed log record");
+
+        if buf.len() < RECORD_HEADER_SIZE {
+            return Err(invalid());
+        }
+
+        let txn_id = TxnId::from_be_bytes(buf[5..13].try_into().unwrap());
+        let prev_lsn = Lsn::from_be_bytes(buf[13..21].try_into().unwrap());
+        let body = &buf[RECORD_HEADER_SIZE..];
+
+        let record = match buf[4] {
+            BEGIN => LogRecord::Begin { txn_id },
+            COMMIT => LogRecord::Commit { txn_id, prev_lsn },
+            ABORT => LogRecord::Abort { txn_id, prev_lsn },
+            UPDATE => {
+                if body.len() < 12 {
+                    return Err(invalid());
+                }
+
+                let page_id = PageId::from_be_bytes(body[0..8].try_into().unwrap());
+                let offset = u16::from_be_bytes(body[8..10].try_into().unwrap());
+                let len = u16::from_be_bytes(body[10..12].try_into().unwrap()) as usize;
+                if body.len() != 12 + len * 2 {
+                    return Err(invalid());
+                }
+
+                let before = body[12..12 + len].to_vec();
+                let after = body[12 + len..].to_vec();
+
+                LogRecord::Update { txn_id, prev_lsn, page_id, offset, before, after }
+            }
+            CLR => {
+                if body.len() < 20 {
+                    return Err(invalid());
+                }
+
+                let page_id = PageId::from_be_bytes(body[0..8].try_into().unwrap());
+                let offset = u16::from_be_bytes(body[8..10].try_into().unwrap());
+                let len = u16::from_be_bytes(body[10..12].try_into().unwrap()) as usize;
+                let undo_next = Lsn::from_be_bytes(body[12..20].try_into().unwrap());
+                if body.len() != 20 + len {
+                    return Err(invalid());
+                }
+
+                let after = body[20..].to_vec();
+
+                LogRecord::Clr { txn_id, prev_lsn, page_id, offset, after, undo_next }
+            }
+            END => LogRecord::End { txn_id, prev_lsn },
+            CHECKPOINT => {
+                if body.len() < 28 {
+                    return Err(invalid());
+                }
+
+                let redo_lsn = Lsn::from_be_bytes(body[0..8].try_into().unwrap());
+                let next_txn_id = TxnId::from_be_bytes(body[8..16].try_into().unwrap());
+                let next_page_id = PageId::from_be_bytes(body[16..24].try_into().unwrap());
+                let count = u32::from_be_bytes(body[24..28].try_into().unwrap()) as usize;
+                if body.len() != 28 + count * 16 {
+                    return Err(invalid());
+                }
+
+                let txns = body[28..]
+                    .chunks_exact(16)
+                    .map(|c| {
+                        let txn_id = TxnId::from_be_bytes(c[0..8].try_into().unwrap());
+                        let last_lsn = Lsn::from_be_bytes(c[8..16].try_into().unwrap());
+
+                        (txn_id, last_lsn)
+                    })
+                    .collect();
+
+                LogRecord::Checkpoint { redo_lsn, next_txn_id, next_page_id, txns }
+            }
+            DROP => {
+                if body.len() != 4 {
+                    return Err(invalid());
+                }
+
+                LogRecord::Drop { relation: RelationId::from_be_bytes(body.try_into().unwrap()) }
+            }
+            HEAP_UPDATE => {
+                if body.len() < 18 {
+                    return Err(invalid());
+                }
+
+                let page_id = PageId::from_be_bytes(body[0..8].try_into().unwrap());
+                let offset = u16::from_be_bytes(body[8..10].try_into().unwrap());
+                let len = u16::from_be_bytes(body[10..12].try_into().unwrap()) as usize;
+                let slot_id = u32::from_be_bytes(body[12..16].try_into().unwrap());
+                let before_len = u16::from_be_bytes(body[16..18].try_into().unwrap()) as usize;
+                if body.len() != 18 + len + before_len {
+                    return Err(invalid());
+                }
+
+                let after = body[18..18 + len].to_vec();
+                let before = body[18 + len..].to_vec();
+
+                LogRecord::HeapUpdate { txn_id, prev_lsn, page_id, offset, after, slot_id, before }
+            }
+            REDO => {
+                if body.len() < 12 {
+                    return Err(invalid());
+                }
+
+                let page_id = PageId::from_be_bytes(body[0..8].try_into().unwrap());
+                let offset = u16::from_be_bytes(body[8..10].try_into().unwrap());
+                let len = u16::from_be_bytes(body[10..12].try_into().unwrap()) as usize;
+                if body.len() != 12 + len {
+                    return Err(invalid());
+                }
+
+                LogRecord::Redo { page_id, offset, after: body[12..].to_vec() }
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok(record)
+    }
+}
+
+struct Inner {
+    /// Records appended but not yet written to the store
+    buf: Vec<u8>,
+    /// Everything before this LSN is durable
+    flushed: Lsn,
+    /// The first record in the log, everything before it was truncated
+    start: Lsn,
+    /// Pages last changed before this log an image on their next change
+    redo_lsn: Lsn,
+}
+
+impl Inner {
+    /// Where the record at `lsn` is in the store
+    fn offset(&self, lsn: Lsn) -> u64 {
+        lsn - self.start + LOG_HEADER_SIZE as u64
+    }
+
+    fn append(&mut self, record: &LogRecord) -> Lsn {
+        let lsn = self.flushed + self.buf.len() as Lsn;
+        self.buf.extend_from_slice(&record.to_bytes());
+
+        lsn
+    }
+}
+
+/// Append-only write-ahead log. A record's LSN is its byte offset in the log, counting the bytes
+/// truncated from the front.
+pub struct Wal {
+    inner: Mutex<Inner>,
+    store: Box<dyn LogStore>,
+}
+
+impl Wal {
+    pub fn new(store: impl LogStore + 'static) -> io::Result<Arc<Self>> {
+        let mut len = store.size()?;
+        if len == 0 {
+            store.append(&log_header(LOG_HEADER_SIZE as Lsn))?;
+            store.sync()?;
+            len = LOG_HEADER_SIZE as u64;
+        }
+
+        let mut header = [0; LOG_HEADER_SIZE];
+        if store.read_at(0, &mut header)? < LOG_HEADER_SIZE || &header[..8] != LOG_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a log file"));
+        }
+
+        let start = Lsn::from_be_bytes(header[8..].try_into().unwrap());
+        let flushed = start + len - LOG_HEADER_SIZE as u64;
+        let inner = Mutex::new(Inner { buf: Vec::new(), flushed, start, redo_lsn: flushed });
+        let wal = Self { inner, store: Box::new(store) };
+
+        let mut end = start;
+        for result in wal.iter() {
+            let (lsn, record) = result?;
+            end = lsn + record.size() as Lsn;
+        }
+
+        // A crash during a flush can leave a torn record at the end of the log, drop it
+        if end != flushed {
+            let mut inner = wal.inner.lock().expect("todo");
+            wal.store.truncate(inner.offset(end))?;
+            inner.flushed = end;
+            inner.redo_lsn = end;
+        }
+
+        Ok(Arc::new(wal))
+    }
+
+    pub fn append(&self, record: &LogRecord) -> Lsn {
+        self.inner.lock().expect("todo").append(record)
+    }
+
+    /// Make every record up to and including `lsn` durable
+    pub fn flush(&self, lsn: Lsn) -> io::Result<()> {
+        let mut inner = self.inner.lock().expect("todo");
+        if lsn < inner.flushed || inner.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.store.append(&inner.buf)?;
+        self.store.sync()?;
+        inner.flushed += inner.buf.len() as Lsn;
+        inner.buf.clear();
+
+        Ok(())
+    }
+
+    pub fn flushed_lsn(&self) -> Lsn {
+        self.inner.lock().expect("todo").flushed
+    }
+
+    pub fn next_lsn(&self) -> Lsn {
+        let inner = self.inner.lock().expect("todo");
+        inner.flushed + inner.buf.len() as Lsn
+    }
+
+    /// Discard every record before `lsn`, which has to be the start of a flushed record. The LSNs
+    /// of the records kept don't change.
+    pub fn truncate(&self, lsn: Lsn) -> io::Result<()> {
+        let mut inner = self.inner.lock().expect("todo");
+        assert!(lsn <= inner.flushed, "only flushed records can be truncated");
+        if lsn <= inner.start {
+            return Ok(());
+        }
+
+        let mut buf = vec![0; LOG_HEADER_SIZE + (inner.flushed - lsn) as usize];
+        buf[..LOG_HEADER_SIZE].copy_from_slice(&log_header(lsn));
+        self.store.read_at(inner.offset(lsn), &mut buf[LOG_HEADER_SIZE..])?;
+        self.store.replace(&buf)?;
+        inner.start = lsn;
+
+        Ok(())
+    }
+
+    /// Start a checkpoint, returns its redo LSN. Every page changed from here logs an image first.
+    pub fn begin_checkpoint(&self) -> Lsn {
+        let mut inner = self.inner.lock().expect("todo");
+        inner.redo_lsn = inner.flushed + inner.buf.len() as Lsn;
+
+        inner.redo_lsn
+    }
+
+    /// Read the record at `lsn`, returns `None` if there is no complete record there
+    pub fn read(&self, lsn: Lsn) -> io::Result<Option<LogRecord>> {
+        let inner = self.inner.lock().expect("todo");
+
+        let mut len = [0; 4];
+        if self.read_at(&inner, lsn, &mut len)? < len.len() {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len < RECORD_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut buf = vec![0; len];
+        if self.read_at(&inner, lsn, &mut buf)? < len {
+            return Ok(None);
+        }
+
+        LogRecord::from_bytes(&buf).map(Some)
+    }
+
+    fn read_at(&self, inner: &Inner, lsn: Lsn, buf: &mut [u8]) -> io::Result<usize> {
+        if lsn >= inner.flushed {
+            let offset = ((lsn - inner.flushed) as usize).min(inner.buf.len());
+            let len = buf.len().min(inner.buf.len() - offset);
+            buf[..len].copy_from_slice(&inner.buf[offset..offset + len]);
+
+            return Ok(len);
+        }
+
+        if lsn < inner.start {
+            return Ok(0);
+        }
+
+        // Records are flushed whole so a record never spans the store and the buffer
+        self.store.read_at(inner.offset(lsn), buf)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        let start = self.inner.lock().expect("todo").start;
+        self.iter_from(start)
+    }
+
+    /// `lsn` has to be the start of a record
+    pub fn iter_from(&self, lsn: Lsn) -> Iter<'_> {
+        Iter { wal: self, lsn }
+    }
+
+    /// Log the change from the page's current contents to `data`, then apply it. Only the range
+    /// of bytes that differ is logged. Returns `prev_lsn` if nothing changed.
+    pub fn write(&self, txn_id: TxnId, prev_lsn: Lsn, page: &mut PageInner, data: &PageBuf) -> Lsn {
+        let page_id = page.id;
+        self.write_with(page, data, |offset, before, after| LogRecord::Update {
+            txn_id,
+            prev_lsn,
+            page_id,
+            offset,
+            before,
+            after,
+        })
+        .unwrap_or(prev_lsn)
+    }
+
+    /// Like `write`, for a change to one slot of a heap page. `before` is the slot and its tuple
+    /// before the change, see `LogRecord::HeapUpdate`.
+    pub fn write_slot(
+        &self,
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+        page: &mut PageInner,
+        data: &PageBuf,
+        slot_id: u32,
+        before: &[u8],
+    ) -> Lsn {
+        let page_id = page.id;
+        self.write_with(page, data, |offset, _, after| LogRecord::HeapUpdate {
+            txn_id,
+            prev_lsn,
+            page_id,
+            offset,
+            after,
+            slot_id,
+            before: before.to_vec(),
+        })
+        .unwrap_or(prev_lsn)
+    }
+
+    /// Like `write`, but the change is logged as a compensation record, so it is never undone.
+    /// Undo carries on from `undo_next`.
+    pub fn compensate(
+        &self,
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+        page: &mut PageInner,
+        data: &PageBuf,
+        undo_next: Lsn,
+    ) -> Lsn {
+        let page_id = page.id;
+        self.write_with(page, data, |offset, _, after| LogRecord::Clr {
+            txn_id,
+            prev_lsn,
+            page_id,
+            offset,
+            after,
+            undo_next,
+        })
+        .unwrap_or(prev_lsn)
+    }
+
+    /// Like `write`, for a change made outside of any transaction. Returns the page's LSN if nothing
+    /// changed.
+    pub fn write_redo(&self, page: &mut PageInner, data: &PageBuf) -> Lsn {
+        let page_id = page.id;
+        self.write_with(page, data, |offset, _, after| LogRecord::Redo { page_id, offset, after })
+            .unwrap_or(page.lsn)
+    }
+
+    fn write_with(
+        &self,
+        page: &mut PageInner,
+        data: &PageBuf,
+        record: impl FnOnce(u16, Vec<u8>, Vec<u8>) -> LogRecord,
+    ) -> Option<Lsn> {
+        let (start, end) = diff(&page.data, data)?;
+
+        // Checked under the lock so a checkpoint can't start between the image and the change
+        let mut inner = self.inner.lock().expect("todo");
+        if page.lsn < inner.redo_lsn {
+            if let Some(image) = LogRecord::image(page.id, &page.data) {
+                inner.append(&image);
+            }
+        }
+        let lsn = inner.append(&record(
+            start as u16,
+            page.data[start..end].to_vec(),
+            data[start..end].to_vec(),
+        ));
+        drop(inner);
+
+        page.data[start..end].copy_from_slice(&data[start..end]);
+        page.dirty = true;
+        page.lsn = lsn;
+
+        Some(lsn)
+    }
+}
+
+/// The range of bytes that differ between two versions of a page, the header is stamped by the
+/// page cache so it is never included
+pub fn diff(old: &PageBuf, new: &PageBuf) -> Option<(usize, usize)> {
+    let differs = |(a, b): (&u8, &u8)| a != b;
+    let pairs = || old.iter().zip(new.iter()).skip(PAGE_HEADER_SIZE);
+    let start = pairs().position(differs)? + PAGE_HEADER_SIZE;
+    let end = pairs().rposition(differs).unwrap() + PAGE_HEADER_SIZE + 1;
+
+    Some((start, end))
+}
+
+pub struct Iter<'a> {
+    wal: &'a Wal,
+    lsn: Lsn,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = io::Result<(Lsn, LogRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.wal.read(self.lsn) {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let lsn = self.lsn;
+        self.lsn += record.size() as Lsn;
+
+        Some(Ok((lsn, record)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{
+        page::{PageInner, PAGE_SIZE},
+        test::CleanUp,
+        wal::{LogFile, LogMemory, LogRecord, LogStore, Wal},
+    };
+
+    #[test]
+    fn test_wal() -> std::io::Result<()> {
+        let store = Arc::new(LogMemory::default());
+        let wal = Wal::new(store.clone())?;
+
+        let mut page = PageInner { id: 3, ..Default::default() };
+        let mut data = [0; PAGE_SIZE];
+        data[100..105].copy_from_slice(b"hello");
+
+        let begin = wal.append(&LogRecord::Begin { txn_id: 1 });
+        let update = wal.write(1, begin, &mut page, &data);
+        let commit = wal.append(&LogRecord::Commit { txn_id: 1, prev_lsn: update });
+        let checkpoint_record = LogRecord::Checkpoint {
+            redo_lsn: update,
+            next_txn_id: 4,
+            next_page_id: 6,
+            txns: vec![(2, 8), (3, 9)],
+        };
+        let checkpoint = wal.append(&checkpoint_record);
+        let dropped = wal.append(&LogRecord::Drop { relation: 4 });
+        let heap = LogRecord::HeapUpdate {
+            txn_id: 2,
+            prev_lsn: 8,
+            page_id: 5,
+            offset: 40,
+            after: b"after".to_vec(),
+            slot_id: 1,
+            before: b"before".to_vec(),
+        };
+        let heap_update = wal.append(&heap);
+        let redo_record = LogRecord::Redo { page_id: 6, offset: 20, after: b"redo".to_vec() };
+        let redo = wal.append(&redo_record);
+
+        assert!(page.dirty);
+        assert_eq!(page.lsn, update);
+        assert_eq!(page.data, data);
+
+        // Nothing reaches the store until flushed
+        assert_eq!(store.size()?, 16);
+        wal.flush(redo)?;
+        assert_eq!(wal.flushed_lsn(), wal.next_lsn());
+
+        let want = vec![
+            (begin, LogRecord::Begin { txn_id: 1 }),
+            (
+                update,
+                LogRecord::Update {
+                    txn_id: 1,
+                    prev_lsn: begin,
+                    page_id: 3,
+                    offset: 100,
+                    before: vec![0; 5],
+                    after: b"hello".to_vec(),
+                },
+            ),
+            (commit, LogRecord::Commit { txn_id: 1, prev_lsn: update }),
+            (checkpoint, checkpoint_record),
+            (dropped, LogRecord::Drop { relation: 4 }),
+            (heap_update, heap),
+            (redo, redo_record),
+        ];
+
+        // Read back after reopening
+        drop(wal);
+        let wal = Wal::new(store)?;
+        let have = wal.iter().collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_unflushed() -> std::io::Result<()> {
+        let store = Arc::new(LogMemory::default());
+        let wal = Wal::new(store.clone())?;
+
+        let flushed = wal.append(&LogRecord::Begin { txn_id: 1 });
+        wal.flush(flushed)?;
+        wal.append(&LogRecord::Begin { txn_id: 2 });
+
+        // Records that were never flushed are lost
+        drop(wal);
+        let wal = Wal::new(store)?;
+        let have = wal.iter().collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(vec![(flushed, LogRecord::Begin { txn_id: 1 })], have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_truncate() -> std::io::Result<()> {
+        const FILE: &str = "test_wal_truncate.log";
+        let _cleanup = CleanUp::file(FILE);
+
+        let wal = Wal::new(LogFile::new(FILE)?)?;
+        let lsns =
+            (1..=10).map(|txn_id| wal.append(&LogRecord::Begin { txn_id })).collect::<Vec<_>>();
+        wal.flush(wal.next_lsn())?;
+        let size = LogFile::new(FILE)?.size()?;
+
+        wal.truncate(lsns[6])?;
+        assert!(LogFile::new(FILE)?.size()? < size);
+        assert_eq!(wal.read(lsns[5])?, None);
+        let commit = wal.append(&LogRecord::Commit { txn_id: 7, prev_lsn: lsns[6] });
+        wal.flush(commit)?;
+
+        // LSNs don't change
+        drop(wal);
+        let wal = Wal::new(LogFile::new(FILE)?)?;
+        let have = wal.iter().collect::<std::io::Result<Vec<_>>>()?;
+        let mut want = (7..=10)
+            .map(|txn_id| (lsns[txn_id as usize - 1], LogRecord::Begin { txn_id }))
+            .collect::<Vec<_>>();
+        want.push((commit, LogRecord::Commit { txn_id: 7, prev_lsn: lsns[6] }));
+        assert_eq!(want, have);
+        assert_eq!(wal.next_lsn(), commit + have[4].1.size() as u64);
+
+        Ok(())
+    }
+}